@@ -0,0 +1,41 @@
+use std::net::SocketAddr;
+use clap::Parser;
+use derive_more::Display;
+use tarpc::context;
+use crate::command::Run;
+use crate::error::Result;
+use crate::AppState;
+
+#[derive(Parser, Debug, Display)]
+#[clap(
+    name = "add_listener",
+    about = "Start accepting connections on another address")]
+#[display("add_listener")]
+pub struct AddListener {
+    #[clap(short, long, help = "The name of the listener, which rules can match on as `:listener`")]
+    pub name: String,
+    #[clap(short, long, help = "The address to bind to (ip:port)")]
+    pub bind: SocketAddr,
+    #[clap(short, long, help = "Where connections go until a program is set (ip:port)")]
+    pub destination: SocketAddr,
+}
+
+// `SocketAddr` has no default; this is only for listing the commands
+impl Default for AddListener {
+    fn default() -> Self {
+        let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+        Self {
+            name: String::new(),
+            bind: unspecified,
+            destination: unspecified,
+        }
+    }
+}
+
+impl Run for AddListener {
+    async fn run(&self, app_state: &AppState) -> Result<()> {
+        let addr = app_state.client.add_listener(context::current(), self.name.clone(), self.bind, self.destination).await??;
+        println!("Added listener {} on {}", self.name, addr);
+        Ok(())
+    }
+}
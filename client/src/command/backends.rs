@@ -0,0 +1,43 @@
+use clap::Parser;
+use derive_more::Display;
+use shared::model::BackendHealth;
+use tarpc::context;
+use crate::command::Run;
+use crate::error::Result;
+use crate::AppState;
+
+#[derive(Parser, Debug, Default, Display)]
+#[clap(
+    name = "backends",
+    about = "List the backends of the listeners' upstreams and their health")]
+#[display("backends")]
+pub struct Backends {}
+
+impl Run for Backends {
+    async fn run(&self, app_state: &AppState) -> Result<()> {
+        let backends = app_state.client.backends(context::current()).await??;
+        if backends.is_empty() {
+            println!("No upstreams in the listeners' programs");
+            return Ok(());
+        }
+
+        println!("\n    {:<20} {:<20} {:<24} {:<7} {:<6} {:<10}", "Listener", "Upstream", "Backend", "Weight", "Open", "Health");
+
+        for backend in backends {
+            let health = match backend.health {
+                BackendHealth::Unchecked => "unchecked".to_string(),
+                BackendHealth::Pending => "pending".to_string(),
+                BackendHealth::Up => "up".to_string(),
+                BackendHealth::Down(reason) => format!("down ({})", reason),
+                BackendHealth::Ejected(left) => format!("ejected ({}s left)", left.as_secs()),
+            };
+            println!(
+                "    {:<20} {:<20} {:<24} {:<7} {:<6} {:<10}",
+                backend.listener, backend.upstream, backend.addr, backend.weight, backend.connections, health
+            );
+        }
+        println!();
+
+        Ok(())
+    }
+}
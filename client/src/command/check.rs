@@ -0,0 +1,27 @@
+use std::fs;
+use clap::Parser;
+use derive_more::Display;
+use crate::command::Run;
+use crate::error::Result;
+use crate::AppState;
+
+#[derive(Parser, Debug, Default, Display)]
+#[clap(
+    name = "check",
+    about = "Check a rule file for errors without uploading it")]
+#[display("check")]
+pub struct Check {
+    #[clap(short, long, help = "The path to the rule file")]
+    pub path: String,
+}
+
+impl Run for Check {
+    async fn run(&self, _app_state: &AppState) -> Result<()> {
+        let content = fs::read_to_string(&self.path)?;
+        match rulelib::compile(&content) {
+            Ok(_) => println!("{} compiled successfully", self.path),
+            Err(diagnostics) => print!("{}", rulelib::diagnostic::render(&self.path, &content, &diagnostics)),
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,5 @@
 use std::fs;
-use clap::{Parser, Subcommand};
+use clap::Parser;
 use derive_more::Display;
 use tarpc::context;
 use crate::command::Run;
@@ -11,7 +11,6 @@ use crate::AppState;
     name = "create",
     about = "Create a new rule file")]
 #[display("create")]
-
 pub struct Create {
     #[clap(short, long, help = "The name of the rule file")]
     pub name: String,
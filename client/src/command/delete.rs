@@ -10,7 +10,6 @@ use crate::AppState;
     name = "delete",
     about = "Delete a rule file by id")]
 #[display("delete")]
-
 pub struct Delete {
     #[clap(short, long, help = "The id of the rule file")]
     pub id: i64,
@@ -18,7 +17,7 @@ pub struct Delete {
 
 impl Run for Delete {
     async fn run(&self, app_state: &AppState) -> Result<()> {
-        app_state.client.delete(context::current(), self.id.clone()).await??;
+        app_state.client.delete(context::current(), self.id).await??;
         println!("Deleted rule file (id {})", &self.id);
         Ok(())
     }
@@ -9,7 +9,6 @@ use crate::AppState;
     name = "exit",
     about = "Exits immediately")]
 #[display("exit")]
-
 pub struct Exit {}
 
 impl Run for Exit {
@@ -0,0 +1,36 @@
+use clap::Parser;
+use derive_more::Display;
+use tarpc::context;
+use crate::command::Run;
+use crate::error::Result;
+use crate::AppState;
+
+#[derive(Parser, Debug, Default, Display)]
+#[clap(
+    name = "listeners",
+    about = "List the listeners and their programs")]
+#[display("listeners")]
+pub struct Listeners {}
+
+impl Run for Listeners {
+    async fn run(&self, app_state: &AppState) -> Result<()> {
+        let listeners = app_state.client.listeners(context::current()).await??;
+        if listeners.is_empty() {
+            println!("No listeners");
+            return Ok(());
+        }
+
+        println!("\n    {:<20} {:<24} {:<24} {:<20}", "Name", "Bind", "Destination", "Program");
+
+        for listener in listeners {
+            let program = listener.program.unwrap_or_else(|| "none".to_string());
+            println!(
+                "    {:<20} {:<24} {:<24} {:<20}",
+                listener.name, listener.bind, listener.destination, program
+            );
+        }
+        println!();
+
+        Ok(())
+    }
+}
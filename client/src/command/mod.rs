@@ -5,6 +5,11 @@ mod update;
 mod delete;
 mod set_program;
 mod list;
+mod check;
+mod backends;
+mod add_listener;
+mod remove_listener;
+mod listeners;
 
 use clap::Parser;
 use derive_more::Display;
@@ -21,7 +26,12 @@ pub enum Command {
     Request(request::Request),
     Update(update::Update),
     Delete(delete::Delete),
-    SetProgram(set_program::SetProgram)
+    SetProgram(set_program::SetProgram),
+    Check(check::Check),
+    Backends(backends::Backends),
+    AddListener(add_listener::AddListener),
+    RemoveListener(remove_listener::RemoveListener),
+    Listeners(listeners::Listeners)
 }
 
 pub trait Run {
@@ -38,7 +48,12 @@ impl Run for Command {
             Command::Request(request) => request.run(app_state).await,
             Command::Update(update) => update.run(app_state).await,
             Command::Delete(delete) => delete.run(app_state).await,
-            Command::SetProgram(set_program) => set_program.run(app_state).await
+            Command::SetProgram(set_program) => set_program.run(app_state).await,
+            Command::Check(check) => check.run(app_state).await,
+            Command::Backends(backends) => backends.run(app_state).await,
+            Command::AddListener(add_listener) => add_listener.run(app_state).await,
+            Command::RemoveListener(remove_listener) => remove_listener.run(app_state).await,
+            Command::Listeners(listeners) => listeners.run(app_state).await
         }
     }
 }
@@ -0,0 +1,24 @@
+use clap::Parser;
+use derive_more::Display;
+use tarpc::context;
+use crate::command::Run;
+use crate::error::Result;
+use crate::AppState;
+
+#[derive(Parser, Debug, Default, Display)]
+#[clap(
+    name = "remove_listener",
+    about = "Stop accepting connections on a listener; open connections carry on")]
+#[display("remove_listener")]
+pub struct RemoveListener {
+    #[clap(help = "The name of the listener")]
+    pub name: String,
+}
+
+impl Run for RemoveListener {
+    async fn run(&self, app_state: &AppState) -> Result<()> {
+        app_state.client.remove_listener(context::current(), self.name.clone()).await??;
+        println!("Removed listener {}", self.name);
+        Ok(())
+    }
+}
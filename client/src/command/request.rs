@@ -1,4 +1,3 @@
-use std::fs;
 use clap::Parser;
 use derive_more::Display;
 use tarpc::context;
@@ -18,7 +17,7 @@ pub struct Request {
 
 impl Run for Request {
     async fn run(&self, app_state: &AppState) -> Result<()> {
-        let rule_file = app_state.client.request(context::current(), self.id.clone()).await??;
+        let rule_file = app_state.client.request(context::current(), self.id).await??;
         println!("Got rule file (id {}):\n{}", &self.id, rule_file.content);
         Ok(())
     }
@@ -1,4 +1,3 @@
-use std::fs;
 use clap::Parser;
 use derive_more::Display;
 use tarpc::context;
@@ -14,12 +13,14 @@ use crate::AppState;
 pub struct SetProgram {
     #[clap(help = "The ID of the program to set")]
     pub id: i64,
+    #[clap(short, long, default_value = "default", help = "The listener to set it on")]
+    pub listener: String,
 }
 
 impl Run for SetProgram {
     async fn run(&self, app_state: &AppState) -> Result<()> {
-        app_state.client.set_program(context::current(), self.id).await??;
-        println!("Set program with id: {}", self.id);
+        app_state.client.set_program(context::current(), self.listener.clone(), self.id).await??;
+        println!("Set program with id {} on {}", self.id, self.listener);
         Ok(())
     }
 }
\ No newline at end of file
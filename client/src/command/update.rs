@@ -21,7 +21,7 @@ pub struct Update {
 impl Run for Update {
     async fn run(&self, app_state: &AppState) -> Result<()> {
         let content = fs::read_to_string(&self.path)?;
-        app_state.client.update(context::current(), self.id.clone(), content).await??;
+        app_state.client.update(context::current(), self.id, content).await??;
         println!("Updated rule file (id {}) to match {}", self.id, self.path);
         Ok(())
     }
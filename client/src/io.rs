@@ -9,7 +9,7 @@ use serde::{Deserialize, Serialize};
 use crate::command::Command;
 use crate::error::{Error, Result};
 
-pub fn readline(prompt: Option<String>) -> Result<String> {
+pub fn readline(_prompt: Option<String>) -> Result<String> {
     // History
     let history = Box::new(FileBackedHistory::with_file(1024, "history.txt".parse().unwrap()).expect("Failed to create history"));
 
@@ -46,7 +46,7 @@ pub fn readline(prompt: Option<String>) -> Result<String> {
                 .with_style(Style::new().italic().fg(nu_ansi_term::Color::LightGray))))
         .with_edit_mode(edit_mode);
 
-    let signal = rl.read_line(&mut StandardPrompt::default())?;
+    let signal = rl.read_line(&StandardPrompt::default())?;
 
     match signal {
         Signal::Success(buffer) => Ok(buffer),
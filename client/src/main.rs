@@ -29,7 +29,7 @@ async fn main() -> anyhow::Result<()> {
 
     let client = RuleSvcClient::new(client::Config::default(), transport.await?).spawn();
 
-    let mut app_state = AppState { client };
+    let app_state = AppState { client };
     loop {
         let input = match io::readline(None) {
             Ok(input) => input,
@@ -40,8 +40,10 @@ async fn main() -> anyhow::Result<()> {
             Ok(command) => command,
             Err(err) => { println!("{}", err); continue; },
         };
-        match command.run(&mut app_state).await {
+        match command.run(&app_state).await {
             Ok(_) => {},
+            // rule file errors arrive pre-rendered, so print them as-is
+            Err(error::Error::Shared(err)) => { println!("{}", err.to_string().trim_end()); },
             Err(err) => { println!("Error executing command: {:?}", err); },
         };
     }
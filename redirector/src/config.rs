@@ -0,0 +1,790 @@
+//! The config file, which declares the redirector's listeners, how it handles connections, where
+//! it logs, its RPC endpoint and its database, and which is reloaded on SIGHUP
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use rulelib::diagnostic::render;
+use rulelib::vm::Program;
+use serde::{Deserialize, Deserializer};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn, Level};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::{reload, Registry};
+
+use crate::model::{AppState, DropPolicy, Failover, Settings, StreamLimits};
+use crate::redirector::{Listener, Stopped};
+use crate::rpc::RPC_BIND;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Where the RPC server binds to
+    #[serde(default = "default_rpc")]
+    pub rpc: SocketAddr,
+    /// The sqlite database rule files are saved in
+    #[serde(default = "default_database")]
+    pub database: PathBuf,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub settings: SettingsConfig,
+    #[serde(default, rename = "listener")]
+    pub listeners: Vec<ListenerConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Maximum level to log
+    #[serde(deserialize_with = "level")]
+    pub level: Level,
+    /// Directory the daily log files go in
+    pub dir: PathBuf,
+    pub file: PathBuf,
+    /// Log to stdout instead of a file
+    pub stdout: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: Level::INFO,
+            dir: PathBuf::from("log"),
+            file: PathBuf::from("connections.log"),
+            stdout: false,
+        }
+    }
+}
+
+/// How connections are handled, whichever listener accepts them; each is named after its flag
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SettingsConfig {
+    pub drop_policy: DropPolicy,
+    pub stream_window: usize,
+    pub stream_buffer: usize,
+    pub stream_flush_ms: u64,
+    pub connect_timeout_ms: u64,
+    pub connect_retries: u32,
+    pub retry_backoff_ms: u64,
+    pub eject_after: u32,
+    pub eject_cooldown_ms: u64,
+}
+
+impl Default for SettingsConfig {
+    fn default() -> Self {
+        Self {
+            drop_policy: DropPolicy::Hold,
+            stream_window: 4096,
+            stream_buffer: 65536,
+            stream_flush_ms: 50,
+            connect_timeout_ms: 5000,
+            connect_retries: 2,
+            retry_backoff_ms: 100,
+            eject_after: 5,
+            eject_cooldown_ms: 30000,
+        }
+    }
+}
+
+impl SettingsConfig {
+    pub fn settings(&self) -> Settings {
+        Settings {
+            drop_policy: self.drop_policy,
+            limits: StreamLimits {
+                window: self.stream_window,
+                cap: self.stream_buffer,
+                flush_after: Duration::from_millis(self.stream_flush_ms),
+            },
+            failover: Failover {
+                connect_timeout: Duration::from_millis(self.connect_timeout_ms),
+                retries: self.connect_retries,
+                backoff: Duration::from_millis(self.retry_backoff_ms),
+                eject_after: self.eject_after,
+                cooldown: Duration::from_millis(self.eject_cooldown_ms),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// What rules see as `:listener`; unique
+    pub name: String,
+    pub bind: SocketAddr,
+    /// Where connections go while there's no program
+    pub destination: SocketAddr,
+    /// The rule file the listener's program is compiled from, relative to the config file
+    pub rules: Option<PathBuf>,
+    /// What was compiled from `rules` when the config was loaded
+    #[serde(skip)]
+    pub program: Option<Rules>,
+}
+
+/// A rule file and the program compiled from it
+#[derive(Debug, Clone)]
+pub struct Rules {
+    pub content: String,
+    pub program: Program,
+}
+
+/// Rule files are the same if what's in them is, as the same program compiles from them
+impl PartialEq for Rules {
+    fn eq(&self, other: &Self) -> bool {
+        self.content == other.content
+    }
+}
+
+fn default_rpc() -> SocketAddr {
+    RPC_BIND
+}
+
+fn default_database() -> PathBuf {
+    PathBuf::from("redirector.db")
+}
+
+fn level<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Level, D::Error> {
+    let level = String::deserialize(deserializer)?;
+    level.parse().map_err(serde::de::Error::custom)
+}
+
+impl Config {
+    /// Reads and validates a config file, compiling the rule files of its listeners
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        Self::parse(&text, dir).with_context(|| format!("invalid config {}", path.display()))
+    }
+
+    /// Parses and validates a config, with rule files relative to `dir`
+    pub fn parse(text: &str, dir: &Path) -> anyhow::Result<Self> {
+        let mut config: Self = toml::from_str(text)?;
+        if config.settings.stream_window >= config.settings.stream_buffer {
+            bail!("`stream_buffer` must be bigger than `stream_window`");
+        }
+        let (mut names, mut binds) = (HashSet::new(), Vec::<(SocketAddr, String)>::new());
+        for (i, listener) in config.listeners.iter_mut().enumerate() {
+            if listener.name.is_empty() {
+                bail!("listener {} has an empty name", i + 1);
+            }
+            if !names.insert(listener.name.clone()) {
+                bail!("there's more than one listener named `{}`", listener.name);
+            }
+            // Port 0 binds whichever port is free, so those never clash
+            if listener.bind.port() != 0 {
+                if let Some((bind, other)) = binds.iter().find(|(b, _)| clashes(*b, listener.bind)) {
+                    bail!(
+                        "`{}` and `{}` both bind {} (as {} and {})",
+                        other,
+                        listener.name,
+                        listener.bind.port(),
+                        bind,
+                        listener.bind
+                    );
+                }
+                binds.push((listener.bind, listener.name.clone()));
+            }
+            if let Some(rules) = &mut listener.rules {
+                *rules = dir.join(&rules);
+                listener.program = Some(Rules::load(rules)?);
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Whether two binds can't both be listened on: they share a port, and one of them is every
+/// address of a family the other is in. `::` is dual-stack, so it takes in IPv4 addresses too.
+fn clashes(a: SocketAddr, b: SocketAddr) -> bool {
+    let covers = |wide: IpAddr, other: IpAddr| match wide {
+        IpAddr::V4(ip) => ip.is_unspecified() && other.is_ipv4(),
+        IpAddr::V6(ip) => ip.is_unspecified(),
+    };
+    a.port() == b.port() && (a.ip() == b.ip() || covers(a.ip(), b.ip()) || covers(b.ip(), a.ip()))
+}
+
+impl Rules {
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        match rulelib::compile(&content) {
+            Ok(program) => Ok(Self { content, program }),
+            Err(diagnostics) => {
+                let report = render(&path.display().to_string(), &content, &diagnostics);
+                bail!("{} failed to compile:\n{}", path.display(), report)
+            }
+        }
+    }
+}
+
+/// What has to be done to the running listeners to go from one config to another
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change<'a> {
+    /// Stop a listener that's no longer declared
+    Remove(&'a str),
+    /// Start a listener that's newly declared, with its program if it has one
+    Add(&'a ListenerConfig),
+    /// Move a listener to its new address or destination, keeping its program
+    Move(&'a ListenerConfig),
+    /// Set the program of a listener whose rule file changed
+    SetProgram(&'a ListenerConfig),
+}
+
+/// The changes that take the listeners `old` declares to the ones `new` does. Listeners are
+/// removed first, so their ports are free for the others. A listener whose rule file is taken out
+/// keeps the program it has.
+pub fn diff<'a>(old: &'a [ListenerConfig], new: &'a [ListenerConfig]) -> Vec<Change<'a>> {
+    let mut changes = vec![];
+    for was in old {
+        if !new.iter().any(|listener| listener.name == was.name) {
+            changes.push(Change::Remove(&was.name));
+        }
+    }
+    for listener in new {
+        let Some(was) = old.iter().find(|was| was.name == listener.name) else {
+            changes.push(Change::Add(listener));
+            continue;
+        };
+        if (was.bind, was.destination) != (listener.bind, listener.destination) {
+            changes.push(Change::Move(listener));
+        }
+        let rules_changed = (&was.rules, &was.program) != (&listener.rules, &listener.program);
+        if listener.program.is_some() && rules_changed {
+            changes.push(Change::SetProgram(listener));
+        }
+    }
+    changes
+}
+
+/// Takes the running listeners from the ones `old` declares to the ones `new` does, logging each
+/// change, and returns the listeners that are declared now: `new`, but for the changes that
+/// failed, so that they're tried again by the next reload. Listeners added over RPC are left
+/// alone.
+pub async fn apply(
+    old: &[ListenerConfig],
+    new: &[ListenerConfig],
+    app_state: &AppState,
+) -> Vec<ListenerConfig> {
+    let changes = diff(old, new);
+    // Every listener that moves lets go of its port before any binds its new one, so that two can
+    // swap ports
+    let mut moving = HashMap::new();
+    for change in &changes {
+        if let Change::Move(listener) = change {
+            moving.insert(&listener.name, app_state.listeners.stop(&listener.name).await);
+        }
+    }
+
+    let mut applied = old.to_vec();
+    for change in changes {
+        let result = match change {
+            Change::Move(listener) => {
+                let stopped = moving.remove(&listener.name).flatten();
+                move_listener(listener, stopped, app_state)
+            }
+            change => apply_change(change, app_state).await,
+        };
+        if let Err(e) = result {
+            error!("{:#}", e);
+            // A listener that didn't move is left stopped, so it's added again next time
+            if let Change::Move(listener) = change {
+                applied.retain(|was| was.name != listener.name);
+            }
+            continue;
+        }
+        match change {
+            Change::Remove(name) => applied.retain(|was| was.name != name),
+            Change::Add(listener) => applied.push(listener.clone()),
+            Change::Move(listener) => {
+                if let Some(was) = applied.iter_mut().find(|was| was.name == listener.name) {
+                    (was.bind, was.destination) = (listener.bind, listener.destination);
+                }
+            }
+            Change::SetProgram(listener) => {
+                if let Some(was) = applied.iter_mut().find(|was| was.name == listener.name) {
+                    (was.rules, was.program) = (listener.rules.clone(), listener.program.clone());
+                }
+            }
+        }
+    }
+    applied
+}
+
+async fn apply_change(change: Change<'_>, app_state: &AppState) -> anyhow::Result<()> {
+    let listeners = &app_state.listeners;
+    match change {
+        Change::Remove(name) => {
+            if listeners.stop(name).await.is_some() {
+                info!("Removed the listener {}", name);
+            }
+        }
+        Change::Add(listener) => start(listener, app_state)
+            .with_context(|| format!("failed to add the listener {}", listener.name))?,
+        Change::Move(_) => unreachable!("moves are applied together"),
+        Change::SetProgram(listener) => set_program(listener, app_state)?,
+    }
+    Ok(())
+}
+
+fn move_listener(
+    listener: &ListenerConfig,
+    stopped: Option<Stopped>,
+    app_state: &AppState,
+) -> anyhow::Result<()> {
+    let (name, bind, destination) = (&listener.name, listener.bind, listener.destination);
+    let listeners = &app_state.listeners;
+    let moved = match stopped {
+        Some(stopped) => listeners.restart(stopped, bind, destination, app_state).map(drop),
+        // It may have been removed over RPC since
+        None => start(listener, app_state),
+    };
+    moved.with_context(|| format!("failed to move the listener {}", name))
+}
+
+/// Starts a listener with its program, if it has one
+fn start(listener: &ListenerConfig, app_state: &AppState) -> anyhow::Result<()> {
+    let started = Listener::new(listener.name.clone(), listener.bind, listener.destination);
+    app_state.listeners.start(started, app_state)?;
+    set_program(listener, app_state)
+}
+
+fn set_program(listener: &ListenerConfig, app_state: &AppState) -> anyhow::Result<()> {
+    let (Some(path), Some(rules)) = (&listener.rules, &listener.program) else {
+        return Ok(());
+    };
+    let source = path.display().to_string();
+    if !app_state.listeners.set_program(&listener.name, rules.program.clone(), source) {
+        bail!("failed to set the program of {}: it isn't running", listener.name);
+    }
+    info!("Set the program of {} to {}", listener.name, path.display());
+    Ok(())
+}
+
+/// Sets the maximum level of the running log
+pub type LevelHandle = reload::Handle<LevelFilter, Registry>;
+
+/// Reloads the config file whenever the redirector gets a SIGHUP and applies what changed. A config
+/// that doesn't load is logged and the running one is kept.
+pub async fn reload_on_hangup(
+    path: PathBuf,
+    mut config: Config,
+    app_state: AppState,
+    log_level: LevelHandle,
+) -> anyhow::Result<()> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    while hangups.recv().await.is_some() {
+        info!("Reloading {}", path.display());
+        match Config::load(&path) {
+            Ok(new) => config = reload(config, new, &app_state, &log_level).await,
+            Err(e) => error!("Kept the running config: {:#}", e),
+        }
+    }
+    Ok(())
+}
+
+/// Applies what changed from `old` to `new`, returning the config that's now running. Where the
+/// RPC server listens, the database and where the log goes are only set up on start, so they keep
+/// their running values and are reported on every reload until the redirector is restarted.
+async fn reload(old: Config, new: Config, app_state: &AppState, log_level: &LevelHandle) -> Config {
+    let mut log = LogConfig { level: new.log.level, ..old.log.clone() };
+    let restart_only = [
+        ("`rpc`", old.rpc != new.rpc),
+        ("`database`", old.database != new.database),
+        ("Where the log goes", log != new.log),
+    ];
+    for (setting, changed) in restart_only {
+        if changed {
+            warn!("{} changed, which only takes effect on restart", setting);
+        }
+    }
+    if log.level != old.log.level {
+        match log_level.modify(|level| *level = LevelFilter::from_level(log.level)) {
+            Ok(()) => info!("Set the log level to {}", log.level),
+            Err(e) => {
+                error!("Failed to set the log level to {}: {}", log.level, e);
+                log.level = old.log.level;
+            }
+        }
+    }
+    if new.settings != old.settings {
+        *app_state.settings.write().unwrap() = new.settings.settings();
+        info!("Set the settings to {:?}; connections already open keep theirs", new.settings);
+    }
+    let listeners = apply(&old.listeners, &new.listeners, app_state).await;
+    if listeners != new.listeners {
+        warn!("Some of the listeners didn't apply, so they're tried again next time");
+    }
+    Config {
+        rpc: old.rpc,
+        database: old.database,
+        log,
+        settings: new.settings,
+        listeners,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redirector::testing::{forwards_to, test_app_state};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// A directory of its own for a test's rule files
+    fn rule_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("redirector-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn parsing_fills_in_defaults() {
+        let dir = rule_dir("defaults");
+        fs::write(dir.join("web.rf"), "(set-mode OPAQUE) (def-rule r DROP)").unwrap();
+        let config = Config::parse(
+            r#"
+            [log]
+            level = "debug"
+
+            [[listener]]
+            name = "web"
+            bind = "0.0.0.0:80"
+            destination = "127.0.0.1:8000"
+            rules = "web.rf"
+
+            [[listener]]
+            name = "smtp"
+            bind = "[::]:25"
+            destination = "[::1]:2525"
+            "#,
+            &dir,
+        )
+        .unwrap();
+
+        assert_eq!(config.rpc, RPC_BIND);
+        assert_eq!(config.database, PathBuf::from("redirector.db"));
+        assert_eq!(config.log.level, Level::DEBUG);
+        assert_eq!(config.log.dir, PathBuf::from("log"));
+        assert!(!config.log.stdout);
+        assert_eq!(config.settings, SettingsConfig::default());
+        let names: Vec<_> = config.listeners.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, ["web", "smtp"]);
+        assert_eq!(config.listeners[0].rules, Some(dir.join("web.rf")));
+        assert!(config.listeners[0].program.is_some());
+        assert_eq!(config.listeners[1].destination, "[::1]:2525".parse().unwrap());
+        assert!(config.listeners[1].program.is_none());
+    }
+
+    #[test]
+    fn settings_are_read_like_their_flags() {
+        let config = Config::parse(
+            r#"
+            [settings]
+            drop_policy = "close"
+            stream_window = 64
+            stream_flush_ms = 10
+            eject_after = 0
+            "#,
+            Path::new(""),
+        )
+        .unwrap();
+
+        let settings = config.settings.settings();
+        assert_eq!(settings.drop_policy, DropPolicy::Close);
+        assert_eq!(settings.limits.window, 64);
+        assert_eq!(settings.limits.cap, 65536);
+        assert_eq!(settings.limits.flush_after, Duration::from_millis(10));
+        assert_eq!(settings.failover.eject_after, 0);
+        assert_eq!(settings.failover.retries, 2);
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        let dir = rule_dir("invalid");
+        fs::write(dir.join("bad.rf"), "(set-mode OPAQUE) (def-rule r (REDIRECT))").unwrap();
+        let listener = |name: &str, bind: &str| {
+            format!(
+                "[[listener]]\nname = \"{}\"\nbind = \"{}\"\ndestination = \"127.0.0.1:8000\"\n",
+                name, bind
+            )
+        };
+        let invalid = [
+            (listener("web", "0.0.0.0:80") + &listener("web", "0.0.0.0:81"), "more than one"),
+            (listener("web", "0.0.0.0:80") + &listener("mail", "0.0.0.0:80"), "both bind"),
+            (listener("web", "[::]:80") + &listener("mail", "0.0.0.0:80"), "both bind"),
+            (listener("web", "[::]:80") + &listener("mail", "127.0.0.1:80"), "both bind"),
+            (listener("web", "[::1]:80") + &listener("mail", "[::]:80"), "both bind"),
+            (listener("web", "127.0.0.1:80") + &listener("mail", "0.0.0.0:80"), "both bind"),
+            (listener("", "0.0.0.0:80"), "empty name"),
+            (listener("web", "0.0.0.0"), "invalid socket address"),
+            (listener("web", "0.0.0.0:80") + "rules = \"missing.rf\"", "failed to read"),
+            (listener("web", "0.0.0.0:80") + "rules = \"bad.rf\"", "failed to compile"),
+            ("[log]\nlevel = \"loud\"".to_string(), "level"),
+            ("rpc_bind = \"127.0.0.1:1\"".to_string(), "unknown field"),
+            ("[settings]\ndrop_policy = \"reset\"".to_string(), "unknown variant"),
+            ("[settings]\nstream_window = 65536".to_string(), "must be bigger"),
+        ];
+        for (text, error) in invalid {
+            let e = Config::parse(&text, &dir).unwrap_err();
+            assert!(format!("{:#}", e).contains(error), "{}: {:#}", text, e);
+        }
+
+        let ephemeral = listener("web", "127.0.0.1:0") + &listener("mail", "127.0.0.1:0");
+        assert!(Config::parse(&ephemeral, &dir).is_ok());
+
+        // different addresses of the same family, or an IPv4 wildcard and an IPv6 address, don't
+        let apart = [
+            listener("web", "127.0.0.1:80") + &listener("mail", "127.0.0.2:80"),
+            listener("web", "0.0.0.0:80") + &listener("mail", "[::1]:80"),
+            listener("web", "[::]:80") + &listener("mail", "[::]:81"),
+        ];
+        for text in apart {
+            assert!(Config::parse(&text, &dir).is_ok(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn diffs_change_only_what_changed() {
+        let dir = rule_dir("diff");
+        fs::write(dir.join("drop.rf"), "(set-mode OPAQUE) (def-rule r DROP)").unwrap();
+        fs::write(dir.join("reject.rf"), "(set-mode OPAQUE) (def-rule r REJECT)").unwrap();
+        let old = Config::parse(
+            r#"
+            [[listener]]
+            name = "gone"
+            bind = "0.0.0.0:1"
+            destination = "127.0.0.1:1"
+            [[listener]]
+            name = "same"
+            bind = "0.0.0.0:2"
+            destination = "127.0.0.1:2"
+            rules = "drop.rf"
+            [[listener]]
+            name = "moved"
+            bind = "0.0.0.0:3"
+            destination = "127.0.0.1:3"
+            [[listener]]
+            name = "rules"
+            bind = "0.0.0.0:4"
+            destination = "127.0.0.1:4"
+            rules = "drop.rf"
+            [[listener]]
+            name = "unruled"
+            bind = "0.0.0.0:5"
+            destination = "127.0.0.1:5"
+            rules = "drop.rf"
+            "#,
+            &dir,
+        )
+        .unwrap();
+        let new = Config::parse(
+            r#"
+            [[listener]]
+            name = "same"
+            bind = "0.0.0.0:2"
+            destination = "127.0.0.1:2"
+            rules = "drop.rf"
+            [[listener]]
+            name = "moved"
+            bind = "0.0.0.0:3"
+            destination = "127.0.0.1:30"
+            [[listener]]
+            name = "rules"
+            bind = "0.0.0.0:4"
+            destination = "127.0.0.1:4"
+            rules = "reject.rf"
+            [[listener]]
+            name = "unruled"
+            bind = "0.0.0.0:5"
+            destination = "127.0.0.1:5"
+            [[listener]]
+            name = "added"
+            bind = "0.0.0.0:1"
+            destination = "127.0.0.1:1"
+            "#,
+            &dir,
+        )
+        .unwrap();
+        let (old, new) = (&old.listeners, &new.listeners);
+
+        assert_eq!(
+            diff(old, new),
+            [
+                Change::Remove("gone"),
+                Change::Move(&new[1]),
+                Change::SetProgram(&new[2]),
+                Change::Add(&new[4]),
+            ]
+        );
+        assert_eq!(diff(new, new), []);
+
+        // Editing a rule file in place changes the program too
+        let mut edited = old.clone();
+        fs::write(dir.join("drop.rf"), "(set-mode OPAQUE) (def-rule other DROP)").unwrap();
+        edited[1].program = Some(Rules::load(&dir.join("drop.rf")).unwrap());
+        assert_eq!(diff(old, &edited), [Change::SetProgram(&edited[1])]);
+    }
+
+    #[tokio::test]
+    async fn applying_starts_moves_and_stops_listeners() {
+        let app_state = test_app_state();
+        let dir = rule_dir("apply");
+        let (old, new, routed) = (
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        let rules = format!(
+            r#"(set-mode OPAQUE) (def-rule r (REDIRECT "127.0.0.1" {}))"#,
+            routed.local_addr().unwrap().port()
+        );
+        fs::write(dir.join("routed.rf"), rules).unwrap();
+        // Bind a port, then let go of it, for the listener to take
+        let port = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let config = |destination: SocketAddr, rules: &str| {
+            let text = format!(
+                "[[listener]]\nname = \"web\"\nbind = \"{}\"\ndestination = \"{}\"\n{}",
+                port, destination, rules
+            );
+            Config::parse(&text, &dir).unwrap()
+        };
+
+        let started = config(old.local_addr().unwrap(), "");
+        assert_eq!(apply(&[], &started.listeners, &app_state).await, started.listeners);
+        forwards_to(port, &old).await;
+
+        let moved = config(new.local_addr().unwrap(), "");
+        assert_eq!(apply(&started.listeners, &moved.listeners, &app_state).await, moved.listeners);
+        forwards_to(port, &new).await;
+
+        let ruled = config(new.local_addr().unwrap(), "rules = \"routed.rf\"");
+        assert_eq!(apply(&moved.listeners, &ruled.listeners, &app_state).await, ruled.listeners);
+        forwards_to(port, &routed).await;
+        let (_, source) = app_state.listeners.list().remove(0);
+        assert_eq!(source, Some(dir.join("routed.rf").display().to_string()));
+
+        assert!(apply(&ruled.listeners, &[], &app_state).await.is_empty());
+        assert!(app_state.listeners.list().is_empty());
+        assert!(TcpStream::connect(port).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn moved_listeners_can_swap_ports() {
+        let app_state = test_app_state();
+        let dir = rule_dir("swap");
+        let (web, mail) = (
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        let ports = [
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        ];
+        let [first, second] = ports.map(|port| port.local_addr().unwrap());
+        let config = |web_bind: SocketAddr, mail_bind: SocketAddr| {
+            let text = format!(
+                "[[listener]]\nname = \"web\"\nbind = \"{}\"\ndestination = \"{}\"\n\
+                 [[listener]]\nname = \"mail\"\nbind = \"{}\"\ndestination = \"{}\"\n",
+                web_bind,
+                web.local_addr().unwrap(),
+                mail_bind,
+                mail.local_addr().unwrap()
+            );
+            Config::parse(&text, &dir).unwrap()
+        };
+
+        let started = config(first, second);
+        assert_eq!(apply(&[], &started.listeners, &app_state).await, started.listeners);
+        let swapped = config(second, first);
+        let applied = apply(&started.listeners, &swapped.listeners, &app_state).await;
+        assert_eq!(applied, swapped.listeners);
+        forwards_to(first, &mail).await;
+        forwards_to(second, &web).await;
+    }
+
+    #[tokio::test]
+    async fn changes_that_failed_are_tried_again() {
+        let app_state = test_app_state();
+        let dir = rule_dir("retry");
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = taken.local_addr().unwrap();
+        let text = format!(
+            "[[listener]]\nname = \"web\"\nbind = \"{}\"\ndestination = \"{}\"\n",
+            port,
+            upstream.local_addr().unwrap()
+        );
+        let config = Config::parse(&text, &dir).unwrap();
+
+        // The port is taken, so the listener isn't declared yet
+        let applied = apply(&[], &config.listeners, &app_state).await;
+        assert!(applied.is_empty());
+        drop(taken);
+        assert_eq!(apply(&applied, &config.listeners, &app_state).await, config.listeners);
+        forwards_to(port, &upstream).await;
+    }
+
+    #[tokio::test]
+    async fn reloading_sets_settings_and_log_level_but_keeps_what_needs_a_restart() {
+        let app_state = test_app_state();
+        let dir = rule_dir("reload");
+        let (_level, log_level) = reload::Layer::<_, Registry>::new(LevelFilter::INFO);
+        let old = Config::parse("", &dir).unwrap();
+        let new = Config::parse(
+            "rpc = \"127.0.0.1:1\"\ndatabase = \"other.db\"\n\
+             [log]\nlevel = \"debug\"\nstdout = true\n\
+             [settings]\ndrop_policy = \"close\"\n",
+            &dir,
+        )
+        .unwrap();
+
+        let running = reload(old.clone(), new.clone(), &app_state, &log_level).await;
+        assert_eq!(log_level.clone_current(), Some(LevelFilter::DEBUG));
+        assert_eq!(*app_state.settings.read().unwrap(), new.settings.settings());
+        assert_eq!(running.settings, new.settings);
+        assert_eq!(running.log, LogConfig { level: Level::DEBUG, ..old.log.clone() });
+        // the rest still differs from the file, so it's reported again on the next reload
+        assert_eq!((&running.rpc, &running.database), (&old.rpc, &old.database));
+        let running = reload(running, new, &app_state, &log_level).await;
+        assert_eq!((running.rpc, running.database), (old.rpc, old.database));
+    }
+
+    #[tokio::test]
+    async fn listeners_removed_over_rpc_move_with_their_program() {
+        let app_state = test_app_state();
+        let dir = rule_dir("removed");
+        let (old, new, routed) = (
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        let rules = format!(
+            r#"(set-mode OPAQUE) (def-rule r (REDIRECT "127.0.0.1" {}))"#,
+            routed.local_addr().unwrap().port()
+        );
+        fs::write(dir.join("routed.rf"), rules).unwrap();
+        let port = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let config = |destination: SocketAddr| {
+            let text = format!(
+                "[[listener]]\nname = \"web\"\nbind = \"{}\"\ndestination = \"{}\"\n\
+                 rules = \"routed.rf\"\n",
+                port, destination
+            );
+            Config::parse(&text, &dir).unwrap()
+        };
+
+        let started = config(old.local_addr().unwrap());
+        assert_eq!(apply(&[], &started.listeners, &app_state).await, started.listeners);
+        assert!(app_state.listeners.stop("web").await.is_some());
+        let moved = config(new.local_addr().unwrap());
+        let applied = apply(&started.listeners, &moved.listeners, &app_state).await;
+        assert_eq!(applied, moved.listeners);
+        forwards_to(port, &routed).await;
+    }
+}
@@ -1,18 +1,20 @@
-use std::collections::HashMap;
-use std::net::Ipv4Addr;
-use std::sync::{Arc, Mutex};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
 use clap::Parser;
 use futures::future;
 use rusqlite::Connection;
 use tracing::Level;
 use tracing_appender::rolling::{RollingFileAppender, Rotation};
-use tracing_subscriber;
-use rulelib::vm::Instruction::{DROP, ITE, REDIRECT, SEQ};
-use rulelib::vm::{Object, PACKET_SOURCE_IP, Program};
-use crate::model::AppState;
-use crate::redirector::redirect;
-use crate::rpc::init_rpc;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::reload;
+use crate::config::{apply, reload_on_hangup, Config, ListenerConfig, LogConfig, SettingsConfig};
+use crate::model::{AppState, DropPolicy};
+use crate::redirector::check_health;
+use crate::rpc::{init_rpc, RPC_BIND};
 
+mod config;
 mod redirector;
 mod rpc;
 mod sql;
@@ -21,65 +23,140 @@ pub mod model;
 #[derive(Parser, Debug)]
 #[clap(name = "Reverse TCP Proxy", version="0.1.0", author="Ronan Boyarski, Nikil Date, Ethan Zhang, Somrishi Bannerjee")]
 struct Args {
+    #[clap(short, long, help = "TOML file declaring the listeners, settings, logging, RPC endpoint and database instead of their flags; reloaded on SIGHUP")]
+    config: Option<PathBuf>,
     // Redirection
-    #[clap(short = 'b', long, help = "Local port to bind to")]
-    bind_port: u16,
-    #[clap(short = 'l', long, default_value = "0.0.0.0", help = "Local IP to bind to")]
-    bind_ip: Ipv4Addr,
-    #[clap(short, long, help = "Destination port to forward to")]
-    dest_port: u16,
-    #[clap(short = 'r', long, default_value = "127.0.0.1", help = "Destination IP to forward to")]
-    dest_ip: Ipv4Addr,
+    #[clap(short = 'b', long, required_unless_present = "config", conflicts_with = "config", help = "Local port to bind to")]
+    bind_port: Option<u16>,
+    #[clap(short = 'l', long, default_value = "0.0.0.0", conflicts_with = "config", help = "Local IP to bind to; `::` accepts both IPv4 and IPv6")]
+    bind_ip: IpAddr,
+    #[clap(short, long, required_unless_present = "config", conflicts_with = "config", help = "Destination port to forward to")]
+    dest_port: Option<u16>,
+    #[clap(short = 'r', long, default_value = "127.0.0.1", conflicts_with = "config", help = "Destination IP to forward to")]
+    dest_ip: IpAddr,
+    #[clap(long, default_value = "default", conflicts_with = "config", help = "Name of the listener, which rules can match on as `:listener`")]
+    listener_name: String,
+    #[clap(long, value_enum, default_value = "hold", conflicts_with = "config", help = "Whether dropped connections are held open or closed")]
+    drop_policy: DropPolicy,
+    // Stream reassembly
+    #[clap(long, default_value = "4096", conflicts_with = "config", help = "Longest match, in bytes, that content rules find across reads")]
+    stream_window: usize,
+    #[clap(long, default_value = "65536", conflicts_with = "config", help = "Most bytes buffered for each direction of a connection")]
+    stream_buffer: usize,
+    #[clap(long, default_value = "50", conflicts_with = "config", help = "Milliseconds held-back data waits for more before it's forwarded anyway")]
+    stream_flush_ms: u64,
+    // Failover
+    #[clap(long, default_value = "5000", conflicts_with = "config", help = "Milliseconds a connect to an upstream may take")]
+    connect_timeout_ms: u64,
+    #[clap(long, default_value = "2", conflicts_with = "config", help = "Connects tried after the first one fails, on other backends when balancing")]
+    connect_retries: u32,
+    #[clap(long, default_value = "100", conflicts_with = "config", help = "Milliseconds before the first retry; each one after waits twice as long")]
+    retry_backoff_ms: u64,
+    #[clap(long, default_value = "5", conflicts_with = "config", help = "Failed connects in a row that eject a backend from balancing; 0 never ejects")]
+    eject_after: u32,
+    #[clap(long, default_value = "30000", conflicts_with = "config", help = "Milliseconds an ejected backend is left out of balancing")]
+    eject_cooldown_ms: u64,
     // Interactive Settings (for non-daemon mode)
     #[clap(short = 's', long, help = "Log to stdout instead of a file")]
     stdout: bool,
     // Logging configuration
-    #[clap(long, default_value = "info", help = "Maximum log level to display")]
+    #[clap(long, default_value = "info", conflicts_with = "config", help = "Maximum log level to display")]
     log_level: Level,
-    #[clap(long, default_value = "log", help = "Directory to store logs")]
-    log_dir: String,
-    #[clap(long, default_value = "connections.log", help = "File to store logs")]
-    log_file: String,
+    #[clap(long, default_value = "log", conflicts_with = "config", help = "Directory to store logs")]
+    log_dir: PathBuf,
+    #[clap(long, default_value = "connections.log", conflicts_with = "config", help = "File to store logs")]
+    log_file: PathBuf,
+}
+
+impl Args {
+    /// The config the flags declare when there's no config file: a single listener with no program
+    fn config(&self) -> Config {
+        // Ok to unwrap: clap requires both ports without a config file
+        let (bind_port, dest_port) = (self.bind_port.unwrap(), self.dest_port.unwrap());
+        Config {
+            rpc: RPC_BIND,
+            database: PathBuf::from("redirector.db"),
+            log: LogConfig {
+                level: self.log_level,
+                dir: self.log_dir.clone(),
+                file: self.log_file.clone(),
+                stdout: self.stdout,
+            },
+            settings: SettingsConfig {
+                drop_policy: self.drop_policy,
+                stream_window: self.stream_window,
+                stream_buffer: self.stream_buffer,
+                stream_flush_ms: self.stream_flush_ms,
+                connect_timeout_ms: self.connect_timeout_ms,
+                connect_retries: self.connect_retries,
+                retry_backoff_ms: self.retry_backoff_ms,
+                eject_after: self.eject_after,
+                eject_cooldown_ms: self.eject_cooldown_ms,
+            },
+            listeners: vec![ListenerConfig {
+                name: self.listener_name.clone(),
+                bind: SocketAddr::new(self.bind_ip, bind_port),
+                destination: SocketAddr::new(self.dest_ip, dest_port),
+                rules: None,
+                program: None,
+            }],
+        }
+    }
 }
 
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
-
-    // Fake IP checker program (pre-compiled)
-    // If IP != localhost DROP else REDIRECT to DEST_IP:PORT
-    let insns = vec![
-        REDIRECT(0x80000000 | 2, 0x80000000 | 3),
-    ];
-
-    let program = Program {
-        instructions: insns,
-        data: Default::default(),
+    if args.stream_window >= args.stream_buffer {
+        anyhow::bail!("--stream-buffer must be bigger than --stream-window");
+    }
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => args.config(),
     };
 
     let app_state = AppState{
-        conn: Arc::new(Mutex::new(Connection::open("redirector.db").unwrap())),
-        program: Arc::new(Mutex::new(program))
+        conn: Arc::new(Mutex::new(Connection::open(&config.database)?)),
+        listeners: Default::default(),
+        balancer: Default::default(),
+        health: Default::default(),
+        settings: Arc::new(RwLock::new(config.settings.settings())),
     };
 
     // Initialize logging
-    tracing_subscriber::fmt()
-        .with_max_level(args.log_level)
-        .with_writer(move || -> Box<dyn std::io::Write> {
-            match args.stdout {
-                true => Box::new(std::io::stdout()),
-                false => Box::new(RollingFileAppender::new(Rotation::DAILY, args.log_dir.clone(), args.log_file.clone()))
-            }
-        })
+    let log = config.log.clone();
+    let stdout = log.stdout || args.stdout;
+    // The level is behind a handle so a reloaded config can change it
+    let (level, log_level) = reload::Layer::new(LevelFilter::from_level(log.level));
+    let writer = move || -> Box<dyn std::io::Write> {
+        match stdout {
+            true => Box::new(std::io::stdout()),
+            false => Box::new(RollingFileAppender::new(Rotation::DAILY, &log.dir, &log.file))
+        }
+    };
+    tracing_subscriber::registry()
+        .with(level)
+        .with(tracing_subscriber::fmt::layer().with_writer(writer))
         .init();
 
-    // Start redirector
-    let binding = app_state.clone();
-    tokio::spawn(async move { redirect(args.bind_ip, args.bind_port, args.dest_ip, args.dest_port, binding).await } );
+    // Start the listeners with their programs; more can be added over RPC
+    if apply(&[], &config.listeners, &app_state).await != config.listeners {
+        anyhow::bail!("Failed to start the listeners; see the log");
+    }
+
+    // Probe the backends of the programs' upstreams
+    tokio::spawn(check_health(app_state.clone()));
 
     // Start RPC server
-    tokio::spawn(async move { init_rpc(app_state).await });
+    let rpc = config.rpc;
+    let rpc_state = app_state.clone();
+    tokio::spawn(async move { init_rpc(rpc_state, rpc).await });
+
+    // Apply changes to the config file when told to
+    if let Some(path) = args.config {
+        tokio::spawn(reload_on_hangup(path, config, app_state, log_level));
+    }
 
     // Wait for both to finish
     future::pending::<()>().await;
@@ -1,8 +1,64 @@
-use std::sync::{Arc, Mutex};
-use rulelib::vm::Program;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use crate::redirector::{Balancer, Health, Listeners};
 
 #[derive(Debug, Clone)]
 pub struct AppState {
     pub conn: Arc<Mutex<rusqlite::Connection>>,
-    pub program: Arc<Mutex<Program>>
+    /// The listeners, each with its own destination and program
+    pub listeners: Listeners,
+    /// Picks the backends of the programs' upstream pools, and counts the connections to them
+    pub balancer: Balancer,
+    /// What the probes of the upstreams' checks found, which balancing follows
+    pub health: Health,
+    /// Replaced when the config file is reloaded; a connection keeps the ones it was accepted with
+    pub settings: Arc<RwLock<Settings>>,
+}
+
+/// How connections are handled, whichever listener accepts them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    pub drop_policy: DropPolicy,
+    pub limits: StreamLimits,
+    pub failover: Failover,
+}
+
+/// What happens to a connection once the program decides to `DROP` it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DropPolicy {
+    /// Keep the connection open and silently discard whatever the client sends
+    Hold,
+    /// Quietly close the connection without sending anything back
+    Close,
+}
+
+/// How much of each direction of a connection is buffered, so content rules see its byte stream
+/// rather than each read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamLimits {
+    /// The most that's held back while a needle or pattern may still match it, which is how long
+    /// a match can be and still be found across reads
+    pub window: usize,
+    /// The most that's ever buffered; reads bigger than this less the window are taken in pieces
+    pub cap: usize,
+    /// How long held-back data waits for more before it's forwarded anyway
+    pub flush_after: Duration,
+}
+
+/// How the redirector connects to upstreams, and how it gives up on backends that keep failing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Failover {
+    /// How long a connect may take before it counts as failed
+    pub connect_timeout: Duration,
+    /// How many more connects are tried after the first one fails, on another backend of the
+    /// pool while there are any left to try
+    pub retries: u32,
+    /// How long the first retry waits; each one after that waits twice as long as the last
+    pub backoff: Duration,
+    /// How many connects in a row a backend can fail before it's ejected from balancing; 0 never
+    /// ejects
+    pub eject_after: u32,
+    /// How long an ejected backend is left out of balancing
+    pub cooldown: Duration,
 }
@@ -0,0 +1,268 @@
+//! Picking a backend from an upstream pool, and keeping count of the connections to each backend
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use rulelib::vm::{Backend, Pool, Strategy};
+
+use super::Health;
+
+/// Shared by every connection, so the counts survive a new program being set
+#[derive(Debug, Clone, Default)]
+pub struct Balancer {
+    state: Arc<Mutex<BalancerState>>,
+}
+
+#[derive(Debug, Default)]
+struct BalancerState {
+    /// The open connections to each backend, whichever pools it's in
+    connections: HashMap<SocketAddr, usize>,
+    /// How many times each pool has been balanced across, by name, for round-robin
+    turns: HashMap<String, u64>,
+}
+
+/// A connection counted against a backend, until it's dropped
+#[derive(Debug)]
+pub struct Lease {
+    addr: SocketAddr,
+    balancer: Balancer,
+}
+
+impl Lease {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let mut state = self.balancer.state.lock().unwrap();
+        if let Some(count) = state.connections.get_mut(&self.addr) {
+            *count -= 1;
+            if *count == 0 {
+                state.connections.remove(&self.addr);
+            }
+        }
+    }
+}
+
+impl Balancer {
+    /// Picks a backend of `pool` for a connection from `client`, following the pool's strategy and
+    /// leaving out the backends that `health` has down. The backends in `avoid` are only picked
+    /// when every other one is down. With every backend down there's none to pick.
+    pub fn pick(
+        &self,
+        pool: &Pool,
+        client: IpAddr,
+        health: &Health,
+        avoid: &[SocketAddr],
+    ) -> Option<Lease> {
+        let mut up: Vec<&Backend> = pool
+            .backends
+            .iter()
+            .filter(|backend| health.is_up(&pool.name, backend.addr))
+            .collect();
+        if up.is_empty() {
+            return None;
+        }
+        if up.iter().any(|backend| !avoid.contains(&backend.addr)) {
+            up.retain(|backend| !avoid.contains(&backend.addr));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let backend = match pool.strategy {
+            Strategy::RoundRobin => {
+                let total: u64 = up.iter().map(|backend| backend.weight as u64).sum();
+                let turn = state.turns.entry(pool.name.clone()).or_default();
+                let backend = weighted(&up, *turn % total);
+                *turn = turn.wrapping_add(1);
+                backend
+            }
+            Strategy::LeastConnections => {
+                let open = |backend: &Backend| {
+                    state.connections.get(&backend.addr).copied().unwrap_or(0) as u64
+                };
+                // compares open / weight without dividing; the first backend wins a tie
+                up.iter()
+                    .min_by(|a, b| {
+                        (open(a) * b.weight as u64).cmp(&(open(b) * a.weight as u64))
+                    })
+                    .expect("there's a backend up")
+            }
+            // rendezvous hashing: each client ranks the backends, and takes the best one that's up,
+            // so a backend going down only moves its own clients
+            Strategy::SourceIpHash => up
+                .iter()
+                .max_by(|a, b| rank(client, a).total_cmp(&rank(client, b)))
+                .expect("there's a backend up"),
+        };
+
+        let addr = backend.addr;
+        *state.connections.entry(addr).or_default() += 1;
+        Some(Lease {
+            addr,
+            balancer: self.clone(),
+        })
+    }
+
+    /// The open connections to a backend
+    pub fn connections(&self, addr: SocketAddr) -> usize {
+        let state = self.state.lock().unwrap();
+        state.connections.get(&addr).copied().unwrap_or(0)
+    }
+}
+
+/// The backend that the `slot`th of the backends' total weight falls to
+fn weighted<'a>(backends: &[&'a Backend], mut slot: u64) -> &'a Backend {
+    for backend in backends {
+        if slot < backend.weight as u64 {
+            return backend;
+        }
+        slot -= backend.weight as u64;
+    }
+    unreachable!("the slot is less than the backends' total weight")
+}
+
+/// How much `client` prefers `backend`, scaled so a backend with twice the weight is the best for
+/// about twice as many clients
+fn rank(client: IpAddr, backend: &Backend) -> f64 {
+    let mut hasher = DefaultHasher::new();
+    (client, backend.addr).hash(&mut hasher);
+    // the top 53 bits, as a float in [0, 1)
+    let unit = (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64;
+    -(backend.weight as f64) / unit.ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rulelib::vm::HealthCheck;
+    use std::time::Duration;
+
+    fn pool(strategy: Strategy, weights: &[u32]) -> Pool {
+        Pool {
+            name: "web".to_string(),
+            strategy,
+            backends: weights
+                .iter()
+                .enumerate()
+                .map(|(i, &weight)| Backend {
+                    addr: SocketAddr::from(([127, 0, 0, 1], 8000 + i as u16)),
+                    weight,
+                })
+                .collect(),
+            check: None,
+        }
+    }
+
+    fn pick(balancer: &Balancer, pool: &Pool, client: IpAddr) -> Lease {
+        balancer.pick(pool, client, &Health::default(), &[]).unwrap()
+    }
+
+    fn port(lease: &Lease) -> u16 {
+        lease.addr().port()
+    }
+
+    #[test]
+    fn round_robin_follows_the_weights() {
+        let balancer = Balancer::default();
+        let pool = pool(Strategy::RoundRobin, &[2, 1]);
+        let client = IpAddr::from([10, 0, 0, 1]);
+
+        let ports: Vec<_> = (0..6).map(|_| port(&pick(&balancer, &pool, client))).collect();
+        assert_eq!(ports, [8000, 8000, 8001, 8000, 8000, 8001]);
+    }
+
+    #[test]
+    fn least_connections_counts_open_leases() {
+        let balancer = Balancer::default();
+        let pool = pool(Strategy::LeastConnections, &[1, 2]);
+        let client = IpAddr::from([10, 0, 0, 1]);
+
+        let first = pick(&balancer, &pool, client);
+        let second = pick(&balancer, &pool, client);
+        let third = pick(&balancer, &pool, client);
+        // the second backend can take twice as many
+        assert_eq!((port(&first), port(&second), port(&third)), (8000, 8001, 8001));
+        assert_eq!(balancer.connections(first.addr()), 1);
+        assert_eq!(balancer.connections(second.addr()), 2);
+
+        drop(first);
+        assert_eq!(balancer.connections(SocketAddr::from(([127, 0, 0, 1], 8000))), 0);
+        assert_eq!(port(&pick(&balancer, &pool, client)), 8000);
+    }
+
+    #[test]
+    fn source_ip_hash_sticks_to_a_backend() {
+        let balancer = Balancer::default();
+        let pool = pool(Strategy::SourceIpHash, &[1, 1, 1, 1]);
+
+        for last in 0..16 {
+            let client = IpAddr::from([10, 0, 0, last]);
+            let first = port(&pick(&balancer, &pool, client));
+            assert!((0..4).all(|_| port(&pick(&balancer, &pool, client)) == first));
+        }
+        let spread: std::collections::HashSet<_> = (0..64)
+            .map(|last| port(&pick(&balancer, &pool, IpAddr::from([10, 0, 1, last]))))
+            .collect();
+        assert!(spread.len() > 1);
+    }
+
+    #[test]
+    fn leaves_out_backends_that_are_down() {
+        let balancer = Balancer::default();
+        let health = Health::default();
+        let mut pool = pool(Strategy::SourceIpHash, &[1, 1, 1, 1]);
+        pool.check = Some(HealthCheck {
+            interval: Duration::from_secs(1),
+            send: vec![],
+            expect: vec![],
+        });
+        let pools = [Arc::new(pool.clone())];
+        health.track(&pools);
+        let clients: Vec<_> = (0..64).map(|last| IpAddr::from([10, 0, 2, last])).collect();
+        let before: Vec<_> = clients
+            .iter()
+            .map(|&client| pick(&balancer, &pool, client).addr())
+            .collect();
+
+        let down = pool.backends[0].addr;
+        health.record("web", down, Err("refused".to_string()));
+        for (&client, &addr) in clients.iter().zip(&before) {
+            let lease = balancer.pick(&pool, client, &health, &[]).unwrap();
+            assert_ne!(lease.addr(), down);
+            // only the clients of the backend that went down move
+            if addr != down {
+                assert_eq!(lease.addr(), addr);
+            }
+        }
+
+        pool.strategy = Strategy::RoundRobin;
+        for _ in 0..6 {
+            let lease = balancer.pick(&pool, clients[0], &health, &[]).unwrap();
+            assert_ne!(lease.addr(), down);
+        }
+        for backend in &pool.backends {
+            health.record("web", backend.addr, Err("refused".to_string()));
+        }
+        assert!(balancer.pick(&pool, clients[0], &health, &[]).is_none());
+    }
+
+    #[test]
+    fn avoids_backends_while_there_are_others() {
+        let balancer = Balancer::default();
+        let health = Health::default();
+        let pool = pool(Strategy::SourceIpHash, &[1, 1]);
+        let client = IpAddr::from([10, 0, 0, 1]);
+        let (first, second) = (pool.backends[0].addr, pool.backends[1].addr);
+
+        let lease = balancer.pick(&pool, client, &health, &[first]).unwrap();
+        assert_eq!(lease.addr(), second);
+        let lease = balancer.pick(&pool, client, &health, &[second]).unwrap();
+        assert_eq!(lease.addr(), first);
+        assert!(balancer.pick(&pool, client, &health, &[first, second]).is_some());
+    }
+}
@@ -0,0 +1,353 @@
+//! Probing the backends of the active program's upstreams, so balancing leaves out the ones that are down
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rulelib::vm::{HealthCheck, Object, Pool, Program};
+use shared::model::BackendHealth;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Instant};
+use tracing::{info, warn};
+
+use crate::model::{AppState, Failover};
+
+/// How often the prober looks for backends that are due a probe
+const TICK: Duration = Duration::from_millis(100);
+
+/// The health of each backend of the listeners' checked upstreams, and of the backends failing to
+/// connect. Upstreams of different listeners that share a name share their backends' health.
+#[derive(Debug, Clone, Default)]
+pub struct Health {
+    state: Arc<Mutex<HealthState>>,
+}
+
+#[derive(Debug, Default)]
+struct HealthState {
+    /// What the probes found, by upstream name, then backend address
+    checks: HashMap<String, HashMap<SocketAddr, BackendHealth>>,
+    /// The backends whose last connect failed, whichever pools they're in
+    failures: HashMap<SocketAddr, Failures>,
+}
+
+#[derive(Debug, Default)]
+struct Failures {
+    in_a_row: u32,
+    /// Once ejected, a backend that fails again after its cooldown is ejected straight away
+    ejected_until: Option<Instant>,
+}
+
+impl Health {
+    /// Whether balancing across `upstream` may pick the backend at `addr`; a failed probe or an
+    /// ejection leaves it out
+    pub fn is_up(&self, upstream: &str, addr: SocketAddr) -> bool {
+        let state = self.state.lock().unwrap();
+        let health = state.checks.get(upstream).and_then(|backends| backends.get(&addr));
+        !matches!(health, Some(BackendHealth::Down(_))) && ejected_for(&state, addr).is_none()
+    }
+
+    /// The health of a backend of `pool`
+    pub fn get(&self, pool: &Pool, addr: SocketAddr) -> BackendHealth {
+        let state = self.state.lock().unwrap();
+        if let Some(left) = ejected_for(&state, addr) {
+            return BackendHealth::Ejected(left);
+        }
+        if pool.check.is_none() {
+            return BackendHealth::Unchecked;
+        }
+        state
+            .checks
+            .get(&pool.name)
+            .and_then(|backends| backends.get(&addr))
+            .cloned()
+            .unwrap_or(BackendHealth::Pending)
+    }
+
+    /// Records that a connect to a backend worked, which forgives its failures
+    pub fn connected(&self, addr: SocketAddr) {
+        self.state.lock().unwrap().failures.remove(&addr);
+    }
+
+    /// Records that a connect to a backend failed, returning whether that ejects it
+    pub fn connect_failed(&self, addr: SocketAddr, failover: &Failover) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let failures = state.failures.entry(addr).or_default();
+        failures.in_a_row += 1;
+        if failover.eject_after == 0 || failures.in_a_row < failover.eject_after {
+            return false;
+        }
+        failures.ejected_until = Some(Instant::now() + failover.cooldown);
+        true
+    }
+
+    /// Tracks the backends of `pools`, keeping what's known about the ones already tracked and
+    /// forgetting the rest
+    pub(super) fn track(&self, pools: &[Arc<Pool>]) {
+        let mut state = self.state.lock().unwrap();
+        let mut tracked = HashMap::new();
+        for pool in pools {
+            let known = state.checks.get(&pool.name);
+            let backends: &mut HashMap<_, _> = tracked.entry(pool.name.clone()).or_default();
+            for backend in &pool.backends {
+                let health = known.and_then(|known| known.get(&backend.addr));
+                let health = health.cloned().unwrap_or(BackendHealth::Pending);
+                backends.insert(backend.addr, health);
+            }
+        }
+        state.checks = tracked;
+    }
+
+    /// Records the result of a probe, logging when a backend goes down or comes back up. Probes
+    /// of backends that stopped being tracked while they ran are ignored.
+    pub(super) fn record(&self, upstream: &str, addr: SocketAddr, result: Result<(), String>) {
+        let mut state = self.state.lock().unwrap();
+        let Some(health) = state.checks.get_mut(upstream).and_then(|backends| backends.get_mut(&addr)) else {
+            return;
+        };
+        let new = match result {
+            Ok(()) => BackendHealth::Up,
+            Err(reason) => BackendHealth::Down(reason),
+        };
+        match (&*health, &new) {
+            (BackendHealth::Down(_), BackendHealth::Up) => {
+                info!("Backend {} of `{}` is up again", addr, upstream)
+            }
+            (BackendHealth::Down(_), BackendHealth::Down(_)) => {}
+            (_, BackendHealth::Down(reason)) => {
+                warn!("Backend {} of `{}` is down: {}", addr, upstream, reason)
+            }
+            _ => {}
+        }
+        *health = new;
+    }
+}
+
+/// How much longer a backend is ejected for, if it is
+fn ejected_for(state: &HealthState, addr: SocketAddr) -> Option<Duration> {
+    let until = state.failures.get(&addr)?.ejected_until?;
+    let left = until.saturating_duration_since(Instant::now());
+    (!left.is_zero()).then_some(left)
+}
+
+/// The upstreams of `program`, by name
+pub fn pools(program: &Program) -> Vec<Arc<Pool>> {
+    let mut pools: Vec<_> = program
+        .data
+        .values()
+        .filter_map(|object| match object {
+            Object::Pool(pool) => Some(pool.clone()),
+            _ => None,
+        })
+        .collect();
+    pools.sort_by(|a, b| a.name.cmp(&b.name));
+    pools
+}
+
+/// Probes the backends of the listeners' upstreams that have a check, each as often as the check
+/// says. Runs for as long as the redirector does, following whichever programs are set.
+pub async fn check_health(app_state: AppState) {
+    let mut due: HashMap<(String, SocketAddr), Instant> = HashMap::new();
+    let mut ticker = tokio::time::interval(TICK);
+    loop {
+        ticker.tick().await;
+        let checked: Vec<_> = app_state
+            .listeners
+            .pools()
+            .into_iter()
+            .map(|(_, pool)| pool)
+            .filter(|pool| pool.check.is_some())
+            .collect();
+        app_state.health.track(&checked);
+
+        let now = Instant::now();
+        let mut next = HashMap::new();
+        for pool in &checked {
+            let check = pool.check.as_ref().expect("only checked pools are probed");
+            for backend in &pool.backends {
+                let key = (pool.name.clone(), backend.addr);
+                // probed once, however many listeners share the upstream
+                if next.contains_key(&key) {
+                    continue;
+                }
+                match due.get(&key) {
+                    Some(&at) if at > now => {
+                        next.insert(key, at);
+                        continue;
+                    }
+                    _ => next.insert(key, now + check.interval),
+                };
+
+                let (health, pool, check, addr) =
+                    (app_state.health.clone(), pool.clone(), check.clone(), backend.addr);
+                tokio::spawn(async move {
+                    let result = probe(addr, &check).await;
+                    health.record(&pool.name, addr, result);
+                });
+            }
+        }
+        due = next;
+    }
+}
+
+/// Connects to `addr`, then sends and expects what the check says; a probe that takes longer than
+/// the check's interval fails
+async fn probe(addr: SocketAddr, check: &HealthCheck) -> Result<(), String> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(addr)
+            .await
+            .map_err(|e| format!("failed to connect: {}", e))?;
+        if !check.send.is_empty() {
+            stream
+                .write_all(&check.send)
+                .await
+                .map_err(|e| format!("failed to send: {}", e))?;
+        }
+
+        let mut reply = vec![];
+        let mut buf = [0; 1024];
+        while reply.len() < check.expect.len() {
+            let n = stream
+                .read(&mut buf)
+                .await
+                .map_err(|e| format!("failed to read a reply: {}", e))?;
+            if n == 0 {
+                return Err("closed the connection before replying in full".to_string());
+            }
+            reply.extend_from_slice(&buf[..n]);
+            let len = reply.len().min(check.expect.len());
+            if reply[..len] != check.expect[..len] {
+                return Err(format!("replied {:?}", String::from_utf8_lossy(&reply)));
+            }
+        }
+        Ok(())
+    };
+    timeout(check.interval, exchange)
+        .await
+        .unwrap_or_else(|_| Err(format!("timed out after {:?}", check.interval)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redirector::testing::closed_port;
+    use rulelib::vm::{Backend, Strategy};
+    use tokio::net::TcpListener;
+
+    fn check(send: &[u8], expect: &[u8]) -> HealthCheck {
+        HealthCheck {
+            interval: Duration::from_millis(500),
+            send: send.to_vec(),
+            expect: expect.to_vec(),
+        }
+    }
+
+    /// A backend that answers every connection with `reply` once it has read `request`
+    async fn backend(request: &'static [u8], reply: &'static [u8]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0; request.len()];
+                // a bare connect closes without sending anything
+                if stream.read_exact(&mut buf).await.is_ok() {
+                    stream.write_all(reply).await.unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn probes_connect_and_exchange_bytes() {
+        let redis = backend(b"PING\r\n", b"+PONG\r\n").await;
+        assert_eq!(probe(redis, &check(b"", b"")).await, Ok(()));
+        assert_eq!(probe(redis, &check(b"PING\r\n", b"+PONG")).await, Ok(()));
+
+        let result = probe(redis, &check(b"PING\r\n", b"-ERR")).await;
+        assert_eq!(result, Err("replied \"+PONG\\r\\n\"".to_string()));
+
+        assert!(probe(closed_port().await, &check(b"", b"")).await.is_err());
+
+        // a backend that never answers times out
+        let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let result = probe(silent.local_addr().unwrap(), &check(b"PING\r\n", b"+PONG")).await;
+        assert!(result.unwrap_err().starts_with("timed out"));
+    }
+
+    #[test]
+    fn records_probes_of_tracked_backends() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8000));
+        let pools = [Arc::new(Pool {
+            name: "web".to_string(),
+            strategy: Strategy::RoundRobin,
+            backends: vec![Backend { addr, weight: 1 }],
+            check: Some(check(b"", b"")),
+        })];
+        let pool = &pools[0];
+        let health = Health::default();
+
+        // untracked backends are up, and probes of them are ignored
+        health.record("web", addr, Err("refused".to_string()));
+        assert!(health.is_up("web", addr));
+
+        health.track(&pools);
+        assert_eq!(health.get(pool, addr), BackendHealth::Pending);
+        health.record("web", addr, Err("refused".to_string()));
+        assert!(!health.is_up("web", addr));
+        assert_eq!(health.get(pool, addr), BackendHealth::Down("refused".to_string()));
+
+        // tracking again keeps what's known, and forgets upstreams that are gone
+        health.track(&pools);
+        assert!(!health.is_up("web", addr));
+        health.record("web", addr, Ok(()));
+        assert_eq!(health.get(pool, addr), BackendHealth::Up);
+        health.track(&[]);
+        assert_eq!(health.get(pool, addr), BackendHealth::Pending);
+    }
+
+    #[test]
+    fn ejects_backends_that_keep_failing_to_connect() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8000));
+        let pool = Pool {
+            name: "web".to_string(),
+            strategy: Strategy::RoundRobin,
+            backends: vec![Backend { addr, weight: 1 }],
+            check: None,
+        };
+        let failover = Failover {
+            connect_timeout: Duration::from_secs(1),
+            retries: 0,
+            backoff: Duration::ZERO,
+            eject_after: 2,
+            cooldown: Duration::from_secs(60),
+        };
+        let health = Health::default();
+
+        // a connect that works forgives the failures before it
+        assert!(!health.connect_failed(addr, &failover));
+        health.connected(addr);
+        assert!(!health.connect_failed(addr, &failover));
+        assert!(health.connect_failed(addr, &failover));
+        assert!(!health.is_up("web", addr));
+        let health_now = health.get(&pool, addr);
+        assert!(matches!(health_now, BackendHealth::Ejected(left) if left > Duration::from_secs(59)));
+
+        health.connected(addr);
+        assert!(health.is_up("web", addr));
+        assert_eq!(health.get(&pool, addr), BackendHealth::Unchecked);
+
+        // a cooldown that's over lets the backend back in, until its next failure
+        let failover = Failover { cooldown: Duration::ZERO, ..failover };
+        health.connect_failed(addr, &failover);
+        assert!(health.connect_failed(addr, &failover));
+        assert!(health.is_up("web", addr));
+        assert!(health.connect_failed(addr, &failover));
+
+        let never = Failover { eject_after: 0, ..failover };
+        let other = SocketAddr::from(([127, 0, 0, 1], 8001));
+        assert!((0..10).all(|_| !health.connect_failed(other, &never)));
+    }
+}
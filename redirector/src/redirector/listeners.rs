@@ -0,0 +1,316 @@
+//! The listeners a redirector runs, each with its own destination and program, which can be added
+//! and removed while it runs
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::bail;
+use rulelib::vm::{Pool, Program};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+use super::{bind, handle_connection, health::pools};
+use crate::model::AppState;
+
+/// A port the redirector accepts connections on
+#[derive(Debug, Clone)]
+pub struct Listener {
+    /// What rules see as `:listener`
+    pub name: String,
+    pub bind: SocketAddr,
+    /// Where connections go while there's no program, or when a `REDIRECT` target doesn't resolve
+    pub destination: SocketAddr,
+    /// The active program; `None` until one is set
+    pub program: Arc<Mutex<Option<Program>>>,
+}
+
+impl Listener {
+    pub fn new(name: String, bind: SocketAddr, destination: SocketAddr) -> Self {
+        Self {
+            name,
+            bind,
+            destination,
+            program: Arc::default(),
+        }
+    }
+}
+
+/// Every listener that's running, by name
+#[derive(Debug, Clone, Default)]
+pub struct Listeners {
+    running: Arc<Mutex<BTreeMap<String, Running>>>,
+}
+
+/// A listener that's been stopped, which can be restarted with its program
+#[derive(Debug)]
+pub struct Stopped {
+    listener: Listener,
+    source: Option<String>,
+}
+
+#[derive(Debug)]
+struct Running {
+    listener: Listener,
+    /// Where the active program came from, for listing
+    source: Option<String>,
+    /// Stops accepting connections
+    stop: CancellationToken,
+    /// The task accepting connections, which holds the port until it finishes
+    accepting: JoinHandle<()>,
+}
+
+impl Listeners {
+    /// Binds a listener and starts accepting connections on it, returning the address it's bound
+    /// to. Names are unique.
+    pub fn start(&self, listener: Listener, app_state: &AppState) -> anyhow::Result<SocketAddr> {
+        self.start_with(listener, None, app_state)
+    }
+
+    fn start_with(
+        &self,
+        mut listener: Listener,
+        source: Option<String>,
+        app_state: &AppState,
+    ) -> anyhow::Result<SocketAddr> {
+        let mut running = self.running.lock().unwrap();
+        if running.contains_key(&listener.name) {
+            bail!("a listener named `{}` already exists", listener.name);
+        }
+        let tcp = match bind(listener.bind) {
+            Ok(tcp) => tcp,
+            Err(e) => bail!("failed to bind {}: {}", listener.bind, e),
+        };
+        listener.bind = tcp.local_addr()?;
+        info!(
+            "Forwarding from {} ({}) to {}",
+            listener.bind, listener.name, listener.destination
+        );
+
+        let stop = CancellationToken::new();
+        let accepting =
+            tokio::spawn(accept(tcp, listener.clone(), stop.clone(), app_state.clone()));
+        let addr = listener.bind;
+        running.insert(
+            listener.name.clone(),
+            Running {
+                listener,
+                source,
+                stop,
+                accepting,
+            },
+        );
+        Ok(addr)
+    }
+
+    /// Starts a stopped listener again at another address or destination, keeping its program,
+    /// and returns the address it's bound to. If the new port can't be bound, it stays stopped.
+    pub fn restart(
+        &self,
+        stopped: Stopped,
+        bind: SocketAddr,
+        destination: SocketAddr,
+        app_state: &AppState,
+    ) -> anyhow::Result<SocketAddr> {
+        let listener = Listener {
+            bind,
+            destination,
+            ..stopped.listener
+        };
+        self.start_with(listener, stopped.source, app_state)
+    }
+
+    /// Stops a listener accepting connections and lets go of its port, returning it if there was
+    /// one by that name. The connections it already accepted carry on with its program.
+    pub async fn stop(&self, name: &str) -> Option<Stopped> {
+        let running = self.running.lock().unwrap().remove(name)?;
+        running.stop.cancel();
+        let _ = running.accepting.await;
+        Some(Stopped {
+            listener: running.listener,
+            source: running.source,
+        })
+    }
+
+    /// Sets the program of a listener, returning whether there was one by that name. `source`
+    /// says where the program came from.
+    pub fn set_program(&self, name: &str, program: Program, source: String) -> bool {
+        let mut running = self.running.lock().unwrap();
+        let Some(running) = running.get_mut(name) else {
+            return false;
+        };
+        *running.listener.program.lock().unwrap() = Some(program);
+        running.source = Some(source);
+        true
+    }
+
+    pub fn get(&self, name: &str) -> Option<Listener> {
+        let running = self.running.lock().unwrap();
+        running.get(name).map(|running| running.listener.clone())
+    }
+
+    /// Every listener, by name, with where its program came from
+    pub fn list(&self) -> Vec<(Listener, Option<String>)> {
+        let running = self.running.lock().unwrap();
+        running
+            .values()
+            .map(|running| (running.listener.clone(), running.source.clone()))
+            .collect()
+    }
+
+    /// The upstreams of every listener's program, by listener
+    pub fn pools(&self) -> Vec<(String, Arc<Pool>)> {
+        let mut all = vec![];
+        for (listener, _) in self.list() {
+            if let Some(program) = listener.program.lock().unwrap().as_ref() {
+                all.extend(pools(program).into_iter().map(|pool| (listener.name.clone(), pool)));
+            }
+        }
+        all
+    }
+}
+
+/// How long a listener waits after failing to accept a connection before trying again
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Accepts connections on a listener until it's stopped. Failing to accept doesn't stop it.
+async fn accept(
+    tcp: TcpListener,
+    listener: Listener,
+    stop: CancellationToken,
+    app_state: AppState,
+) {
+    loop {
+        let inbound = tokio::select! {
+            _ = stop.cancelled() => break,
+            accepted = tcp.accept() => match accepted {
+                Ok((inbound, _)) => inbound,
+                // e.g. running out of file descriptors, which passes as connections close
+                Err(e) => {
+                    error!(
+                        "Error accepting on {} ({}): {}; retrying in {:?}",
+                        listener.bind, listener.name, e, ACCEPT_BACKOFF
+                    );
+                    tokio::select! {
+                        _ = stop.cancelled() => break,
+                        _ = tokio::time::sleep(ACCEPT_BACKOFF) => continue,
+                    }
+                }
+            },
+        };
+        tokio::spawn(handle_connection(inbound, listener.clone(), app_state.clone()));
+    }
+    info!("Stopped listening on {} ({})", listener.bind, listener.name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redirector::testing::{forwards_to, test_app_state};
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn listeners_have_their_own_destination_and_program() {
+        let app_state = test_app_state();
+        let listeners = &app_state.listeners;
+        let (web, mail, routed) = (
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        let any = "127.0.0.1:0".parse().unwrap();
+        let web_listener = Listener::new("web".to_string(), any, web.local_addr().unwrap());
+        let web_proxy = listeners.start(web_listener, &app_state).unwrap();
+        let mail_listener = Listener::new("mail".to_string(), any, mail.local_addr().unwrap());
+        let mail_proxy = listeners.start(mail_listener, &app_state).unwrap();
+
+        let duplicate = Listener::new("web".to_string(), any, web.local_addr().unwrap());
+        assert!(listeners.start(duplicate, &app_state).is_err());
+        let taken = Listener::new("other".to_string(), web_proxy, web.local_addr().unwrap());
+        assert!(listeners.start(taken, &app_state).is_err());
+
+        let program = format!(
+            r#"
+            (set-mode OPAQUE)
+            (def-rule only-mail
+                (if (exact? :listener "mail") (REDIRECT "127.0.0.1" {}) REJECT))
+            "#,
+            routed.local_addr().unwrap().port()
+        );
+        let program = rulelib::compile(&program).unwrap();
+        assert!(listeners.set_program("mail", program, "rule file 1 (mail)".to_string()));
+        forwards_to(web_proxy, &web).await;
+        forwards_to(mail_proxy, &routed).await;
+
+        let listed: Vec<_> = listeners
+            .list()
+            .into_iter()
+            .map(|(listener, source)| (listener.name, listener.bind, source))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("mail".to_string(), mail_proxy, Some("rule file 1 (mail)".to_string())),
+                ("web".to_string(), web_proxy, None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn stopped_listeners_stop_accepting() {
+        let app_state = test_app_state();
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listener = Listener::new(
+            "web".to_string(),
+            "127.0.0.1:0".parse().unwrap(),
+            upstream.local_addr().unwrap(),
+        );
+        let proxy = app_state.listeners.start(listener, &app_state).unwrap();
+        forwards_to(proxy, &upstream).await;
+
+        assert!(app_state.listeners.stop("web").await.is_some());
+        assert!(app_state.listeners.stop("web").await.is_none());
+        let program = rulelib::compile("(set-mode OPAQUE) (def-rule r DROP)").unwrap();
+        assert!(!app_state.listeners.set_program("web", program, String::new()));
+        assert!(TcpStream::connect(proxy).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn restarted_listeners_keep_their_port_and_program() {
+        let app_state = test_app_state();
+        let (old, new, routed) = (
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        let listener = Listener::new(
+            "web".to_string(),
+            "127.0.0.1:0".parse().unwrap(),
+            old.local_addr().unwrap(),
+        );
+        let listeners = &app_state.listeners;
+        let proxy = listeners.start(listener, &app_state).unwrap();
+        let program = format!(
+            r#"(set-mode OPAQUE) (def-rule r (REDIRECT "127.0.0.1" {}))"#,
+            routed.local_addr().unwrap().port()
+        );
+        let program = rulelib::compile(&program).unwrap();
+        assert!(listeners.set_program("web", program, "web.rf".to_string()));
+
+        let destination = new.local_addr().unwrap();
+        let stopped = listeners.stop("web").await.unwrap();
+        let restarted = listeners.restart(stopped, proxy, destination, &app_state);
+        assert_eq!(restarted.unwrap(), proxy);
+        forwards_to(proxy, &routed).await;
+        let (listener, source) = listeners.list().remove(0);
+        assert_eq!(listener.destination, destination);
+        assert_eq!(source.as_deref(), Some("web.rf"));
+
+        // it's still running, so its name is taken
+        let stopped = Stopped { listener, source };
+        assert!(listeners.restart(stopped, proxy, destination, &app_state).is_err());
+    }
+}
@@ -1,167 +1,1140 @@
-use crate::model::AppState;
+use crate::model::{AppState, DropPolicy, Failover, Settings, StreamLimits};
 use core::net::SocketAddr;
-use core::str;
-use futures::{future, FutureExt, StreamExt, TryFutureExt};
+use futures::StreamExt;
 use rulelib::vm::Object;
-use rulelib::vm::{Action, Packet, VM};
-use std::cell::RefCell;
-use std::io;
-use std::net::Ipv4Addr;
-use std::process::Output;
-use std::str::FromStr;
-use std::sync::Arc;
-use std::task::{ready, Poll};
-use tokio::io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::macros::support::poll_fn;
+use rulelib::vm::{Action, Evaluation, Packet, Program, Transform, VM};
+use socket2::{Domain, Socket, Type};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpListener, TcpStream};
-use tokio_stream::wrappers::TcpListenerStream;
+use tokio::time::timeout;
 use tokio_util::bytes::Bytes;
-use tokio_util::io::{ReaderStream, StreamReader};
-use tracing::{error, event, info, Level};
+use tokio_util::codec::{BytesCodec, FramedRead};
+use tokio_util::io::ReaderStream;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
 
-fn convert_to_packet(local_addr: SocketAddr, peer_addr: SocketAddr, content: Bytes) -> Packet {
+use balance::Lease;
+use stream::Reassembly;
+
+mod balance;
+mod health;
+mod listeners;
+mod stream;
+#[cfg(test)]
+pub(crate) mod testing;
+
+pub use balance::Balancer;
+pub use health::{check_health, Health};
+pub use listeners::{Listener, Listeners, Stopped};
+
+fn convert_to_packet(
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+    listener: &Arc<Vec<u8>>,
+    content: Arc<Vec<u8>>,
+    offset: usize,
+    end: bool,
+) -> Packet {
     Packet {
-        // Disgusting type nonsense
-        source: (
-            Ipv4Addr::from_str(&local_addr.ip().to_string()).unwrap(),
-            local_addr.port(),
-        ),
-        dest: (
-            Ipv4Addr::from_str(&peer_addr.ip().to_string()).unwrap(),
-            peer_addr.port(),
-        ),
-        content: Arc::new(Vec::from(content)),
+        // A dual-stack listener sees IPv4 clients as IPv4-mapped IPv6 addresses
+        source: (peer_addr.ip().to_canonical(), peer_addr.port()),
+        dest: (local_addr.ip().to_canonical(), local_addr.port()),
+        content,
+        offset,
+        end,
+        listener: listener.clone(),
     }
 }
 
-fn filter(packet: Packet, app_state: &AppState) -> Action {
-    let mut vm = VM::new();
+/// Runs the active program on a packet of the data buffered in `stream`, returning its action,
+/// the rewrites to apply to the data on the way, and where the part its content rules may still
+/// match, which is held back, begins. Nothing is held back when `window` is 0.
+/// With no program loaded, everything is redirected to the fallback destination.
+fn filter(
+    packet: Packet,
+    program: &Mutex<Option<Program>>,
+    fallback: SocketAddr,
+    stream: &mut Reassembly,
+    window: usize,
+) -> (Action, Vec<Transform>, usize) {
     // ok to unwrap here: if the unwrap fails something has gone very wrong
-    let program = app_state.program.lock().unwrap();
-    let result = vm.run_program(&*program, &packet);
+    let program = program.lock().unwrap();
+    match &*program {
+        Some(program) => {
+            let (action, transforms) = run(program, &packet);
+            (action, transforms, stream.holdback(&program.needles, window))
+        }
+        None => (fallback_action(fallback), vec![], packet.content.len()),
+    }
+}
+
+/// The action for a whole connection, when it can be decided as soon as the client connects: with
+/// no program loaded, or with one that is evaluated on connect
+fn decide_on_connect(
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+    listener: &Arc<Vec<u8>>,
+    program: &Mutex<Option<Program>>,
+    fallback: SocketAddr,
+) -> Option<Action> {
+    let program = program.lock().unwrap();
+    match &*program {
+        None => Some(fallback_action(fallback)),
+        Some(program) if program.evaluation == Evaluation::OnConnect => {
+            // there's no content to rewrite on connect
+            let content = Arc::default();
+            let packet = convert_to_packet(peer_addr, local_addr, listener, content, 0, true);
+            Some(run(program, &packet).0)
+        }
+        Some(_) => None,
+    }
+}
+
+fn run(program: &Program, packet: &Packet) -> (Action, Vec<Transform>) {
+    let mut vm = VM::new();
+    let result = vm.run_program(program, packet);
     if result.is_err() {
         error!("Error running program: {:?}", result.err().unwrap());
-        return Action::DROP;
+        return (Action::DROP, vec![]);
     }
     result.unwrap()
 }
 
-pub async fn redirect(
-    bind_ip: Ipv4Addr,
-    bind_port: u16,
-    dest_ip: Ipv4Addr,
-    dest_port: u16,
-    app_state: AppState,
-) {
-    let listener = TcpListener::bind(format!("{}:{}", bind_ip, bind_port))
-        .await
-        .unwrap(); // We should panic here as a failure this early is unrecoverable
-
-    event!(
-        Level::INFO,
-        "Forwarding from {}:{} to {}:{}",
-        bind_ip,
-        bind_port,
-        dest_ip,
-        dest_port
-    );
-
-    while let Ok((mut inbound, _)) = listener.accept().await {
-        println!("Connection opened");
-
-        // Unwrapping because if we can't get this, something has gone terribly wrong anyway
-        let local_addr = inbound.local_addr().unwrap();
-        let peer_addr = inbound.peer_addr().unwrap();
-
-        let mut outbound =
-            match tokio::net::TcpStream::connect(format!("{}:{}", dest_ip, dest_port)).await {
-                Ok(s) => {
-                    info!(
-                        "Received connection from {}",
-                        inbound
-                            .peer_addr()
-                            .expect("Failed to parse inbound address")
-                    );
-                    s
+fn fallback_action(fallback: SocketAddr) -> Action {
+    Action::REDIRECT(Object::IP(fallback.ip()), Object::Port(fallback.port()))
+}
+
+/// Turns the operands of an `Action::REDIRECT` into the address to connect to
+fn redirect_target(destination: &Object, port: &Object) -> Option<SocketAddr> {
+    match (destination, port) {
+        (Object::IP(ip), Object::Port(port)) => Some(SocketAddr::from((*ip, *port))),
+        _ => None,
+    }
+}
+
+/// The address a `REDIRECT` or `BALANCE` sends a connection to. The backend a `BALANCE` picks has
+/// the connection counted against it for as long as the returned lease is kept, and isn't one in
+/// `failed` while there are others. There's nowhere to send it when every backend of the pool is
+/// down.
+fn route(
+    action: &Action,
+    peer_addr: SocketAddr,
+    fallback: SocketAddr,
+    app_state: &AppState,
+    failed: &[SocketAddr],
+) -> Option<(SocketAddr, Option<Lease>)> {
+    match action {
+        Action::REDIRECT(destination, port) => {
+            Some((redirect_target(destination, port).unwrap_or(fallback), None))
+        }
+        Action::BALANCE(pool) => {
+            let client = peer_addr.ip().to_canonical();
+            let Some(lease) = app_state.balancer.pick(pool, client, &app_state.health, failed)
+            else {
+                error!(
+                    "Closing connection from {}: every backend of `{}` is down",
+                    peer_addr, pool.name
+                );
+                return None;
+            };
+            info!(
+                "Balancing connection from {} across `{}` to {} ({} open)",
+                peer_addr,
+                pool.name,
+                lease.addr(),
+                app_state.balancer.connections(lease.addr())
+            );
+            Some((lease.addr(), Some(lease)))
+        }
+        _ => unreachable!("only REDIRECT and BALANCE route a connection"),
+    }
+}
+
+/// Connects the upstream a `REDIRECT` or `BALANCE` sends a connection to. A connect that fails or
+/// times out is retried after a backoff, on another backend of the pool while there are any left
+/// to try, and a backend that fails too many connects in a row is ejected from balancing.
+async fn connect(
+    action: &Action,
+    peer_addr: SocketAddr,
+    fallback: SocketAddr,
+    failover: Failover,
+    app_state: &AppState,
+) -> Option<(TcpStream, SocketAddr, Option<Lease>)> {
+    let mut failed = vec![];
+    let mut backoff = failover.backoff;
+    for attempt in 0..=failover.retries {
+        let (target, lease) = route(action, peer_addr, fallback, app_state, &failed)?;
+        let error = match timeout(failover.connect_timeout, TcpStream::connect(target)).await {
+            Ok(Ok(outbound)) => {
+                if lease.is_some() {
+                    app_state.health.connected(target);
                 }
-                Err(e) => {
-                    error!("Error connecting to destination: {}", e);
-                    continue;
+                return Some((outbound, target, lease));
+            }
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => format!("timed out after {:?}", failover.connect_timeout),
+        };
+
+        if lease.is_some() && app_state.health.connect_failed(target, &failover) {
+            warn!(
+                "Ejecting backend {} for {:?} after {} failed connects in a row, the last from {}",
+                target, failover.cooldown, failover.eject_after, peer_addr
+            );
+        }
+        if attempt == failover.retries {
+            error!(
+                "Closing connection from {}: connecting to {} failed ({}), after {} attempts",
+                peer_addr,
+                target,
+                error,
+                attempt + 1
+            );
+            break;
+        }
+        warn!(
+            "Connecting {} to {} failed ({}); retrying in {:?}",
+            peer_addr, target, error, backoff
+        );
+        failed.push(target);
+        tokio::time::sleep(backoff).await;
+        backoff = backoff.saturating_mul(2);
+    }
+    None
+}
+
+/// What the task copying responses back to the client needs to run the response rules
+#[derive(Clone)]
+struct ResponseFilter {
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+    listener: Arc<Vec<u8>>,
+    program: Arc<Mutex<Option<Program>>>,
+    drop_policy: DropPolicy,
+    limits: StreamLimits,
+}
+
+impl ResponseFilter {
+    /// Runs the active program's response rules on the data from the upstream buffered in `stream`,
+    /// returning their action, their rewrites and where the part they may still match begins, like
+    /// `filter`. With no program loaded, one without response rules, or when every rule continues,
+    /// there's no action to take.
+    fn filter(
+        &self,
+        stream: &mut Reassembly,
+        window: usize,
+    ) -> (Option<Action>, Vec<Transform>, usize) {
+        let program = self.program.lock().unwrap();
+        let program = match program.as_ref() {
+            Some(program) if !program.response.is_empty() => program,
+            _ => return (None, vec![], stream.buffered().len()),
+        };
+
+        let packet = convert_to_packet(
+            self.peer_addr,
+            self.local_addr,
+            &self.listener,
+            stream.content(),
+            stream.offset(),
+            window == 0,
+        );
+        let mut vm = VM::new();
+        let (action, transforms) = match vm.run_response(program, &packet) {
+            Ok(result) => result,
+            Err(e) => {
+                error!("Error running response rules: {:?}", e);
+                (Some(Action::DROP), vec![])
+            }
+        };
+        (action, transforms, stream.holdback(&program.response_needles, window))
+    }
+}
+
+/// The upstream side of a proxied connection
+struct Upstream {
+    tx: OwnedWriteHalf,
+    /// Stops the task copying responses back to the client, which owns the client's write half
+    closed: CancellationToken,
+    /// Cancelled by that task when the response rules close the connection. By then it has
+    /// given up the client's write half, so the inbound task only has to let go of the socket.
+    client_closed: CancellationToken,
+    /// Counts the connection against its backend, when a `BALANCE` picked it
+    _lease: Option<Lease>,
+}
+
+/// Starts copying the responses of a connection's upstream back to the client, through the
+/// response rules
+fn connect_upstream(
+    outbound: TcpStream,
+    target: SocketAddr,
+    lease: Option<Lease>,
+    mut itx: OwnedWriteHalf,
+    responses: &ResponseFilter,
+) -> Upstream {
+    let peer_addr = responses.peer_addr;
+    info!("Forwarding connection from {} to {}", peer_addr, target);
+
+    let (orx, tx) = outbound.into_split();
+    let mut outbound_reader_stream = ReaderStream::new(orx);
+    let closed = CancellationToken::new();
+    let client_closed = CancellationToken::new();
+    let token = closed.clone();
+    let client_token = client_closed.clone();
+    let responses = responses.clone();
+    tokio::spawn(async move {
+        let limits = responses.limits;
+        let mut stream = Reassembly::new(limits);
+        'connection: loop {
+            // `flush` decides on whatever is held back, rather than waiting for more
+            let (bytes, flush, eof) = tokio::select! {
+                _ = token.cancelled() => {
+                    // Leave the socket to the inbound task, which decides how it gets closed
+                    itx.forget();
+                    break;
+                }
+                result = outbound_reader_stream.next() => match result {
+                    Some(Ok(bytes)) => (bytes, false, false),
+                    _ if stream.is_empty() => break,
+                    _ => (Bytes::new(), true, true),
+                },
+                _ = tokio::time::sleep(limits.flush_after), if !stream.is_empty() => {
+                    (Bytes::new(), true, false)
                 }
             };
 
-        let (orx, mut otx) = outbound.into_split();
-        let (irx, mut itx) = inbound.into_split();
-
-        let mut inbound_reader_stream = ReaderStream::new(irx);
-        let mut outbound_reader_stream = ReaderStream::new(orx);
-
-        let binding = app_state.clone();
-        tokio::spawn(async move {
-            while let Some(result) = inbound_reader_stream.next().await {
-                match result {
-                    Ok(bytes) => {
-                        let packet = convert_to_packet(local_addr, peer_addr, bytes);
-
-                        // Get another handle to packet content so we can modify it in place
-                        let content = packet.content.clone();
-
-                        match filter(packet, &binding) {
-                            Action::REDIRECT(destination, port) => {
-                                if let Some(e) = otx.write_all(&**content).await.err() {
-                                    error!("Error writing to outbound stream: {:?}", e);
-                                    break;
-                                }
-                            }
-                            Action::DROP => {
-                                continue;
-                            }
-                            Action::REJECT => {
-                                continue;
-                            }
-                            Action::REWRITE(find, replace) => {
-                                let find = if let Object::Data(find) = find {
-                                    find
-                                } else {
-                                    unreachable!();
-                                };
-                                let replace = if let Object::Data(replace) = replace {
-                                    replace
-                                } else {
-                                    unreachable!();
-                                };
-                                unsafe {
-                                    let content = str::from_utf8_unchecked(&**content);
-                                    let find = str::from_utf8_unchecked(&**find);
-                                    let replace = str::from_utf8_unchecked(&**replace);
-                                    let res = content.replace(find, replace);
-
-                                    if let Some(e) = otx.write_all((&res).as_bytes()).await.err() {
-                                        error!("Error writing to outbound stream: {:?}", e);
-                                        break;
-                                    }
-                                }
-                            }
-                        }
+            let pieces: Vec<&[u8]> = if flush { vec![&[]] } else { stream.pieces(&bytes).collect() };
+            for piece in pieces {
+                stream.extend(piece);
+                let window = if flush { 0 } else { limits.window };
+                let payload = match responses.filter(&mut stream, window) {
+                    (None, transforms, hold) => stream.rewrite(hold, &transforms, window == 0),
+                    (Some(Action::DROP), _, _) if responses.drop_policy == DropPolicy::Hold => {
+                        stream.clear();
+                        continue;
                     }
-                    Err(e) => {
-                        break;
+                    (Some(Action::DROP), _, _) => {
+                        info!("Dropping connection from {} on a response", peer_addr);
+                        itx.forget();
+                        client_token.cancel();
+                        break 'connection;
                     }
+                    (Some(Action::REJECT), _, _) => {
+                        info!("Rejecting connection from {} on a response", peer_addr);
+                        // see `close`
+                        if let Err(e) = itx.as_ref().set_linger(Some(Duration::ZERO)) {
+                            error!("Error setting linger on inbound stream: {:?}", e);
+                        }
+                        itx.forget();
+                        client_token.cancel();
+                        break 'connection;
+                    }
+                    (Some(action), _, _) => unreachable!("response rules can't {:?}", action),
+                };
+                if let Err(e) = itx.write_all(&payload).await {
+                    error!("Error writing to inbound stream: {:?}", e);
+                    break 'connection;
                 }
             }
-        });
+            if eof {
+                break;
+            }
+        }
+    });
+
+    Upstream {
+        tx,
+        closed,
+        client_closed,
+        _lease: lease,
+    }
+}
+
+/// Tears down a connection the program has decided against.
+/// Dropping a write half shuts it down, which would send a FIN ahead of any RST, so the client's
+/// write half is forgotten instead and the socket closes along with the read half.
+fn close(
+    irx: &OwnedReadHalf,
+    itx: Option<OwnedWriteHalf>,
+    upstream: Option<Upstream>,
+    reset: bool,
+) {
+    if reset {
+        // A zero linger turns the close into an RST, which the client sees as a refused connection
+        if let Err(e) = irx.as_ref().set_linger(Some(Duration::ZERO)) {
+            error!("Error setting linger on inbound stream: {:?}", e);
+        }
+    }
+    if let Some(itx) = itx {
+        itx.forget();
+    }
+    if let Some(upstream) = upstream {
+        upstream.closed.cancel();
+    }
+}
+
+/// Proxies a single inbound connection.
+///
+/// The upstream is only connected once the program has made its first decision, so the
+/// destination of the first `REDIRECT` is used for the rest of the connection. With no program
+/// loaded, or one evaluated on connect, the decision is already known at accept time: it is acted
+/// on before anything is read from the client, and used for every chunk after that.
+async fn handle_connection(inbound: TcpStream, listener: Listener, app_state: AppState) {
+    // Unwrapping because if we can't get this, something has gone terribly wrong anyway
+    let local_addr = inbound.local_addr().unwrap();
+    let peer_addr = inbound.peer_addr().unwrap();
+    info!("Received connection from {} on {}", peer_addr, listener.name);
+
+    let Settings {
+        drop_policy,
+        limits,
+        failover,
+    } = *app_state.settings.read().unwrap();
+    let fallback = listener.destination;
+    let program = listener.program;
+    let listener = Arc::new(listener.name.into_bytes());
+
+    let (irx, itx) = inbound.into_split();
+    let mut itx = Some(itx);
+    let mut upstream: Option<Upstream> = None;
+    let responses = ResponseFilter {
+        peer_addr,
+        local_addr,
+        listener: listener.clone(),
+        program: program.clone(),
+        drop_policy,
+        limits,
+    };
+
+    let decision = decide_on_connect(peer_addr, local_addr, &listener, &program, fallback);
+    match &decision {
+        Some(action @ (Action::REDIRECT(..) | Action::BALANCE(..))) => {
+            let Some((outbound, target, lease)) =
+                connect(action, peer_addr, fallback, failover, &app_state).await
+            else {
+                return;
+            };
+            let itx = itx.take().unwrap();
+            upstream = Some(connect_upstream(outbound, target, lease, itx, &responses));
+        }
+        Some(Action::DROP) if drop_policy == DropPolicy::Close => {
+            info!("Dropping connection from {}", peer_addr);
+            close(&irx, itx, upstream, false);
+            return;
+        }
+        Some(Action::REJECT) => {
+            info!("Rejecting connection from {}", peer_addr);
+            close(&irx, itx, upstream, true);
+            return;
+        }
+        // held connections are read and discarded below, like any other dropped data
+        Some(Action::DROP) => {}
+        None => {}
+    }
+
+    let mut stream = Reassembly::new(limits);
+    // Unlike `ReaderStream`, `FramedRead` hands back the socket when we need to close it
+    let mut inbound_reader_stream = FramedRead::new(irx, BytesCodec::new());
+    'connection: loop {
+        // a token that's never cancelled stands in until there's an upstream
+        let client_closed = upstream
+            .as_ref()
+            .map(|upstream| upstream.client_closed.clone())
+            .unwrap_or_default();
+        // `flush` decides on whatever is held back, rather than waiting for more
+        let (bytes, flush, eof) = tokio::select! {
+            bytes = inbound_reader_stream.next() => match bytes {
+                Some(Ok(bytes)) => (bytes.freeze(), false, false),
+                _ if stream.is_empty() => break,
+                _ => (Bytes::new(), true, true),
+            },
+            _ = client_closed.cancelled() => {
+                // the response rules have already decided how the client's end is closed
+                close(inbound_reader_stream.get_ref(), itx, upstream, false);
+                return;
+            }
+            _ = tokio::time::sleep(limits.flush_after), if !stream.is_empty() => {
+                (Bytes::new(), true, false)
+            }
+        };
 
-        tokio::spawn(async move {
-            while let Some(result) = outbound_reader_stream.next().await {
-                match result {
-                    Ok(bytes) => {
-                        itx.write_all(&bytes).await;
+        let pieces: Vec<&[u8]> = if flush { vec![&[]] } else { stream.pieces(&bytes).collect() };
+        for piece in pieces {
+            stream.extend(piece);
+            let window = if flush { 0 } else { limits.window };
+            let (action, transforms, hold) = match &decision {
+                Some(action) => (action.clone(), vec![], stream.buffered().len()),
+                None => {
+                    let (content, offset, end) = (stream.content(), stream.offset(), window == 0);
+                    let packet =
+                        convert_to_packet(peer_addr, local_addr, &listener, content, offset, end);
+                    filter(packet, &program, fallback, &mut stream, window)
+                }
+            };
+            let payload = match action {
+                Action::REDIRECT(..) | Action::BALANCE(..) => {
+                    stream.rewrite(hold, &transforms, window == 0)
+                }
+                Action::DROP => match drop_policy {
+                    DropPolicy::Hold => {
+                        stream.clear();
+                        continue;
                     }
-                    Err(e) => {
-                        break;
+                    DropPolicy::Close => {
+                        info!("Dropping connection from {}", peer_addr);
+                        close(inbound_reader_stream.get_ref(), itx, upstream, false);
+                        return;
                     }
+                },
+                Action::REJECT => {
+                    info!("Rejecting connection from {}", peer_addr);
+                    close(inbound_reader_stream.get_ref(), itx, upstream, true);
+                    return;
+                }
+            };
+
+            if upstream.is_none() {
+                // the rules may well decide differently once they've seen what's held back
+                if payload.is_empty() && !stream.is_empty() {
+                    continue;
                 }
+                let Some((outbound, target, lease)) =
+                    connect(&action, peer_addr, fallback, failover, &app_state).await
+                else {
+                    break 'connection;
+                };
+                let itx = itx.take().unwrap();
+                upstream = Some(connect_upstream(outbound, target, lease, itx, &responses));
+            }
+
+            let tx = &mut upstream.as_mut().unwrap().tx;
+            if let Err(e) = tx.write_all(&payload).await {
+                error!("Error writing to outbound stream: {:?}", e);
+                break 'connection;
             }
-        });
+        }
+        if eof {
+            break;
+        }
+    }
+}
+
+/// Binds a listener. Binding the unspecified IPv6 address (`::`) accepts IPv4 connections too,
+/// whatever the system default for `IPV6_V6ONLY` is.
+fn bind(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if let IpAddr::V6(ip) = addr.ip() {
+        socket.set_only_v6(!ip.is_unspecified())?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{
+        assert_receives, closed_port, set_program, spawn_proxy, spawn_proxy_on, spawn_test_proxy,
+        test_app_state,
+    };
+    use super::*;
+    use rulelib::ast::ProxyMode::{OPAQUE, TRANSPARENT};
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_redirect_uses_program_target() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let fallback = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let program = format!(
+            r#"
+            (set-mode OPAQUE)
+
+            (def-rule redirect-all
+                (REDIRECT "127.0.0.1" {}))
+            "#,
+            upstream.local_addr().unwrap().port()
+        );
+        let app_state = test_app_state();
+        let fallback = fallback.local_addr().unwrap();
+        let proxy = spawn_proxy(Some(&program), fallback, DropPolicy::Hold, &app_state).await;
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut server = assert_receives(&upstream, b"hello").await;
+
+        // the upstream is kept for the rest of the connection
+        server.write_all(b"world").await.unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+    }
+
+    #[tokio::test]
+    async fn test_fallback_without_program() {
+        let fallback = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = spawn_proxy(
+            None,
+            fallback.local_addr().unwrap(),
+            DropPolicy::Hold,
+            &test_app_state(),
+        )
+        .await;
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        // the fallback is connected before the client says anything
+        let (mut server, _) = fallback.accept().await.unwrap();
+        server.write_all(b"banner").await.unwrap();
+        let mut buf = [0; 6];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"banner");
+    }
+
+    #[tokio::test]
+    async fn test_reject_resets_connection() {
+        let proxy = spawn_test_proxy(OPAQUE, "(def-rule only-rule REJECT)", DropPolicy::Hold).await;
+        let mut client = TcpStream::connect(proxy.addr).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+
+        let mut buf = [0; 1];
+        let err = client.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn test_drop_close_closes_quietly() {
+        let proxy = spawn_test_proxy(OPAQUE, "(def-rule only-rule DROP)", DropPolicy::Close).await;
+        let mut client = TcpStream::connect(proxy.addr).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+
+        let mut buf = [0; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_drop_hold_keeps_connection_open() {
+        let proxy = spawn_test_proxy(OPAQUE, "(def-rule only-rule DROP)", DropPolicy::Hold).await;
+        let mut client = TcpStream::connect(proxy.addr).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_millis(200), client.read(&mut buf)).await;
+        assert!(read.is_err(), "held connection should neither close nor answer");
+
+        // the dropped data never reaches an upstream
+        let accept = tokio::time::timeout(Duration::from_millis(50), proxy.upstream.accept()).await;
+        assert!(accept.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_substitutes_captures_in_binary_content() {
+        let proxy = spawn_test_proxy(
+            TRANSPARENT,
+            r#"(def-rule rewrite-all (REWRITE "user=(\\w+)" "user=<$1>"))"#,
+            DropPolicy::Hold,
+        )
+        .await;
+
+        let mut client = TcpStream::connect(proxy.addr).await.unwrap();
+        client.write_all(b"\xff user=bob\x00").await.unwrap();
+        assert_receives(&proxy.upstream, b"\xff user=<bob>\x00").await;
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_then_redirect() {
+        let proxy = spawn_test_proxy(
+            TRANSPARENT,
+            r#"(def-rule agent (REWRITE "agent=\\w+" "agent=redirector"))
+            (def-rule token (REWRITE "token=\\w+" "token=<redacted>"))
+            (def-rule route (if (contains? :packet-content "agent=curl") CONTINUE DROP))"#,
+            DropPolicy::Hold,
+        )
+        .await;
+
+        // the routing rule sees the content as the client sent it, and the upstream gets it rewritten
+        let mut client = TcpStream::connect(proxy.addr).await.unwrap();
+        client.write_all(b"agent=curl token=abc\n").await.unwrap();
+        assert_receives(&proxy.upstream, b"agent=redirector token=<redacted>\n").await;
+    }
+
+    #[tokio::test]
+    async fn test_balance_round_robins_and_counts_connections() {
+        let backends = [
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        ];
+        let fallback = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let program = format!(
+            r#"
+            (set-mode OPAQUE)
+            (set-eval ON-CONNECT)
+
+            (def-upstream web ROUND-ROBIN ("127.0.0.1" {}) ("127.0.0.1" {}))
+            (def-rule balance (BALANCE web))
+        "#,
+            backends[0].local_addr().unwrap().port(),
+            backends[1].local_addr().unwrap().port()
+        );
+        let app_state = test_app_state();
+        let proxy = spawn_proxy(
+            Some(&program),
+            fallback.local_addr().unwrap(),
+            DropPolicy::Hold,
+            &app_state,
+        )
+        .await;
+
+        let mut clients = vec![];
+        let mut servers = vec![];
+        for i in 0..4 {
+            let mut client = TcpStream::connect(proxy).await.unwrap();
+            client.write_all(&[i]).await.unwrap();
+            clients.push(client);
+            servers.push(assert_receives(&backends[i as usize % 2], &[i]).await);
+        }
+        for backend in &backends {
+            assert_eq!(app_state.balancer.connections(backend.local_addr().unwrap()), 2);
+        }
+
+        // a connection stops counting once it's closed
+        drop(clients.remove(0));
+        let mut buf = [0; 1];
+        assert_eq!(servers[0].read(&mut buf).await.unwrap(), 0);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(app_state.balancer.connections(backends[0].local_addr().unwrap()), 1);
+    }
+
+    #[tokio::test]
+    async fn test_balance_leaves_out_backends_that_fail_their_check() {
+        let up = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let down = closed_port().await;
+        let fallback = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let program = format!(
+            r#"
+            (set-mode OPAQUE)
+            (set-eval ON-CONNECT)
+
+            (def-upstream web ROUND-ROBIN (check 50) ("127.0.0.1" {}) ("127.0.0.1" {}))
+            (def-rule balance (BALANCE web))
+        "#,
+            down.port(),
+            up.local_addr().unwrap().port()
+        );
+        let app_state = test_app_state();
+        tokio::spawn(check_health(app_state.clone()));
+        let proxy = spawn_proxy(
+            Some(&program),
+            fallback.local_addr().unwrap(),
+            DropPolicy::Hold,
+            &app_state,
+        )
+        .await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!app_state.health.is_up("web", down));
+
+        for i in 0..4 {
+            let mut client = TcpStream::connect(proxy).await.unwrap();
+            client.write_all(&[i]).await.unwrap();
+            // the probes connect too, and send nothing
+            loop {
+                let (mut server, _) = up.accept().await.unwrap();
+                let mut buf = [0; 1];
+                if server.read_exact(&mut buf).await.is_ok() {
+                    assert_eq!(buf, [i]);
+                    break;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_balance_fails_over_and_ejects_backends() {
+        let up = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let down = closed_port().await;
+        let fallback = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let program = format!(
+            r#"
+            (set-mode OPAQUE)
+            (set-eval ON-CONNECT)
+
+            (def-upstream web ROUND-ROBIN ("127.0.0.1" {}) ("127.0.0.1" {}))
+            (def-rule balance (BALANCE web))
+        "#,
+            down.port(),
+            up.local_addr().unwrap().port()
+        );
+        let app_state = test_app_state();
+        let proxy = spawn_proxy(
+            Some(&program),
+            fallback.local_addr().unwrap(),
+            DropPolicy::Hold,
+            &app_state,
+        )
+        .await;
+
+        // the first and third connections are sent to the backend that's down, and fail over; the
+        // second failure in a row ejects it, so the rest go straight to the one that's up
+        let mut clients = vec![];
+        for i in 0..5 {
+            let mut client = TcpStream::connect(proxy).await.unwrap();
+            client.write_all(&[i]).await.unwrap();
+            let server = assert_receives(&up, &[i]).await;
+            clients.push((client, server));
+        }
+        assert!(!app_state.health.is_up("web", down));
+        assert_eq!(app_state.balancer.connections(up.local_addr().unwrap()), 5);
+    }
+
+    #[tokio::test]
+    async fn test_redirect_gives_up_after_its_retries() {
+        let down = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let fallback = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let program = format!(
+            r#"
+            (set-mode OPAQUE)
+            (set-eval ON-CONNECT)
+
+            (def-rule nowhere (REDIRECT "127.0.0.1" {}))
+        "#,
+            down.port()
+        );
+        let proxy = spawn_proxy(
+            Some(&program),
+            fallback.local_addr().unwrap(),
+            DropPolicy::Hold,
+            &test_app_state(),
+        )
+        .await;
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(2), client.read(&mut buf)).await;
+        assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+    }
+
+    #[tokio::test]
+    async fn test_ipv6_clients_are_filtered() {
+        let upstream = TcpListener::bind("[::1]:0").await.unwrap();
+        let fallback = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let program = format!(
+            r#"
+            (set-mode OPAQUE)
+
+            (def-rule ipv6-only
+                (if (in-subnet? :packet-source-ip cidr"::1/128")
+                    (REDIRECT ip"::1" {})
+                    REJECT))
+            "#,
+            upstream.local_addr().unwrap().port()
+        );
+        let proxy = spawn_proxy_on(
+            Some(&program),
+            "[::1]:0".parse().unwrap(),
+            fallback.local_addr().unwrap(),
+            DropPolicy::Hold,
+            &test_app_state(),
+        )
+        .await;
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        assert_receives(&upstream, b"hello").await;
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack_bind_sees_ipv4_addresses() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let fallback = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let program = format!(
+            r#"
+            (set-mode OPAQUE)
+
+            (def-rule ipv4-only
+                (if (exact? :packet-source-ip ip"127.0.0.1")
+                    (REDIRECT ip"127.0.0.1" {})
+                    REJECT))
+            "#,
+            upstream.local_addr().unwrap().port()
+        );
+        let proxy = spawn_proxy_on(
+            Some(&program),
+            "[::]:0".parse().unwrap(),
+            fallback.local_addr().unwrap(),
+            DropPolicy::Hold,
+            &test_app_state(),
+        )
+        .await;
+
+        // the client connects over IPv4, which the proxy sees as `::ffff:127.0.0.1`
+        let mut client = TcpStream::connect(("127.0.0.1", proxy.port())).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        assert_receives(&upstream, b"hello").await;
+    }
+
+    #[tokio::test]
+    async fn test_dest_metadata_is_the_local_address_hit() {
+        let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let fallback = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let app_state = test_app_state();
+        // a listener on every interface, like one on a multi-homed host
+        let proxy = spawn_proxy_on(
+            None,
+            "0.0.0.0:0".parse().unwrap(),
+            fallback.local_addr().unwrap(),
+            DropPolicy::Hold,
+            &app_state,
+        )
+        .await;
+        let program = format!(
+            r#"
+            (set-mode OPAQUE)
+
+            (def-rule by-local-address
+                (cond ((not (exact? :packet-dest-port {})) REJECT)
+                      ((exact? :packet-dest-ip ip"127.0.0.2") (REDIRECT "127.0.0.1" {}))
+                      (else (REDIRECT "127.0.0.1" {}))))
+            "#,
+            proxy.port(),
+            second.local_addr().unwrap().port(),
+            first.local_addr().unwrap().port(),
+        );
+        set_program(&app_state, &program);
+
+        let mut client = TcpStream::connect(("127.0.0.1", proxy.port())).await.unwrap();
+        client.write_all(b"first").await.unwrap();
+        assert_receives(&first, b"first").await;
+
+        let mut client = TcpStream::connect(("127.0.0.2", proxy.port())).await.unwrap();
+        client.write_all(b"second").await.unwrap();
+        assert_receives(&second, b"second").await;
+    }
+
+    #[tokio::test]
+    async fn test_source_metadata_is_the_client() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let fallback = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let app_state = test_app_state();
+        let fallback = fallback.local_addr().unwrap();
+        let proxy = spawn_proxy(None, fallback, DropPolicy::Hold, &app_state).await;
+
+        let client = tokio::net::TcpSocket::new_v4().unwrap();
+        client.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let program = format!(
+            r#"
+            (set-mode OPAQUE)
+
+            (def-rule only-this-client
+                (if (and (exact? :listener "test")
+                         (exact? :packet-source-ip ip"127.0.0.1")
+                         (exact? :packet-source-port {}))
+                    (REDIRECT "127.0.0.1" {})
+                    REJECT))
+            "#,
+            client.local_addr().unwrap().port(),
+            upstream.local_addr().unwrap().port(),
+        );
+        set_program(&app_state, &program);
+
+        let mut client = client.connect(proxy).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        assert_receives(&upstream, b"hello").await;
+    }
+
+    #[tokio::test]
+    async fn test_on_connect_reject_resets_before_any_data() {
+        let rules = "(set-eval ON-CONNECT) (def-rule only-rule REJECT)";
+        let proxy = spawn_test_proxy(OPAQUE, rules, DropPolicy::Hold).await;
+
+        let mut client = TcpStream::connect(proxy.addr).await.unwrap();
+        let mut buf = [0; 1];
+        let err = client.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn test_on_connect_drop_close_closes_before_any_data() {
+        let rules = "(set-eval ON-CONNECT) (def-rule only-rule DROP)";
+        let proxy = spawn_test_proxy(OPAQUE, rules, DropPolicy::Close).await;
+
+        let mut client = TcpStream::connect(proxy.addr).await.unwrap();
+        let mut buf = [0; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_on_connect_redirect_connects_eagerly() {
+        let proxy = spawn_test_proxy(OPAQUE, "(set-eval ON-CONNECT)", DropPolicy::Hold).await;
+
+        let mut client = TcpStream::connect(proxy.addr).await.unwrap();
+        // a server-speaks-first protocol: the banner arrives before the client writes anything
+        let (mut server, _) = proxy.upstream.accept().await.unwrap();
+        server.write_all(b"banner").await.unwrap();
+        let mut buf = [0; 6];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"banner");
+    }
+
+    #[tokio::test]
+    async fn test_on_connect_decision_is_kept_for_the_connection() {
+        let proxy = spawn_test_proxy(OPAQUE, "(set-eval ON-CONNECT)", DropPolicy::Hold).await;
+
+        let mut client = TcpStream::connect(proxy.addr).await.unwrap();
+        let (mut server, _) = proxy.upstream.accept().await.unwrap();
+
+        // the program is not run again for data on an already decided connection
+        let program = "(set-mode OPAQUE) (def-rule reject-all REJECT)";
+        set_program(&proxy.app_state, program);
+
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_response_rules_rewrite_responses() {
+        let proxy = spawn_test_proxy(
+            TRANSPARENT,
+            r#"(def-response-rule redact (REWRITE "Server: [a-z]+" "Server: redacted"))"#,
+            DropPolicy::Hold,
+        )
+        .await;
+        let mut client = TcpStream::connect(proxy.addr).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut server = assert_receives(&proxy.upstream, b"hello").await;
+
+        server.write_all(b"Server: nginx").await.unwrap();
+        let mut buf = [0; 16];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"Server: redacted");
+    }
+
+    #[tokio::test]
+    async fn test_response_rules_reject_resets_client() {
+        let proxy = spawn_test_proxy(
+            TRANSPARENT,
+            r#"(def-response-rule no-leaks
+                (if (contains? :packet-content "secret") REJECT CONTINUE))"#,
+            DropPolicy::Hold,
+        )
+        .await;
+        let mut client = TcpStream::connect(proxy.addr).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut server = assert_receives(&proxy.upstream, b"hello").await;
+
+        server.write_all(b"ok").await.unwrap();
+        let mut buf = [0; 2];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ok");
+
+        server.write_all(b"a secret").await.unwrap();
+        let err = client.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+        // the upstream is closed too
+        assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+    }
+
+    /// Writes `chunks` with a pause in between, so the proxy reads each of them separately
+    async fn write_in_chunks(stream: &mut TcpStream, chunks: &[&[u8]]) {
+        for chunk in chunks {
+            stream.write_all(chunk).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_content_rules_see_across_reads() {
+        let proxy = spawn_test_proxy(
+            TRANSPARENT,
+            r#"(def-rule no-secrets (if (contains? :packet-content "secret") REJECT CONTINUE))"#,
+            DropPolicy::Hold,
+        )
+        .await;
+
+        let mut client = TcpStream::connect(proxy.addr).await.unwrap();
+        write_in_chunks(&mut client, &[b"the sec", b"ret"]).await;
+
+        let mut buf = [0; 1];
+        let err = client.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+        // only what couldn't be part of the needle made it through
+        let (mut server, _) = proxy.upstream.accept().await.unwrap();
+        let mut received = vec![];
+        let _ = server.read_to_end(&mut received).await;
+        assert_eq!(received, b"the ");
+    }
+
+    #[tokio::test]
+    async fn test_rewrites_across_reads() {
+        let proxy = spawn_test_proxy(
+            TRANSPARENT,
+            r#"(def-rule rewrite (REWRITE "user=(\\w+);" "user=<$1>;"))"#,
+            DropPolicy::Hold,
+        )
+        .await;
+
+        let mut client = TcpStream::connect(proxy.addr).await.unwrap();
+        write_in_chunks(&mut client, &[b"a us", b"er=bo", b"b; done"]).await;
+        assert_receives(&proxy.upstream, b"a user=<bob>; done").await;
+    }
+
+    #[tokio::test]
+    async fn test_prefix_rules_only_match_the_start_of_the_stream() {
+        let proxy = spawn_test_proxy(
+            TRANSPARENT,
+            r#"(def-rule no-posts (if (prefix? :packet-content "POST") REJECT CONTINUE))"#,
+            DropPolicy::Hold,
+        )
+        .await;
+
+        // the needle is only completed by a later read, well into the stream, so nothing's rejected
+        let mut client = TcpStream::connect(proxy.addr).await.unwrap();
+        write_in_chunks(&mut client, &[b"GET /x\r\nPOS", b"T /y"]).await;
+        assert_receives(&proxy.upstream, b"GET /x\r\nPOST /y").await;
+    }
+
+    #[tokio::test]
+    async fn test_held_back_data_is_flushed() {
+        let proxy = spawn_test_proxy(
+            TRANSPARENT,
+            r#"(def-rule no-secrets (if (contains? :packet-content "secret") REJECT CONTINUE))"#,
+            DropPolicy::Hold,
+        )
+        .await;
+
+        // the client then waits for an answer, so the proxy mustn't wait for the rest of "secret"
+        let mut client = TcpStream::connect(proxy.addr).await.unwrap();
+        client.write_all(b"GET /sec").await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), assert_receives(&proxy.upstream, b"GET /sec"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_response_rules_see_across_reads() {
+        let proxy = spawn_test_proxy(
+            TRANSPARENT,
+            r#"(def-response-rule no-leaks
+                (if (contains? :packet-content "secret") REJECT CONTINUE))"#,
+            DropPolicy::Hold,
+        )
+        .await;
+        let mut client = TcpStream::connect(proxy.addr).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut server = assert_receives(&proxy.upstream, b"hello").await;
+
+        server.write_all(b"the sec").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        server.write_all(b"ret").await.unwrap();
+
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"the ");
+        let err = client.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
     }
 }
@@ -0,0 +1,211 @@
+//! Reassembly buffers, so content rules run on a connection's byte stream rather than on each read
+
+use std::sync::Arc;
+
+use crate::model::StreamLimits;
+use rulelib::vm::{Edges, Needles, Scan, Transform};
+
+/// The data read from one direction of a connection that hasn't been forwarded yet.
+/// After each decision, whatever a content rule may still match stays here to be decided on again
+/// with the next read.
+pub(super) struct Reassembly {
+    /// Shared with the packet the rules run on, rather than copied for each read
+    buffer: Arc<Vec<u8>>,
+    /// How far into the stream the buffer begins
+    offset: usize,
+    /// The byte before the buffer, unless it begins the stream
+    before: Option<u8>,
+    /// How far the needles of the rules have been looked for, so each read is only scanned once
+    scan: Option<Scan>,
+    limits: StreamLimits,
+}
+
+impl Reassembly {
+    pub fn new(limits: StreamLimits) -> Self {
+        Self {
+            buffer: Arc::default(),
+            offset: 0,
+            before: None,
+            scan: None,
+            limits,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    pub fn buffered(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    pub fn content(&self) -> Arc<Vec<u8>> {
+        self.buffer.clone()
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Splits a read into pieces that each fit in the buffer. No more than the window is held back
+    /// after a decision, so adding the next piece never takes the buffer past its cap.
+    pub fn pieces<'a>(&self, bytes: &'a [u8]) -> std::slice::Chunks<'a, u8> {
+        bytes.chunks(self.limits.cap - self.limits.window)
+    }
+
+    pub fn extend(&mut self, piece: &[u8]) {
+        debug_assert!(self.buffer.len() + piece.len() <= self.limits.cap);
+        Arc::make_mut(&mut self.buffer).extend_from_slice(piece);
+    }
+
+    /// Where the part of the buffer that `needles` may still match begins, like `Scan::holdback`
+    pub fn holdback(&mut self, needles: &Needles, window: usize) -> usize {
+        let scan = match &mut self.scan {
+            Some(scan) if scan.follows(needles) => scan,
+            // the program has been replaced since
+            scan => scan.insert(needles.scan()),
+        };
+        scan.holdback(&self.buffer, self.offset, self.before, window)
+    }
+
+    /// Takes everything before `hold` to be forwarded
+    pub fn take(&mut self, hold: usize) -> Vec<u8> {
+        if hold > 0 {
+            self.offset += hold;
+            self.before = Some(self.buffer[hold - 1]);
+        }
+        Arc::make_mut(&mut self.buffer).drain(..hold).collect()
+    }
+
+    /// Takes everything before `hold` to be forwarded, with the rewrites applied in order. A match
+    /// that starts before `hold` is settled, so it's rewritten and taken whole. `end` is whether
+    /// the buffer runs to the end of what's been sent, with no more to wait for.
+    pub fn rewrite(&mut self, hold: usize, transforms: &[Transform], end: bool) -> Vec<u8> {
+        let edges = Edges {
+            start: self.offset == 0,
+            end,
+        };
+        // taking a settled match whole may settle one of another rewrite, and so on
+        let mut hold = hold;
+        loop {
+            let settled = transforms
+                .iter()
+                .map(|transform| transform.pattern.settled_end(&self.buffer, hold, edges))
+                .fold(hold, usize::max);
+            if settled == hold {
+                break;
+            }
+            hold = settled;
+        }
+        let edges = Edges {
+            end: end && hold == self.buffer.len(),
+            ..edges
+        };
+        transforms
+            .iter()
+            .fold(self.take(hold), |content, transform| {
+                transform.apply(&content, edges)
+            })
+    }
+
+    pub fn clear(&mut self) {
+        self.take(self.buffer.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::bytes::Regex;
+    use rulelib::vm::Pattern;
+    use std::time::Duration;
+
+    fn reassembly(window: usize, cap: usize) -> Reassembly {
+        Reassembly::new(StreamLimits {
+            window,
+            cap,
+            flush_after: Duration::from_secs(1),
+        })
+    }
+
+    #[test]
+    fn pieces_never_overflow_the_cap() {
+        let mut stream = reassembly(4, 10);
+        stream.extend(b"held");
+
+        let read = [0; 20];
+        let pieces: Vec<_> = stream.pieces(&read).map(<[u8]>::len).collect();
+        assert_eq!(pieces, [6, 6, 6, 2]);
+        stream.extend(&read[..6]);
+        assert_eq!(stream.buffered().len(), 10);
+    }
+
+    fn transform(pattern: &str, replacement: &[u8]) -> Transform {
+        Transform {
+            pattern: Pattern::new(Regex::new(pattern).unwrap()),
+            replacement: Arc::new(replacement.to_vec()),
+        }
+    }
+
+    #[test]
+    fn rewrites_settled_matches_and_keeps_the_rest() {
+        let mut stream = reassembly(8, 64);
+        let transforms = [transform("user=(\\w+);", b"user=<$1>;")];
+        stream.extend(b"a user=bob; user=al");
+
+        assert_eq!(stream.rewrite(12, &transforms, false), b"a user=<bob>; ");
+        assert_eq!(stream.buffered(), b"user=al");
+
+        stream.extend(b"ice; done");
+        assert_eq!(stream.rewrite(11, &transforms, false), b"user=<alice>;");
+        assert_eq!(stream.rewrite(5, &[], false), b" done");
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn applies_rewrites_in_order() {
+        let mut stream = reassembly(16, 64);
+        // each rewrite sees the result of the one before
+        let transforms = [
+            transform("cat", b"dog"),
+            transform("dog=(\\w+);", b"pet=$1;"),
+        ];
+        stream.extend(b"cat=tom; ");
+        assert_eq!(stream.rewrite(9, &transforms, false), b"pet=tom; ");
+
+        // settling a match of one rewrite may settle one of the next
+        let transforms = [transform("a=\\w+;", b"A;"), transform("; b=\\w+;", b";B;")];
+        stream.extend(b"a=1; b=2; c");
+        assert_eq!(stream.rewrite(1, &transforms, false), b"A;B;");
+        assert_eq!(stream.buffered(), b" c");
+    }
+
+    #[test]
+    fn rewrites_anchored_patterns_only_at_the_edges_of_the_stream() {
+        let mut stream = reassembly(16, 64);
+        let transforms = [transform("^a|b$", b"_")];
+        stream.extend(b"a=b;a=b");
+        assert_eq!(stream.rewrite(4, &transforms, false), b"_=b;");
+        assert_eq!(stream.offset(), 4);
+        assert_eq!(stream.rewrite(3, &transforms, true), b"a=_");
+    }
+
+    #[test]
+    fn scans_reads_where_the_last_left_off() {
+        let needles = rulelib::compile(
+            r#"(set-mode TRANSPARENT)
+            (def-rule r (if (prefix? :packet-content "POST") REJECT (REDIRECT "127.0.0.1" 80)))"#,
+        )
+        .unwrap()
+        .needles;
+        let mut stream = reassembly(16, 64);
+        stream.extend(b"PO");
+        assert_eq!(stream.holdback(&needles, 16), 0);
+        stream.extend(b"K\r\nPO");
+        assert_eq!(stream.holdback(&needles, 16), 7);
+        assert_eq!(stream.take(7), b"POK\r\nPO");
+        stream.extend(b"ST");
+        // a prefix only matches at the start of the stream
+        assert_eq!(stream.holdback(&needles, 16), 2);
+    }
+}
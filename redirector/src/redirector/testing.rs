@@ -0,0 +1,124 @@
+//! What the tests of the redirector's modules share
+
+use crate::model::{AppState, DropPolicy, Failover, Settings, StreamLimits};
+use crate::redirector::Listener;
+use core::net::SocketAddr;
+use rulelib::ast::ProxyMode;
+use rusqlite::Connection;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const TEST_LIMITS: StreamLimits = StreamLimits {
+    window: 64,
+    cap: 1024,
+    flush_after: Duration::from_millis(200),
+};
+
+const TEST_FAILOVER: Failover = Failover {
+    connect_timeout: Duration::from_millis(500),
+    retries: 2,
+    backoff: Duration::from_millis(10),
+    eject_after: 2,
+    cooldown: Duration::from_secs(60),
+};
+
+/// An app state with an in-memory database, no listeners, and settings fit for tests
+pub(crate) fn test_app_state() -> AppState {
+    AppState {
+        conn: Arc::new(Mutex::new(Connection::open_in_memory().unwrap())),
+        listeners: Default::default(),
+        balancer: Default::default(),
+        health: Default::default(),
+        settings: Arc::new(RwLock::new(Settings {
+            drop_policy: DropPolicy::Hold,
+            limits: TEST_LIMITS,
+            failover: TEST_FAILOVER,
+        })),
+    }
+}
+
+/// A local address nothing listens on, since its listener is dropped as soon as it's bound
+pub(crate) async fn closed_port() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap()
+}
+
+/// Starts a proxy on an ephemeral loopback port, returning its address
+pub(crate) async fn spawn_proxy(
+    program: Option<&str>,
+    fallback: SocketAddr,
+    drop_policy: DropPolicy,
+    app_state: &AppState,
+) -> SocketAddr {
+    let bind_addr = "127.0.0.1:0".parse().unwrap();
+    spawn_proxy_on(program, bind_addr, fallback, drop_policy, app_state).await
+}
+
+/// Starts a proxy as the listener `test`, with `program` set
+pub(crate) async fn spawn_proxy_on(
+    program: Option<&str>,
+    bind_addr: SocketAddr,
+    fallback: SocketAddr,
+    drop_policy: DropPolicy,
+    app_state: &AppState,
+) -> SocketAddr {
+    app_state.settings.write().unwrap().drop_policy = drop_policy;
+    let listener = Listener::new("test".to_string(), bind_addr, fallback);
+    let addr = app_state.listeners.start(listener, app_state).unwrap();
+    if let Some(program) = program {
+        set_program(app_state, program);
+    }
+    addr
+}
+
+/// Compiles `program` and sets it on the listener `test`
+pub(crate) fn set_program(app_state: &AppState, program: &str) {
+    let program = rulelib::compile(program).unwrap();
+    assert!(app_state.listeners.set_program("test", program, "test".to_string()));
+}
+
+/// A proxy started by [`spawn_test_proxy`]
+pub(crate) struct TestProxy {
+    pub(crate) addr: SocketAddr,
+    /// Where the proxy's `forward` rule redirects to
+    pub(crate) upstream: TcpListener,
+    pub(crate) app_state: AppState,
+}
+
+/// Starts a proxy running `rules` in `mode`. They're followed by a rule `forward`, which redirects
+/// to the proxy's upstream, so a rule that lets traffic through can `CONTINUE` to it; nothing
+/// listens on the listener's destination, so nothing else reaches an upstream.
+pub(crate) async fn spawn_test_proxy(
+    mode: ProxyMode,
+    rules: &str,
+    drop_policy: DropPolicy,
+) -> TestProxy {
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let program = format!(
+        "(set-mode {:?})\n{}\n(def-rule forward (REDIRECT \"127.0.0.1\" {}))",
+        mode,
+        rules,
+        upstream.local_addr().unwrap().port()
+    );
+    let app_state = test_app_state();
+    let fallback = closed_port().await;
+    let addr = spawn_proxy(Some(&program), fallback, drop_policy, &app_state).await;
+    TestProxy { addr, upstream, app_state }
+}
+
+/// Asserts that the next connection accepted by `upstream` starts with `expected`, returning it
+pub(crate) async fn assert_receives(upstream: &TcpListener, expected: &[u8]) -> TcpStream {
+    let (mut server, _) = upstream.accept().await.unwrap();
+    let mut buf = vec![0; expected.len()];
+    server.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, expected);
+    server
+}
+
+/// Asserts that what a client sends to `proxy` reaches `upstream`
+pub(crate) async fn forwards_to(proxy: SocketAddr, upstream: &TcpListener) {
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(b"hi").await.unwrap();
+    assert_receives(upstream, b"hi").await;
+}
@@ -1,27 +1,23 @@
 use crate::model::AppState;
+use crate::redirector::Listener;
 use crate::sql::init_sql;
 use futures::{future, StreamExt};
 
-use rulelib::parser::{Rule, RuleParser};
-use rulelib::ast::AstNode;
-use rulelib::vm::Program;
-
-use pest::Parser;
+use rulelib::diagnostic::render;
 
 use rusqlite::params;
 use shared::error::{Error, Result};
-use shared::model::RuleFile;
+use shared::model::{BackendStatus, ListenerStatus, RuleFile};
 use shared::services::RuleSvc;
 use std::future::Future;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use tarpc::server::incoming::Incoming;
 use tarpc::tokio_serde::formats::Json;
-use tarpc::Request;
 use tarpc::{context, server, server::Channel};
 use tracing::{event, Level};
 
-/// Constant value for where the RPC server binds to
-const RPC_BIND: (Ipv4Addr, u16) = (Ipv4Addr::LOCALHOST, 50050);
+/// Where the RPC server binds to unless the config file says otherwise
+pub const RPC_BIND: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 50050));
 
 #[derive(Clone)]
 struct Server {
@@ -95,7 +91,7 @@ impl RuleSvc for Server {
             }
         };
 
-        let mut stmt = conn.prepare("SELECT id, name, content FROM rulefiles");
+        let stmt = conn.prepare("SELECT id, name, content FROM rulefiles");
         if stmt.is_err() {
             return Err(Error::Anyhow(format!(
                 "Failed to prepare statement: {}",
@@ -163,19 +159,93 @@ impl RuleSvc for Server {
         }
     }
 
-    async fn set_program(self, context: tarpc::context::Context, id: i64) -> Result<()> {
+    async fn set_program(
+        self,
+        context: tarpc::context::Context,
+        listener: String,
+        id: i64,
+    ) -> Result<()> {
+        if self.app_state.listeners.get(&listener).is_none() {
+            return Err(Error::Anyhow(format!("No listener named `{}`", listener)));
+        }
         let rule_file = self.request_helper(context, id).await?;
-        let parse_tree = RuleParser::parse(Rule::program, &rule_file.content)
-            .unwrap()
-            .next()
-            .unwrap();
-        let ast = AstNode::try_from(parse_tree).unwrap();
-
-        let bytecode = AstNode::codegen(&ast);
-        let mut app_state_program = self.app_state.program.lock().unwrap();
-        *app_state_program = bytecode;
+        let bytecode = match rulelib::compile(&rule_file.content) {
+            Ok(bytecode) => bytecode,
+            Err(diagnostics) => {
+                let report = render(&rule_file.name, &rule_file.content, &diagnostics);
+                event!(Level::WARN, "Rule file {} failed to compile:\n{}", id, report);
+                return Err(Error::InvalidRuleFile(report));
+            }
+        };
+
+        let source = format!("rule file {} ({})", id, rule_file.name);
+        if !self.app_state.listeners.set_program(&listener, bytecode, source) {
+            return Err(Error::Anyhow(format!("No listener named `{}`", listener)));
+        }
+        event!(
+            Level::INFO,
+            "{} set the program of {} to rule file {}",
+            self.addr,
+            listener,
+            id
+        );
+        Ok(())
+    }
+
+    async fn backends(self, _: context::Context) -> Result<Vec<BackendStatus>> {
+        let mut backends = Vec::new();
+        for (listener, pool) in self.app_state.listeners.pools() {
+            for backend in &pool.backends {
+                backends.push(BackendStatus {
+                    listener: listener.clone(),
+                    upstream: pool.name.clone(),
+                    addr: backend.addr,
+                    weight: backend.weight,
+                    health: self.app_state.health.get(&pool, backend.addr),
+                    connections: self.app_state.balancer.connections(backend.addr),
+                });
+            }
+        }
+        Ok(backends)
+    }
+
+    async fn add_listener(
+        self,
+        _: context::Context,
+        name: String,
+        bind: SocketAddr,
+        destination: SocketAddr,
+    ) -> Result<SocketAddr> {
+        let listener = Listener::new(name.clone(), bind, destination);
+        match self.app_state.listeners.start(listener, &self.app_state) {
+            Ok(addr) => {
+                event!(Level::INFO, "{} added the listener {} on {}", self.addr, name, addr);
+                Ok(addr)
+            }
+            Err(e) => Err(Error::Anyhow(format!("Failed to add listener: {}", e))),
+        }
+    }
+
+    async fn remove_listener(self, _: context::Context, name: String) -> Result<()> {
+        if self.app_state.listeners.stop(&name).await.is_none() {
+            return Err(Error::Anyhow(format!("No listener named `{}`", name)));
+        }
+        event!(Level::INFO, "{} removed the listener {}", self.addr, name);
         Ok(())
     }
+
+    async fn listeners(self, _: context::Context) -> Result<Vec<ListenerStatus>> {
+        let listeners = self.app_state.listeners.list();
+        Ok(listeners
+            .into_iter()
+            .map(|(listener, program)| ListenerStatus {
+                name: listener.name,
+                bind: listener.bind,
+                destination: listener.destination,
+                program,
+            })
+            .collect())
+    }
 }
 
 /// Used to enforce trait bounds
@@ -184,17 +254,12 @@ async fn spawn(fut: impl Future<Output = ()> + Send + 'static) {
 }
 
 /// Start the RPC server
-pub async fn init_rpc(app_state: AppState) -> anyhow::Result<()> {
-    let mut listener = tarpc::serde_transport::tcp::listen(&RPC_BIND, Json::default)
+pub async fn init_rpc(app_state: AppState, addr: SocketAddr) -> anyhow::Result<()> {
+    let mut listener = tarpc::serde_transport::tcp::listen(&addr, Json::default)
         .await
         .expect("Failed to bind RPC listener");
 
-    event!(
-        Level::INFO,
-        "RPC listening on {}:{}",
-        RPC_BIND.0,
-        RPC_BIND.1
-    );
+    event!(Level::INFO, "RPC listening on {}", addr);
 
     init_sql(app_state.clone())?;
 
@@ -226,15 +291,11 @@ pub async fn init_rpc(app_state: AppState) -> anyhow::Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use rusqlite::Connection;
-    use std::sync::{Arc, Mutex};
+    use crate::redirector::testing::test_app_state;
 
     #[test]
     pub fn test_all_ok() -> anyhow::Result<()> {
-        let state = AppState {
-            conn: Arc::new(Mutex::new(Connection::open_in_memory()?)),
-            program: Arc::new(Mutex::new(Program::default())),
-        };
+        let state = test_app_state();
         init_sql(state.clone())?;
 
         let conn = match state.conn.lock() {
@@ -1,13 +1,20 @@
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
+use ipnet::IpNet;
+use regex::bytes::Regex;
+
+use crate::ast::validate::CONTENT_PREDICATES;
 use crate::ast::*;
+use crate::diagnostic::Span;
 use crate::vm::{
-    Instruction, Label, ObjKey, Object, Program, Reg, PACKET_CONTENT, PACKET_SOURCE_IP,
-    PACKET_SOURCE_PORT,
+    self, Instruction, Label, Needles, ObjKey, Object, Pattern, Pool, Program, Reg, PACKET_CONTENT, PACKET_CONTENT_LENGTH,
+    PACKET_DEST_IP, PACKET_DEST_PORT, PACKET_LISTENER, PACKET_SOURCE_IP, PACKET_SOURCE_PORT,
 };
 
-const INVALID_PROGRAM: &'static str = "Precondition failed: Program is invalid";
+const INVALID_PROGRAM: &str = "Precondition failed: Program is invalid";
 
 #[derive(Debug, Default)]
 struct AstCodeGenEnv {
@@ -62,16 +69,36 @@ impl AstNode {
     /// Assumes that program has already been validated via `AstNode::validate`
     pub fn codegen(&self) -> Program {
         match self {
-            AstNode::Program(statements) => {
+            AstNode::Program(statements, _) => {
                 let mut env = AstCodeGenEnv::default();
                 // FIXME: we use Object::Port as bools for now
                 env.insert_into_obj("TRUE", Object::Port(1));
                 env.insert_into_obj("FALSE", Object::Port(0));
 
                 // skip the proxy mode check since validation should catch that.
+                let mut response_rules = vec![];
                 for statement in statements.iter().skip(1) {
+                    match statement {
+                        AstNode::Keyword(
+                            Keyword::SpecialForm(SpecialForm::DefResponseRule { .. }),
+                            _,
+                        ) => response_rules.push(statement),
+                        _ => codegen_toplevel(&mut env, statement),
+                    }
+                }
+
+                // NOTE: response rules get instructions of their own, whose labels start from 0 again,
+                // but share the data (and so every definition) with the request rules
+                let requests = std::mem::take(&mut env.program.instructions);
+                env.curr_label = 0;
+                for statement in response_rules {
                     codegen_toplevel(&mut env, statement);
                 }
+                env.program.response = std::mem::replace(&mut env.program.instructions, requests);
+
+                let program = &mut env.program;
+                program.needles = Needles::new(&program.instructions, &program.data);
+                program.response_needles = Needles::new(&program.response, &program.data);
 
                 env.program
             }
@@ -82,16 +109,57 @@ impl AstNode {
 
 fn codegen_toplevel(env: &mut AstCodeGenEnv, statement: &AstNode) {
     match statement {
-        AstNode::Keyword(keyword) => match keyword {
-            Keyword::SpecialForm(sf) => match sf {
-                SpecialForm::DefVar { name, value } => {
-                    codegen_var(env, name, value);
-                }
-                SpecialForm::DefRule { name, body } => {
-                    codegen_rule(env, name, body);
+        AstNode::Keyword(Keyword::SpecialForm(sf), _) => match sf {
+            SpecialForm::DefVar { name, value } => {
+                codegen_var(env, name, value);
+            }
+            SpecialForm::DefSet { name, members } => {
+                codegen_set(env, name, members);
+            }
+            SpecialForm::DefUpstream {
+                name,
+                strategy,
+                backends,
+                check,
+            } => {
+                let backends = backends
+                    .iter()
+                    .map(|backend| vm::Backend {
+                        addr: SocketAddr::new(backend.addr.parse().expect("Invalid IP"), backend.port),
+                        weight: backend.weight,
+                    })
+                    .collect();
+                let pool = Pool {
+                    name: name.clone(),
+                    strategy: *strategy,
+                    backends,
+                    check: check.as_ref().map(|check| vm::HealthCheck {
+                        interval: Duration::from_millis(check.interval_ms),
+                        send: check.send.clone(),
+                        expect: check.expect.clone(),
+                    }),
+                };
+                env.insert_into_obj(name, Object::Pool(Arc::new(pool)));
+            }
+            SpecialForm::SetEval { evaluation } => {
+                env.program.evaluation = *evaluation;
+            }
+            SpecialForm::DefRule { name, body } | SpecialForm::DefResponseRule { name, body } => {
+                codegen_rule(env, name, body);
+
+                // NOTE: update CONTINUE labels to jump to wherever the next rule starts
+                let next_rule = env.curr_label;
+                for continue_label in std::mem::take(&mut env.should_continue) {
+                    if let Instruction::ITE(curr_reg, _, _) =
+                        env.program.instructions[continue_label]
+                    {
+                        env.update_instr(
+                            continue_label,
+                            Instruction::ITE(curr_reg, next_rule, next_rule),
+                        )
+                    }
                 }
-                _ => unreachable!("{}", INVALID_PROGRAM),
-            },
+            }
             _ => unreachable!("{}", INVALID_PROGRAM),
         },
         _ => unreachable!("{}", INVALID_PROGRAM),
@@ -101,7 +169,7 @@ fn codegen_toplevel(env: &mut AstCodeGenEnv, statement: &AstNode) {
 // TODO: we don't acually need the name since we only allow for linear execution of rules.
 fn codegen_rule(env: &mut AstCodeGenEnv, _name: &str, body: &AstNode) -> Label {
     match body {
-        AstNode::Keyword(kw) => match kw {
+        AstNode::Keyword(kw, _) => match kw {
             Keyword::SpecialForm(sf) => match sf {
                 SpecialForm::If {
                     predicate,
@@ -114,31 +182,22 @@ fn codegen_rule(env: &mut AstCodeGenEnv, _name: &str, body: &AstNode) -> Label {
 
                     let ite = env.add_instr(Instruction::ITE(curr_reg, 0, 0));
 
-                    let cons = codegen_rule(env, "", consequent);
-                    // NOTE: We don't need to "skip" to the next rule, since this one is guaranteed to have an outcome
-                    let alt = codegen_rule(env, "", alternative);
+                    // NOTE: only the ITE reads the predicate, so the branches can reuse its register.
+                    env.curr_reg = curr_reg;
 
-                    env.update_instr(ite, Instruction::ITE(curr_reg, ite + 1, cons + 1));
+                    codegen_rule(env, "", consequent);
+                    // NOTE: We don't need to "skip" over the alternative, since every branch ends
+                    // in an outcome or a CONTINUE
+                    let alt = env.curr_label;
+                    let label = codegen_rule(env, "", alternative);
 
-                    // NOTE: this entire chunk is done, so we can now reuse the registers.
-                    env.curr_reg -= 1;
+                    env.update_instr(ite, Instruction::ITE(curr_reg, ite + 1, alt));
 
-                    // NOTE: update CONTINUE labels
-                    let continue_labels = env.should_continue.clone();
-                    for continue_label in continue_labels {
-                        if let Instruction::ITE(curr_reg, _, _) =
-                            env.program.instructions[continue_label]
-                        {
-                            env.update_instr(continue_label, Instruction::ITE(curr_reg, alt + 1, 0))
-                        }
-                    }
-                    env.should_continue.clear();
-
-                    alt + 1
+                    label
                 }
                 _ => unreachable!("{}", INVALID_PROGRAM),
             },
-            Keyword::Outcome(outcome) => codegen_outcome(env, &outcome),
+            Keyword::Outcome(outcome) => codegen_outcome(env, outcome),
         },
         _ => unreachable!("{}", INVALID_PROGRAM),
     }
@@ -149,8 +208,8 @@ fn codegen_pred(env: &mut AstCodeGenEnv, predicate: &AstNode) -> Label {
     env.curr_reg += 1;
 
     match predicate {
-        AstNode::Keyword(_) => todo!("haven't yet handled nested ifs"),
-        AstNode::Bool(b) => {
+        AstNode::Keyword(..) => unreachable!("{}", INVALID_PROGRAM),
+        AstNode::Bool(b, _) => {
             if *b {
                 env.add_instr(Instruction::SEQ(
                     curr_reg,
@@ -165,15 +224,39 @@ fn codegen_pred(env: &mut AstCodeGenEnv, predicate: &AstNode) -> Label {
                 ))
             }
         }
-        AstNode::Sexp(expr) => {
+        AstNode::Sexp(expr, _) => {
             let mut it = expr.iter();
             match it.next().unwrap() {
-                AstNode::Ident(s) if s == "exact?" => codegen_exact(env, it.as_slice(), curr_reg),
+                AstNode::Ident(s, _) if s == "exact?" => codegen_exact(env, it.as_slice(), curr_reg),
+                AstNode::Ident(s, _) if s == "in-subnet?" => {
+                    codegen_in_subnet(env, it.as_slice(), curr_reg)
+                }
+                AstNode::Ident(s, _) if s == "port-in-range?" => {
+                    codegen_port_in_range(env, it.as_slice(), curr_reg)
+                }
+                AstNode::Ident(s, _) if matches!(s.as_str(), "<" | "<=" | ">" | ">=") => {
+                    codegen_comparison(env, s, it.as_slice(), curr_reg)
+                }
+                AstNode::Ident(s, _) if CONTENT_PREDICATES.contains(&s.as_str()) => {
+                    codegen_content_match(env, s, it.as_slice(), curr_reg)
+                }
+                AstNode::Ident(s, _) if s == "member?" => {
+                    let value = codegen_get_obj_key(env, &expr[1]);
+                    let set = codegen_get_obj_key(env, &expr[2]);
+                    env.add_instr(Instruction::SMEM(curr_reg, value, set))
+                }
+                AstNode::Ident(s, _) if s == "and" => {
+                    codegen_connective(env, it.as_slice(), curr_reg, true)
+                }
+                AstNode::Ident(s, _) if s == "or" => {
+                    codegen_connective(env, it.as_slice(), curr_reg, false)
+                }
+                AstNode::Ident(s, _) if s == "not" => codegen_not(env, &it.as_slice()[0], curr_reg),
                 s => unimplemented!("unknown predicate: {:?}", s),
             }
         }
         // NOTE: we assume that ident has been type-checked to a bool
-        AstNode::Ident(ident) => env.add_instr(Instruction::SEQ(
+        AstNode::Ident(ident, _) => env.add_instr(Instruction::SEQ(
             curr_reg,
             env.get_obj_key(ident),
             env.get_obj_key("TRUE"),
@@ -182,6 +265,68 @@ fn codegen_pred(env: &mut AstCodeGenEnv, predicate: &AstNode) -> Label {
     }
 }
 
+/// Codegen for `and` (when `is_and`) and `or`, which short-circuit: as soon as the result is known,
+/// we jump past the remaining operands.
+fn codegen_connective(
+    env: &mut AstCodeGenEnv,
+    operands: &[AstNode],
+    curr_reg: Reg,
+    is_and: bool,
+) -> Label {
+    // start from the identity, so that `(and)` is true and `(or)` is false
+    let identity = if is_and { "TRUE" } else { "FALSE" };
+    let mut label = env.add_instr(Instruction::SEQ(
+        curr_reg,
+        env.get_obj_key("TRUE"),
+        env.get_obj_key(identity),
+    ));
+
+    let mut short_circuits = vec![];
+    for (i, operand) in operands.iter().enumerate() {
+        let operand_reg = env.curr_reg;
+        codegen_pred(env, operand);
+        label = if is_and {
+            env.add_instr(Instruction::AND(curr_reg, curr_reg, operand_reg))
+        } else {
+            env.add_instr(Instruction::OR(curr_reg, curr_reg, operand_reg))
+        };
+        // NOTE: the operand's result has been folded in, so its registers are free again.
+        env.curr_reg = operand_reg;
+
+        if i + 1 < operands.len() {
+            short_circuits.push(env.add_instr(Instruction::ITE(curr_reg, 0, 0)));
+        }
+    }
+
+    let end = env.curr_label;
+    for ite in short_circuits {
+        let instr = if is_and {
+            Instruction::ITE(curr_reg, ite + 1, end)
+        } else {
+            Instruction::ITE(curr_reg, end, ite + 1)
+        };
+        env.update_instr(ite, instr);
+    }
+
+    label
+}
+
+fn codegen_not(env: &mut AstCodeGenEnv, operand: &AstNode, curr_reg: Reg) -> Label {
+    let operand_reg = env.curr_reg;
+    codegen_pred(env, operand);
+    env.add_instr(Instruction::NOT(curr_reg, operand_reg));
+    // NOTE: `NOT` is bitwise, so mask the result back down to 0 or 1
+    env.add_instr(Instruction::SEQ(
+        operand_reg,
+        env.get_obj_key("TRUE"),
+        env.get_obj_key("TRUE"),
+    ));
+    let label = env.add_instr(Instruction::AND(curr_reg, curr_reg, operand_reg));
+    env.curr_reg = operand_reg;
+
+    label
+}
+
 // NOTE: we assume that we've already validated the arity
 fn codegen_exact(env: &mut AstCodeGenEnv, statements: &[AstNode], curr_reg: Reg) -> Label {
     let args1 = codegen_get_obj_key(env, &statements[0]);
@@ -189,27 +334,144 @@ fn codegen_exact(env: &mut AstCodeGenEnv, statements: &[AstNode], curr_reg: Reg)
     env.add_instr(Instruction::SEQ(curr_reg, args1, args2))
 }
 
+// NOTE: we assume that we've already validated the arity and types
+fn codegen_in_subnet(env: &mut AstCodeGenEnv, statements: &[AstNode], curr_reg: Reg) -> Label {
+    let addr = codegen_get_obj_key(env, &statements[0]);
+    let subnet = codegen_get_obj_key(env, &statements[1]);
+    env.add_instr(Instruction::SIN(curr_reg, addr, subnet))
+}
+
+// NOTE: we assume that we've already validated the arity and types
+fn codegen_comparison(
+    env: &mut AstCodeGenEnv,
+    op: &str,
+    statements: &[AstNode],
+    curr_reg: Reg,
+) -> Label {
+    let lhs = codegen_get_obj_key(env, &statements[0]);
+    let rhs = codegen_get_obj_key(env, &statements[1]);
+    // NOTE: the VM only knows about < and <=, so flip the operands for > and >=
+    let instr = match op {
+        "<" => Instruction::SLT(curr_reg, lhs, rhs),
+        "<=" => Instruction::SLE(curr_reg, lhs, rhs),
+        ">" => Instruction::SLT(curr_reg, rhs, lhs),
+        ">=" => Instruction::SLE(curr_reg, rhs, lhs),
+        _ => unreachable!("{}", INVALID_PROGRAM),
+    };
+    env.add_instr(instr)
+}
+
+/// `(port-in-range? port low high)` is `low <= port <= high`
+fn codegen_port_in_range(env: &mut AstCodeGenEnv, statements: &[AstNode], curr_reg: Reg) -> Label {
+    let port = codegen_get_obj_key(env, &statements[0]);
+    let low = codegen_get_obj_key(env, &statements[1]);
+    let high = codegen_get_obj_key(env, &statements[2]);
+    // NOTE: we don't recurse, so the next register is free to use as scratch space
+    let scratch = env.curr_reg;
+    env.add_instr(Instruction::SLE(curr_reg, low, port));
+    env.add_instr(Instruction::SLE(scratch, port, high));
+    env.add_instr(Instruction::AND(curr_reg, curr_reg, scratch))
+}
+
+// NOTE: we assume that we've already validated the arity and types
+fn codegen_content_match(
+    env: &mut AstCodeGenEnv,
+    form: &str,
+    statements: &[AstNode],
+    curr_reg: Reg,
+) -> Label {
+    let haystack = codegen_get_obj_key(env, &statements[0]);
+    let instr = match form {
+        "contains?" => Instruction::SCON(curr_reg, haystack, codegen_get_obj_key(env, &statements[1])),
+        "prefix?" => Instruction::SPRE(curr_reg, haystack, codegen_get_obj_key(env, &statements[1])),
+        "suffix?" => Instruction::SSUF(curr_reg, haystack, codegen_get_obj_key(env, &statements[1])),
+        _ => {
+            let pattern = match (form, &statements[1]) {
+                ("matches?", AstNode::String(pattern, _)) => pattern.clone(),
+                // NOTE: the case-insensitive forms are just regexes that match the needle literally
+                (_, needle) => {
+                    let needle = escape_bytes(&codegen_data(env, needle));
+                    match form {
+                        "contains-ci?" => format!("(?i){}", needle),
+                        "prefix-ci?" => format!("(?i)\\A{}", needle),
+                        "suffix-ci?" => format!("(?i){}\\z", needle),
+                        _ => unreachable!("{}", INVALID_PROGRAM),
+                    }
+                }
+            };
+            let pattern = Pattern::new(Regex::new(&pattern).expect(INVALID_PROGRAM));
+            let pattern = env.insert_into_obj(&format!("{}", env.obj_key), Object::Pattern(pattern));
+            Instruction::SMAT(curr_reg, haystack, pattern)
+        }
+    };
+    env.add_instr(instr)
+}
+
+/// The bytes of a data literal or a data variable
+fn codegen_data(env: &AstCodeGenEnv, node: &AstNode) -> Vec<u8> {
+    match node {
+        AstNode::String(s, _) => s.as_bytes().to_owned(),
+        AstNode::Bytes(bytes, _) => bytes.clone(),
+        AstNode::Ident(name, _) => match env.get_obj(name) {
+            Object::Data(data) => data.to_vec(),
+            _ => unreachable!("{}", INVALID_PROGRAM),
+        },
+        _ => unreachable!("{}", INVALID_PROGRAM),
+    }
+}
+
+/// Escapes arbitrary bytes so that a regex matches them literally
+fn escape_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                regex::escape(&(b as char).to_string())
+            } else {
+                format!("(?-u:\\x{:02X})", b)
+            }
+        })
+        .collect()
+}
+
+/// The object for a string literal: plain and hex strings are data, while `ip"..."` and
+/// `cidr"..."` are addresses and subnets
+fn literal_object(node: &AstNode) -> Object {
+    match node {
+        AstNode::String(s, _) => Object::Data(Arc::new(s.as_bytes().to_owned())),
+        AstNode::Bytes(bytes, _) => Object::Data(Arc::new(bytes.clone())),
+        AstNode::Ip(s, _) => Object::IP(s.parse().expect("Invalid IP")),
+        AstNode::Cidr(s, _) => {
+            Object::Subnet(s.parse::<IpNet>().expect("Invalid subnet").trunc())
+        }
+        _ => unreachable!("{}", INVALID_PROGRAM),
+    }
+}
+
 // NOTE: this function is different from env.get_obj_key in the sense that it allows for
 // immediates, too.
 fn codegen_get_obj_key(env: &mut AstCodeGenEnv, node: &AstNode) -> ObjKey {
     match node {
-        AstNode::Keyword(_) => {
+        AstNode::Keyword(..) => {
             unreachable!("no well-defined semantics for getting the object key of a keyword")
         }
-        AstNode::Num(_) => todo!("VM doesn't support 64bit integers"),
-        AstNode::Bool(true) => env.get_obj_key("TRUE"),
-        AstNode::Bool(false) => env.get_obj_key("FALSE"),
-        AstNode::Ident(s) => match s.as_str() {
+        AstNode::Num(n, _) => env.insert_into_obj(&format!("{}", env.obj_key), Object::Int(*n)),
+        AstNode::Bool(true, _) => env.get_obj_key("TRUE"),
+        AstNode::Bool(false, _) => env.get_obj_key("FALSE"),
+        AstNode::Ident(s, _) => match s.as_str() {
             ":packet-source-ip" => PACKET_SOURCE_IP,
             ":packet-source-port" => PACKET_SOURCE_PORT,
+            ":packet-dest-ip" => PACKET_DEST_IP,
+            ":packet-dest-port" => PACKET_DEST_PORT,
+            ":listener" => PACKET_LISTENER,
             ":packet-content" => PACKET_CONTENT,
+            ":packet-content-length" => PACKET_CONTENT_LENGTH,
             _ => env.get_obj_key(s),
         },
-        AstNode::String(s) => env.insert_into_obj(
-            &format!("{}", env.obj_key),
-            Object::IP(s.parse().expect("Invalid IP")),
-        ),
-        AstNode::Sexp(_) => {
+        AstNode::String(..) | AstNode::Ip(..) | AstNode::Cidr(..) | AstNode::Bytes(..) => {
+            env.insert_into_obj(&format!("{}", env.obj_key), literal_object(node))
+        }
+        AstNode::Sexp(..) => {
             unreachable!("no well-defined semantics for getting the object key of an s_exp")
         }
         _ => unreachable!(),
@@ -222,34 +484,69 @@ fn codegen_var(env: &mut AstCodeGenEnv, name: &str, value: &AstNode) {
     // better to just store 'references' (ObjKeys) to some objects
     // TODO: only works on atoms for now.
     match value {
-        AstNode::Keyword(_) => {
+        AstNode::Keyword(..) => {
             todo!("If not handled in variables (and I don't think we ever want to)")
         }
-        // NOTE: we assume all nums are ports
-        AstNode::Num(n) => {
-            env.insert_into_obj(name, Object::Port((*n).try_into().expect("invalid port")));
+        AstNode::Num(n, _) => {
+            env.insert_into_obj(name, Object::Int(*n));
         }
-        AstNode::Bool(true) => {
+        AstNode::Bool(true, _) => {
             env.insert_into_obj(name, Object::Port(1));
         }
-        AstNode::Bool(false) => {
+        AstNode::Bool(false, _) => {
             env.insert_into_obj(name, Object::Port(0));
         }
-        AstNode::Ident(ident) => {
+        AstNode::Ident(ident, _) => {
             let val = env.get_obj(ident);
             env.insert_into_obj(name, val);
         }
-        // NOTE: we assume all strings are IPv4 addresses
-        AstNode::String(s) => {
-            env.insert_into_obj(name, Object::IP(s.parse().expect("Invalid IP")));
+        AstNode::String(..) | AstNode::Ip(..) | AstNode::Cidr(..) | AstNode::Bytes(..) => {
+            env.insert_into_obj(name, literal_object(value));
         }
-        AstNode::Sexp(_) => {
+        AstNode::Sexp(..) => {
             todo!("s_exp's not handled in variables (and I don't think we ever want to)")
         }
-        AstNode::Program(_) => unreachable!("{}", INVALID_PROGRAM),
+        AstNode::Program(..) => unreachable!("{}", INVALID_PROGRAM),
     }
 }
 
+// NOTE: we assume that the members have been validated to all be IPs or all be numbers
+fn codegen_set(env: &mut AstCodeGenEnv, name: &str, members: &[AstNode]) {
+    let members: Vec<Object> = members
+        .iter()
+        .map(|member| match member {
+            AstNode::Num(n, _) => Object::Int(*n),
+            AstNode::Ip(..) => literal_object(member),
+            AstNode::Ident(ident, _) => env.get_obj(ident),
+            _ => unreachable!("{}", INVALID_PROGRAM),
+        })
+        .collect();
+
+    let set = if matches!(members.first(), Some(Object::IP(_))) {
+        Object::IPSet(Arc::new(
+            members
+                .iter()
+                .map(|member| match member {
+                    Object::IP(addr) => *addr,
+                    _ => unreachable!("{}", INVALID_PROGRAM),
+                })
+                .collect(),
+        ))
+    } else {
+        Object::IntSet(Arc::new(
+            members
+                .iter()
+                .map(|member| match member {
+                    Object::Port(port) => *port as i64,
+                    Object::Int(n) => *n,
+                    _ => unreachable!("{}", INVALID_PROGRAM),
+                })
+                .collect(),
+        ))
+    };
+    env.insert_into_obj(name, set);
+}
+
 fn codegen_outcome(env: &mut AstCodeGenEnv, outcome: &RuleOutcome) -> Label {
     match outcome {
         RuleOutcome::DROP => env.add_instr(Instruction::DROP),
@@ -269,18 +566,25 @@ fn codegen_outcome(env: &mut AstCodeGenEnv, outcome: &RuleOutcome) -> Label {
         } => {
             let pattern = env.insert_into_obj(
                 &(format!("{}", env.obj_key)),
-                Object::Data(Arc::new(pattern.as_bytes().to_owned())),
+                Object::Pattern(Pattern::new(Regex::new(pattern).expect(INVALID_PROGRAM))),
             );
             let replace_with = env.insert_into_obj(
                 &(format!("{}", env.obj_key)),
-                Object::Data(Arc::new(replace_with.as_bytes().to_owned())),
+                Object::Data(Arc::new(replace_with.clone())),
             );
-            env.add_instr(Instruction::REWRITE(pattern, replace_with))
+            let label = env.add_instr(Instruction::REWRITE(pattern, replace_with));
+            // a rewrite doesn't end evaluation: go on with the next rule
+            codegen_outcome(env, &RuleOutcome::CONTINUE);
+            label
+        }
+        RuleOutcome::BALANCE { upstream } => {
+            let pool = env.get_obj_key(upstream);
+            env.add_instr(Instruction::BALANCE(pool))
         }
         RuleOutcome::CONTINUE => {
             let curr_reg = env.curr_reg;
 
-            let _ = codegen_pred(env, &AstNode::Bool(true));
+            let _ = codegen_pred(env, &AstNode::Bool(true, Span::default()));
             let label = env.add_instr(Instruction::ITE(curr_reg, 0, 0));
 
             env.should_continue.push(label);
@@ -297,6 +601,7 @@ mod tests {
     use pest::Parser;
 
     use crate::parser::RuleParser;
+    use crate::vm::Evaluation;
 
     use super::*;
 
@@ -306,7 +611,7 @@ mod tests {
         let program = r#"
             (set-mode OPAQUE)
 
-            (def-var bad-ip "192.0.1.2")
+            (def-var bad-ip ip"192.0.1.2")
 
             (def-rule simple-rule
                 (if (exact? :packet-source-ip bad-ip)
@@ -329,7 +634,7 @@ mod tests {
         let program = r#"
             (set-mode OPAQUE)
 
-            (def-var bad-ip "192.0.1.2")
+            (def-var bad-ip ip"192.0.1.2")
 
             (def-rule simple-rewrite
                 (if (exact? :packet-source-ip bad-ip)
@@ -351,4 +656,14 @@ mod tests {
         let bytecode = AstNode::codegen(&ast);
         dbg!(&bytecode);
     }
+
+    #[test]
+    fn records_evaluation() {
+        let program = crate::compile("(set-mode OPAQUE) (def-rule r DROP)").unwrap();
+        assert_eq!(program.evaluation, Evaluation::OnData);
+
+        let program =
+            crate::compile("(set-mode OPAQUE) (set-eval ON-CONNECT) (def-rule r DROP)").unwrap();
+        assert_eq!(program.evaluation, Evaluation::OnConnect);
+    }
 }
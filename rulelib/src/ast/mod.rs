@@ -31,21 +31,27 @@
 *    * Otherwise, it's a non-terminal; so, we recursively descend on the node's children and collect their values
 *    into the particular non-terminal variant.
 */
-use crate::parser::Rule;
+use crate::diagnostic::{Span, Spanned};
+use crate::parser::{Rule, RuleParser};
+use crate::vm::{Evaluation, Strategy};
 use lazy_static::lazy_static;
 use pest::iterators::Pair;
+use pest::Parser;
 use std::collections::HashSet;
 
 mod codegen;
+mod validate;
+
+pub use validate::{ValidationError, ValueType};
 
 lazy_static! {
     static ref RESERVED_KEYWORDS: HashSet<&'static str> = HashSet::from([
-        "def-var", "set-mode", "def-rule", "if", "DROP", "REJECT", "REDIRECT", "REPLACE",
-        "REWRITE", "CONTINUE"
+        "def-var", "def-set", "def-upstream", "set-mode", "set-eval", "def-rule", "def-response-rule", "if", "cond", "else", "DROP",
+        "REJECT", "REDIRECT", "REPLACE", "REWRITE", "BALANCE", "CONTINUE"
     ]);
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ProxyMode {
     OPAQUE,
     TRANSPARENT,
@@ -58,20 +64,153 @@ impl TryFrom<&str> for ProxyMode {
         match value {
             "OPAQUE" => Ok(ProxyMode::OPAQUE),
             "TRANSPARENT" => Ok(ProxyMode::TRANSPARENT),
-            _ => Err(Self::Error::ParseError(format!(
-                "Unknown proxy mode: {}",
-                value
-            ))),
+            _ => Err(Self::Error::ParseError(
+                format!("Unknown proxy mode: {}", value),
+                None,
+            )),
+        }
+    }
+}
+
+impl TryFrom<&str> for Evaluation {
+    type Error = AstParseError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "ON-DATA" => Ok(Evaluation::OnData),
+            "ON-CONNECT" => Ok(Evaluation::OnConnect),
+            _ => Err(Self::Error::ParseError(
+                format!("Unknown evaluation mode: {}", value),
+                None,
+            )),
+        }
+    }
+}
+
+impl TryFrom<&str> for Strategy {
+    type Error = AstParseError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "ROUND-ROBIN" => Ok(Strategy::RoundRobin),
+            "LEAST-CONNECTIONS" => Ok(Strategy::LeastConnections),
+            "SOURCE-IP-HASH" => Ok(Strategy::SourceIpHash),
+            _ => Err(Self::Error::ParseError(
+                format!("Unknown balancing strategy: {}", value),
+                None,
+            )),
         }
     }
 }
 
+/// A backend of a `def-upstream`: `(<addr> <port>)`, or `(<addr> <port> <weight>)`
+#[derive(Debug, Clone)]
+pub struct Backend {
+    pub addr: String,
+    pub port: u16,
+    /// 1 unless given
+    pub weight: u32,
+    pub span: Span,
+}
+
+impl Backend {
+    fn parse(pair: &Pair<Rule>) -> Result<Self, AstParseError> {
+        let span: Span = pair.as_span().into();
+        let shape_error = || {
+            AstParseError::at(
+                "a backend must be a list of an address, a port and an optional weight".to_string(),
+                span,
+            )
+        };
+        let mut list = pair.clone();
+        while list.as_rule() == Rule::s_exp {
+            list = list.into_inner().next().ok_or_else(shape_error)?;
+        }
+        if list.as_rule() != Rule::list {
+            return Err(shape_error());
+        }
+        let inner: Vec<_> = list.into_inner().collect();
+        if !(2..=3).contains(&inner.len()) {
+            return Err(shape_error());
+        }
+
+        let port = inner[1].as_str().parse::<u16>().or(Err(AstParseError::at(
+            "bad port in def-upstream".to_string(),
+            inner[1].as_span().into(),
+        )))?;
+        let weight = match inner.get(2) {
+            Some(weight) => weight.as_str().parse::<u32>().or(Err(AstParseError::at(
+                "bad weight in def-upstream".to_string(),
+                weight.as_span().into(),
+            )))?,
+            None => 1,
+        };
+        Ok(Self {
+            addr: address(&inner[0]),
+            port,
+            weight,
+            span,
+        })
+    }
+}
+
+/// The health check of a `def-upstream`: `(check <interval-ms>)`, or `(check <interval-ms> <send> <expect>)`
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub interval_ms: u64,
+    pub send: Vec<u8>,
+    pub expect: Vec<u8>,
+    pub span: Span,
+}
+
+impl HealthCheck {
+    fn parse(pair: &Pair<Rule>) -> Result<Self, AstParseError> {
+        let span: Span = pair.as_span().into();
+        let mut list = pair.clone();
+        while list.as_rule() == Rule::s_exp {
+            list = list.into_inner().next().expect("a check is a list");
+        }
+        let inner: Vec<_> = list.into_inner().collect();
+        if inner.len() != 2 && inner.len() != 4 {
+            return Err(AstParseError::at(
+                "a check must be a list of an interval, and optionally bytes to send and bytes to expect"
+                    .to_string(),
+                span,
+            ));
+        }
+
+        let interval_ms = inner[1].as_str().parse::<u64>().or(Err(AstParseError::at(
+            "bad interval in def-upstream check".to_string(),
+            inner[1].as_span().into(),
+        )))?;
+        let bytes = |pair: &Pair<Rule>| match AstNode::try_from(pair.clone())? {
+            AstNode::String(s, _) => Ok(s.into_bytes()),
+            AstNode::Bytes(b, _) => Ok(b),
+            value => Err(AstParseError::at(
+                "a check sends and expects strings or `#x` bytes".to_string(),
+                value.span(),
+            )),
+        };
+        let (send, expect) = match inner.get(2..) {
+            Some([send, expect]) => (bytes(send)?, bytes(expect)?),
+            _ => (vec![], vec![]),
+        };
+        Ok(Self {
+            interval_ms,
+            send,
+            expect,
+            span,
+        })
+    }
+}
+
 /// These are all meant to be "special forms," which have a different order of evaluation from typical terms;
 /// for example, `(if a b c)` should only execute *either* the consequent or the alternative, depending on the truth value of `a`
 /// See: https://www.cs.cmu.edu/Groups/AI/html/cltl/clm/node59.html
 #[derive(Debug, Clone)]
 pub enum SpecialForm {
     /// (if <predicate> <consequent> <alternative>)
+    /// `(cond (<predicate> <body>)... (else <body>))` is parsed into nested `If`s
     If {
         predicate: Box<AstNode>,
         consequent: Box<AstNode>,
@@ -79,28 +218,39 @@ pub enum SpecialForm {
     },
     /// (def-var <name> <value>)
     DefVar { name: String, value: Box<AstNode> },
+    /// (def-set <name> (<value>...))
+    DefSet { name: String, members: Vec<AstNode> },
+    /// (def-upstream <name> <strategy> [(check ...)] (<addr> <port> [<weight>])...)
+    DefUpstream {
+        name: String,
+        strategy: Strategy,
+        backends: Vec<Backend>,
+        check: Option<HealthCheck>,
+    },
     /// (def-rule <name> <body>)
     DefRule { name: String, body: Box<AstNode> },
+    /// (def-response-rule <name> <body>), run on data the upstream sends back to the client
+    DefResponseRule { name: String, body: Box<AstNode> },
     /// (set-mode OPAQUE) or (set-mode TRANSPARENT)
     SetMode { mode: ProxyMode },
+    /// (set-eval ON-DATA) or (set-eval ON-CONNECT)
+    SetEval { evaluation: Evaluation },
 }
 
 impl SpecialForm {
-    fn parse_if(inner: Vec<Pair<Rule>>) -> Result<Self, AstParseError> {
+    fn parse_if(inner: Vec<Pair<Rule>>, span: Span) -> Result<Self, AstParseError> {
         // "if" + predicate + consequent + alternative
         if inner.len() != 4 {
-            Err(AstParseError::ParseError(format!(
-                "wrong arity for if; expected 3, received {}",
-                inner.len() - 1
-            )))
+            Err(AstParseError::at(
+                format!(
+                    "wrong arity for if; expected 3, received {}",
+                    inner.len() - 1
+                ),
+                span,
+            ))
         } else {
             // FIXME: I believe the clones here are necessary
-            let predicate = Box::new(AstNode::try_from(inner[1].clone())?);
-            if !matches!(*predicate, AstNode::Ident(_) | AstNode::Sexp(_)) {
-                return Err(AstParseError::ParseError(
-                    "predicate must be an ident or Sexp".to_string(),
-                ));
-            }
+            let predicate = Box::new(Self::parse_predicate(inner[1].clone())?);
             let consequent = Box::new(AstNode::try_from(inner[2].clone())?);
             let alternative = Box::new(AstNode::try_from(inner[3].clone())?);
 
@@ -112,52 +262,225 @@ impl SpecialForm {
         }
     }
 
+    fn parse_predicate(pair: Pair<Rule>) -> Result<AstNode, AstParseError> {
+        let predicate = AstNode::try_from(pair)?;
+        if !matches!(predicate, AstNode::Ident(..) | AstNode::Sexp(..)) {
+            return Err(AstParseError::at(
+                "predicate must be an ident or Sexp".to_string(),
+                predicate.span(),
+            ));
+        }
+        Ok(predicate)
+    }
+
+    /// `cond` is sugar for a chain of `if`s:
+    /// `(cond (a x) (b y) (else z))` is parsed as `(if a x (if b y z))`.
+    /// Without an `else` clause, a `cond` where no predicate holds will `CONTINUE`.
+    fn parse_cond(inner: Vec<Pair<Rule>>, span: Span) -> Result<Self, AstParseError> {
+        let mut clauses = vec![];
+        let mut otherwise = None;
+        for clause in inner.into_iter().skip(1) {
+            let clause_span: Span = clause.as_span().into();
+            if otherwise.is_some() {
+                return Err(AstParseError::at(
+                    "`else` must be the last clause of a cond".to_string(),
+                    clause_span,
+                ));
+            }
+
+            let clause = clause
+                .into_inner()
+                .next()
+                .expect("an `s_exp` is always either `list` or `atom`");
+            let parts: Vec<_> = match clause.as_rule() {
+                Rule::list => clause.into_inner().collect(),
+                _ => vec![],
+            };
+            if parts.len() != 2 {
+                return Err(AstParseError::at(
+                    "expected a cond clause of the form `(<predicate> <body>)`".to_string(),
+                    clause_span,
+                ));
+            }
+
+            let body = AstNode::try_from(parts[1].clone())?;
+            if parts[0].as_str() == "else" {
+                otherwise = Some(body);
+            } else {
+                let predicate = Self::parse_predicate(parts[0].clone())?;
+                clauses.push((predicate, body, clause_span));
+            }
+        }
+
+        let alternative = otherwise
+            .unwrap_or(AstNode::Keyword(Keyword::Outcome(RuleOutcome::CONTINUE), span));
+        if clauses.is_empty() {
+            return Err(AstParseError::at(
+                "cond requires at least one clause besides `else`".to_string(),
+                span,
+            ));
+        }
+        let (predicate, consequent, _) = clauses.remove(0);
+        // each later clause becomes the alternative of the one before it
+        let alternative = clauses.into_iter().rev().fold(
+            alternative,
+            |alternative, (predicate, consequent, clause_span)| {
+                AstNode::Keyword(
+                    Keyword::SpecialForm(Self::If {
+                        predicate: Box::new(predicate),
+                        consequent: Box::new(consequent),
+                        alternative: Box::new(alternative),
+                    }),
+                    clause_span,
+                )
+            },
+        );
+
+        Ok(Self::If {
+            predicate: Box::new(predicate),
+            consequent: Box::new(consequent),
+            alternative: Box::new(alternative),
+        })
+    }
+
     fn parse_def(
         special_form: &str,
         inner: Vec<Pair<Rule>>,
+        span: Span,
     ) -> Result<(String, Box<AstNode>), AstParseError> {
         // def-xxx + name + value
         if inner.len() != 3 {
-            Err(AstParseError::ParseError(format!(
-                "wrong arity for {}; expected 2, received {}",
-                special_form,
-                inner.len() - 1
-            )))
+            Err(AstParseError::at(
+                format!(
+                    "wrong arity for {}; expected 2, received {}",
+                    special_form,
+                    inner.len() - 1
+                ),
+                span,
+            ))
         } else {
             let name = AstNode::try_from(inner[1].clone())?;
-            if let AstNode::Ident(name) = name {
+            if let AstNode::Ident(name, _) = name {
                 let value = Box::new(AstNode::try_from(inner[2].clone())?);
                 Ok((name, value))
             } else {
-                Err(AstParseError::ParseError(format!(
-                    "{} expected an `ident`, found {:?}",
-                    special_form,
-                    inner[1].as_rule()
-                )))
+                Err(AstParseError::at(
+                    format!(
+                        "{} expected an `ident`, found {:?}",
+                        special_form,
+                        inner[1].as_rule()
+                    ),
+                    name.span(),
+                ))
             }
         }
     }
 
-    fn parse_def_var(inner: Vec<Pair<Rule>>) -> Result<Self, AstParseError> {
-        Self::parse_def("def-var", inner).map(|(name, value)| Self::DefVar { name, value })
+    fn parse_def_var(inner: Vec<Pair<Rule>>, span: Span) -> Result<Self, AstParseError> {
+        Self::parse_def("def-var", inner, span).map(|(name, value)| Self::DefVar { name, value })
     }
 
-    fn parse_def_rule(inner: Vec<Pair<Rule>>) -> Result<Self, AstParseError> {
-        Self::parse_def("def-rule", inner).map(|(name, body)| Self::DefRule { name, body })
+    fn parse_def_set(inner: Vec<Pair<Rule>>, span: Span) -> Result<Self, AstParseError> {
+        let (name, members) = Self::parse_def("def-set", inner, span)?;
+        match *members {
+            AstNode::Sexp(members, _) => Ok(Self::DefSet { name, members }),
+            members => Err(AstParseError::at(
+                "def-set expected a list of values".to_string(),
+                members.span(),
+            )),
+        }
     }
 
-    fn parse_set_mode(inner: Vec<Pair<Rule>>) -> Result<Self, AstParseError> {
+    fn parse_def_upstream(inner: Vec<Pair<Rule>>, span: Span) -> Result<Self, AstParseError> {
+        // def-upstream + name + strategy + backends
+        if inner.len() < 3 {
+            return Err(AstParseError::at(
+                format!(
+                    "wrong arity for def-upstream; expected at least 2, received {}",
+                    inner.len() - 1
+                ),
+                span,
+            ));
+        }
+        let name = match AstNode::try_from(inner[1].clone())? {
+            AstNode::Ident(name, _) => name,
+            name => {
+                return Err(AstParseError::at(
+                    format!(
+                        "def-upstream expected an `ident`, found {:?}",
+                        inner[1].as_rule()
+                    ),
+                    name.span(),
+                ))
+            }
+        };
+        let strategy = Strategy::try_from(inner[2].as_str())
+            .map_err(|e| e.or_at(inner[2].as_span().into()))?;
+        let mut backends = vec![];
+        let mut check = None;
+        for pair in &inner[3..] {
+            if list_head(pair) != Some("check") {
+                backends.push(Backend::parse(pair)?);
+            } else if check.is_none() {
+                check = Some(HealthCheck::parse(pair)?);
+            } else {
+                return Err(AstParseError::at(
+                    "def-upstream takes at most one check".to_string(),
+                    pair.as_span().into(),
+                ));
+            }
+        }
+
+        Ok(Self::DefUpstream {
+            name,
+            strategy,
+            backends,
+            check,
+        })
+    }
+
+    fn parse_def_rule(inner: Vec<Pair<Rule>>, span: Span) -> Result<Self, AstParseError> {
+        Self::parse_def("def-rule", inner, span).map(|(name, body)| Self::DefRule { name, body })
+    }
+
+    fn parse_def_response_rule(inner: Vec<Pair<Rule>>, span: Span) -> Result<Self, AstParseError> {
+        Self::parse_def("def-response-rule", inner, span)
+            .map(|(name, body)| Self::DefResponseRule { name, body })
+    }
+
+    fn parse_set_mode(inner: Vec<Pair<Rule>>, span: Span) -> Result<Self, AstParseError> {
         // set-mode + OPAQUE/TRANSPARENT
         if inner.len() != 2 {
-            Err(AstParseError::ParseError(format!(
-                "wrong arity for set-mode; expected 1, received {}",
-                inner.len() - 1
-            )))
+            Err(AstParseError::at(
+                format!(
+                    "wrong arity for set-mode; expected 1, received {}",
+                    inner.len() - 1
+                ),
+                span,
+            ))
         } else {
-            let mode = ProxyMode::try_from(inner[1].as_str())?;
+            let mode = ProxyMode::try_from(inner[1].as_str())
+                .map_err(|e| e.or_at(inner[1].as_span().into()))?;
             Ok(Self::SetMode { mode })
         }
     }
+
+    fn parse_set_eval(inner: Vec<Pair<Rule>>, span: Span) -> Result<Self, AstParseError> {
+        // set-eval + ON-DATA/ON-CONNECT
+        if inner.len() != 2 {
+            Err(AstParseError::at(
+                format!(
+                    "wrong arity for set-eval; expected 1, received {}",
+                    inner.len() - 1
+                ),
+                span,
+            ))
+        } else {
+            let evaluation = Evaluation::try_from(inner[1].as_str())
+                .map_err(|e| e.or_at(inner[1].as_span().into()))?;
+            Ok(Self::SetEval { evaluation })
+        }
+    }
 }
 
 impl TryFrom<Pair<'_, Rule>> for SpecialForm {
@@ -174,31 +497,38 @@ impl TryFrom<Pair<'_, Rule>> for SpecialForm {
                     .expect("an `s_exp` is always either `list` or `atom`"),
             ),
             Rule::list => {
+                let span = value.as_span().into();
                 let inner: Vec<_> = value.into_inner().collect();
                 if inner.is_empty() {
-                    Err(Self::Error::ParseError(
+                    Err(Self::Error::at(
                         "expected non-empty list, found `nil`".to_string(),
+                        span,
                     ))
                 } else {
                     match inner.first() {
                         None => unreachable!("list is non-empty"),
                         Some(expr) => match expr.as_str() {
-                            "if" => Self::parse_if(inner),
-                            "def-var" => Self::parse_def_var(inner),
-                            "def-rule" => Self::parse_def_rule(inner),
-                            "set-mode" => Self::parse_set_mode(inner),
-                            _ => Err(Self::Error::ParseError(format!(
-                                "expected a special form, received {}",
-                                expr.as_str()
-                            ))),
+                            "if" => Self::parse_if(inner, span),
+                            "cond" => Self::parse_cond(inner, span),
+                            "def-var" => Self::parse_def_var(inner, span),
+                            "def-set" => Self::parse_def_set(inner, span),
+                            "def-upstream" => Self::parse_def_upstream(inner, span),
+                            "def-rule" => Self::parse_def_rule(inner, span),
+                            "def-response-rule" => Self::parse_def_response_rule(inner, span),
+                            "set-mode" => Self::parse_set_mode(inner, span),
+                            "set-eval" => Self::parse_set_eval(inner, span),
+                            _ => Err(Self::Error::at(
+                                format!("expected a special form, received {}", expr.as_str()),
+                                expr.as_span().into(),
+                            )),
                         },
                     }
                 }
             }
-            rule => Err(Self::Error::ParseError(format!(
-                "expected `s_expr`, received {:?}",
-                rule
-            ))),
+            rule => Err(Self::Error::at(
+                format!("expected `s_expr`, received {:?}", rule),
+                value.as_span().into(),
+            )),
         }
     }
 }
@@ -214,49 +544,85 @@ pub enum RuleOutcome {
     /// Rewrite packet content via regex substitution
     REWRITE {
         pattern: String,
-        replace_with: String,
+        replace_with: Vec<u8>,
     },
+    /// Forward the inbound packet to a backend of the named `def-upstream`
+    BALANCE { upstream: String },
     /// Continue on to the next Rule
     CONTINUE,
 }
 
 impl RuleOutcome {
-    fn parse_redirect(inner: Vec<Pair<Rule>>) -> Result<Self, AstParseError> {
+    fn parse_redirect(inner: Vec<Pair<Rule>>, span: Span) -> Result<Self, AstParseError> {
         // REDIRECT + target + port
         if inner.len() != 3 {
-            Err(AstParseError::ParseError(format!(
-                "wrong arity for REDIRECT; expected 2, received {}",
-                inner.len() - 1
-            )))
+            Err(AstParseError::at(
+                format!(
+                    "wrong arity for REDIRECT; expected 2, received {}",
+                    inner.len() - 1
+                ),
+                span,
+            ))
         } else {
             // avoiding unnecessary recursion here
             inner[2]
                 .as_str()
                 .parse::<u16>()
-                .or(Err(AstParseError::ParseError(
+                .or(Err(AstParseError::at(
                     "bad port to REDIRECT".to_string(),
+                    inner[2].as_span().into(),
                 )))
                 .map(|port| RuleOutcome::REDIRECT {
                     // target validity check to be done elsewhere
-                    addr: inner[1].as_str().trim_matches(|c| c == '"').to_string(),
+                    addr: address(&inner[1]),
                     port,
                 })
         }
     }
 
-    fn parse_rewrite(inner: Vec<Pair<Rule>>) -> Result<Self, AstParseError> {
+    fn parse_balance(inner: Vec<Pair<Rule>>, span: Span) -> Result<Self, AstParseError> {
+        // BALANCE + upstream
+        if inner.len() != 2 {
+            return Err(AstParseError::at(
+                format!(
+                    "wrong arity for BALANCE; expected 1, received {}",
+                    inner.len() - 1
+                ),
+                span,
+            ));
+        }
+        match AstNode::try_from(inner[1].clone())? {
+            AstNode::Ident(upstream, _) => Ok(Self::BALANCE { upstream }),
+            upstream => Err(AstParseError::at(
+                format!("BALANCE expected an `ident`, found {:?}", inner[1].as_rule()),
+                upstream.span(),
+            )),
+        }
+    }
+
+    fn parse_rewrite(inner: Vec<Pair<Rule>>, span: Span) -> Result<Self, AstParseError> {
         if inner.len() != 3 {
-            Err(AstParseError::ParseError(format!(
-                "wrong arity for REWRITE; expected 2, received {}",
-                inner.len() - 1
-            )))
+            Err(AstParseError::at(
+                format!(
+                    "wrong arity for REWRITE; expected 2, received {}",
+                    inner.len() - 1
+                ),
+                span,
+            ))
         } else {
-            let pattern = inner[1].as_str().trim_matches(|c| c == '"');
-            let replace_with = inner[2].as_str().trim_matches(|c| c == '"');
+            let pattern = string_contents(&inner[1])?;
+            let pattern = String::from_utf8(pattern).map_err(|_| {
+                AstParseError::at(
+                    "REWRITE patterns must be valid UTF-8; write `\\\\xNN` to match a raw byte"
+                        .to_string(),
+                    inner[1].as_span().into(),
+                )
+            })?;
+            let replace_with = string_contents(&inner[2])?;
 
             Ok(Self::REWRITE {
-                pattern: pattern.to_string(),
-                replace_with: replace_with.to_string(),
+                pattern,
+                replace_with,
             })
         }
     }
@@ -276,21 +642,27 @@ impl TryFrom<Pair<'_, Rule>> for RuleOutcome {
                     .expect("an `s_exp` is always either `list` or `atom`"),
             ),
             Rule::list => {
+                let span = value.as_span().into();
                 let inner: Vec<_> = value.into_inner().collect();
                 if inner.is_empty() {
-                    Err(Self::Error::ParseError(
+                    Err(Self::Error::at(
                         "expected non-empty list, found `nil`".to_string(),
+                        span,
                     ))
                 } else {
                     match inner.first() {
                         None => unreachable!("list is non-empty"),
                         Some(expr) => match expr.as_str() {
-                            "REDIRECT" => Self::parse_redirect(inner),
-                            "REWRITE" => Self::parse_rewrite(inner),
-                            ident => Err(Self::Error::ParseError(format!(
-                                "expected one of `REDIRECT` or `REWRITE`, received {}",
-                                ident
-                            ))),
+                            "REDIRECT" => Self::parse_redirect(inner, span),
+                            "REWRITE" => Self::parse_rewrite(inner, span),
+                            "BALANCE" => Self::parse_balance(inner, span),
+                            ident => Err(Self::Error::at(
+                                format!(
+                                    "expected one of `REDIRECT`, `REWRITE` or `BALANCE`, received {}",
+                                    ident
+                                ),
+                                expr.as_span().into(),
+                            )),
                         },
                     }
                 }
@@ -305,15 +677,18 @@ impl TryFrom<Pair<'_, Rule>> for RuleOutcome {
                 "DROP" => Ok(Self::DROP),
                 "REJECT" => Ok(Self::REJECT),
                 "CONTINUE" => Ok(Self::CONTINUE),
-                ident => Err(Self::Error::ParseError(format!(
-                    "expected one of `DROP`, `REJECT`, or `CONTINUE`, received {}",
-                    ident
-                ))),
+                ident => Err(Self::Error::at(
+                    format!(
+                        "expected one of `DROP`, `REJECT`, or `CONTINUE`, received {}",
+                        ident
+                    ),
+                    value.as_span().into(),
+                )),
             },
-            rule => Err(Self::Error::ParseError(format!(
-                "expected `s_expr`, received {:?}",
-                rule
-            ))),
+            rule => Err(Self::Error::at(
+                format!("expected `s_expr`, received {:?}", rule),
+                value.as_span().into(),
+            )),
         }
     }
 }
@@ -330,25 +705,132 @@ impl TryFrom<Pair<'_, Rule>> for Keyword {
     /// Tries to convert a parse tree node to a BuiltinOp.
     /// Expects an `s_expr` as input
     fn try_from(value: Pair<'_, Rule>) -> Result<Self, Self::Error> {
+        // a list headed by a keyword can only be that keyword, so its own error is the useful one
+        match list_head(&value) {
+            Some("REDIRECT" | "REWRITE" | "BALANCE") => {
+                return RuleOutcome::try_from(value).map(Self::Outcome)
+            }
+            Some(head) if is_keyword_form(head) => {
+                return SpecialForm::try_from(value).map(Self::SpecialForm)
+            }
+            _ => {}
+        }
+
         if let Ok(form) = SpecialForm::try_from(value.clone()) {
             Ok(Self::SpecialForm(form))
-        } else if let Ok(outcome) = RuleOutcome::try_from(value) {
+        } else if let Ok(outcome) = RuleOutcome::try_from(value.clone()) {
             Ok(Self::Outcome(outcome))
         } else {
-            Err(Self::Error::ParseError("not a builtin".to_string()))
+            Err(Self::Error::at(
+                "not a builtin".to_string(),
+                value.as_span().into(),
+            ))
         }
     }
 }
 
+/// Whether a list headed by `head` can only be a special form or an outcome
+fn is_keyword_form(head: &str) -> bool {
+    matches!(
+        head,
+        "if"
+            | "cond"
+            | "def-var"
+            | "def-set"
+            | "def-upstream"
+            | "def-rule"
+            | "def-response-rule"
+            | "set-mode"
+            | "set-eval"
+            | "REDIRECT"
+            | "REWRITE"
+            | "BALANCE"
+    )
+}
+
+/// The target of a `REDIRECT` or the address of a backend, written as `ip"..."` or just `"..."`.
+/// Unlike the operands of predicates and `def-var`s, where a plain string is a byte string and
+/// [`ValidationError::UntypedAddress`](validate::ValidationError::UntypedAddress) asks for the
+/// prefix, these places only ever take an address, so a plain string can't be mistaken for one.
+fn address(pair: &Pair<Rule>) -> String {
+    let addr = pair.as_str();
+    addr.strip_prefix("ip")
+        .unwrap_or(addr)
+        .trim_matches(|c| c == '"')
+        .to_string()
+}
+
+/// The first element of a list, if `value` is a non-empty list
+fn list_head<'a>(value: &Pair<'a, Rule>) -> Option<&'a str> {
+    let mut value = value.clone();
+    while value.as_rule() == Rule::s_exp {
+        value = value.into_inner().next()?;
+    }
+    match value.as_rule() {
+        Rule::list => value.into_inner().next().map(|head| head.as_str()),
+        _ => None,
+    }
+}
+
+/// The comments of a parsed `program`, which its parse tree leaves out
+fn comments(program: &Pair<'_, Rule>) -> Vec<Spanned<String>> {
+    let offset = program.as_span().start();
+    RuleParser::parse(Rule::trivia, program.as_str())
+        .expect("the source of a `program` always scans")
+        .flatten()
+        .filter(|pair| pair.as_rule() == Rule::comment)
+        .map(|comment| Spanned {
+            value: comment.as_str().to_string(),
+            span: Some(Span {
+                start: offset + comment.as_span().start(),
+                end: offset + comment.as_span().end(),
+            }),
+        })
+        .collect()
+}
+
+/// Every node but `Program` carries the span of source it was parsed from
 #[derive(Debug, Clone)]
 pub enum AstNode {
-    Keyword(Keyword),
-    Num(i64),
-    Bool(bool),
-    Ident(String),
-    String(String),
-    Sexp(Vec<AstNode>),
-    Program(Vec<AstNode>),
+    Keyword(Keyword, Span),
+    Num(i64, Span),
+    Bool(bool, Span),
+    Ident(String, Span),
+    /// A plain `"..."` string, which is a byte string
+    String(String, Span),
+    /// `ip"..."`
+    Ip(String, Span),
+    /// `cidr"..."`
+    Cidr(String, Span),
+    /// `#x"..."`
+    Bytes(Vec<u8>, Span),
+    Sexp(Vec<AstNode>, Span),
+    /// The statements, and the comments between them, as written
+    Program(Vec<AstNode>, Vec<Spanned<String>>),
+}
+
+impl AstNode {
+    /// The span of source this node was parsed from
+    pub fn span(&self) -> Span {
+        match self {
+            AstNode::Keyword(_, span)
+            | AstNode::Num(_, span)
+            | AstNode::Bool(_, span)
+            | AstNode::Ident(_, span)
+            | AstNode::String(_, span)
+            | AstNode::Ip(_, span)
+            | AstNode::Cidr(_, span)
+            | AstNode::Bytes(_, span)
+            | AstNode::Sexp(_, span) => *span,
+            AstNode::Program(statements, _) => match (statements.first(), statements.last()) {
+                (Some(first), Some(last)) => Span {
+                    start: first.span().start,
+                    end: last.span().end,
+                },
+                _ => Span::default(),
+            },
+        }
+    }
 }
 
 impl TryFrom<Pair<'_, Rule>> for AstNode {
@@ -357,18 +839,22 @@ impl TryFrom<Pair<'_, Rule>> for AstNode {
     /// Tries to convert a parse tree node to an AST.
     /// Expects an `s_expr` or a `program` as input
     fn try_from(value: Pair<'_, Rule>) -> Result<Self, Self::Error> {
-        if let Ok(keyword) = Keyword::try_from(value.clone()) {
-            Ok(AstNode::Keyword(keyword))
+        let span = value.as_span().into();
+        if list_head(&value).is_some_and(is_keyword_form) {
+            Keyword::try_from(value).map(|keyword| AstNode::Keyword(keyword, span))
+        } else if let Ok(keyword) = Keyword::try_from(value.clone()) {
+            Ok(AstNode::Keyword(keyword, span))
         } else {
             match value.as_rule() {
                 Rule::program => {
+                    let comments = comments(&value);
                     let inner = value
                         .into_inner()
                         .filter(|v| !matches!(v.as_rule(), Rule::EOI))
                         .map(Self::try_from)
                         .collect::<Result<Vec<_>, _>>()?;
 
-                    Ok(Self::Program(inner))
+                    Ok(Self::Program(inner, comments))
                 }
                 Rule::s_exp => Self::try_from(
                     value
@@ -382,7 +868,7 @@ impl TryFrom<Pair<'_, Rule>> for AstNode {
                         .map(Self::try_from)
                         .collect::<Result<Vec<_>, _>>()?;
 
-                    Ok(Self::Sexp(inner))
+                    Ok(Self::Sexp(inner, span))
                 }
                 Rule::atom => {
                     Self::try_from(value.into_inner().next().expect(
@@ -392,41 +878,151 @@ impl TryFrom<Pair<'_, Rule>> for AstNode {
                 Rule::ident => {
                     let value = value.as_str();
                     if RESERVED_KEYWORDS.contains(value) {
-                        Err(Self::Error::ParseError(format!(
-                            "{} is a reserved keyword!",
-                            value
-                        )))
+                        Err(Self::Error::at(
+                            format!("{} is a reserved keyword!", value),
+                            span,
+                        ))
                     } else {
-                        Ok(Self::Ident(value.to_string()))
+                        Ok(Self::Ident(value.to_string(), span))
                     }
                 }
-                Rule::string => Ok(Self::String(
-                    value.as_str().trim_matches(|c| c == '"').to_string(),
-                )),
-                Rule::number => Ok(Self::Num(
-                    value
-                        .as_str()
-                        .parse::<i64>()
-                        .expect("`number` is guaranteed to be only ascii digits"),
-                )),
+                // strings that aren't valid UTF-8 can only be byte strings
+                Rule::string => match String::from_utf8(string_contents(&value)?) {
+                    Ok(s) => Ok(Self::String(s, span)),
+                    Err(e) => Ok(Self::Bytes(e.into_bytes(), span)),
+                },
+                Rule::ip_string => Ok(Self::Ip(unquote(value)?, span)),
+                Rule::cidr_string => Ok(Self::Cidr(unquote(value)?, span)),
+                Rule::hex => {
+                    let digits: Vec<u8> = unquote(value)?.bytes().filter(|b| *b != b' ').collect();
+                    if !digits.len().is_multiple_of(2) {
+                        return Err(Self::Error::at(
+                            "hex literals must have an even number of digits".to_string(),
+                            span,
+                        ));
+                    }
+                    let bytes = digits
+                        .chunks(2)
+                        .map(|pair| {
+                            // `hex_part` is guaranteed to be only hex digits and spaces
+                            u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap()
+                        })
+                        .collect();
+                    Ok(Self::Bytes(bytes, span))
+                }
+                // `number` is guaranteed to be only ascii digits, but it may not fit in an i64
+                Rule::number => value.as_str().parse::<i64>().map_or_else(
+                    |_| {
+                        Err(Self::Error::at(
+                            format!("{} does not fit in a 64-bit integer", value.as_str()),
+                            span,
+                        ))
+                    },
+                    |n| Ok(Self::Num(n, span)),
+                ),
                 Rule::bool => match value.as_str() {
-                    "#t" => Ok(Self::Bool(true)),
-                    "#f" => Ok(Self::Bool(false)),
+                    "#t" => Ok(Self::Bool(true, span)),
+                    "#f" => Ok(Self::Bool(false, span)),
                     _ => unreachable!("`bool` should only match #t or #f"),
                 },
-                rule => Err(Self::Error::ParseError(format!(
-                    "expected `s_expr`, received {:?}",
-                    rule
-                ))),
+                rule => Err(Self::Error::at(
+                    format!("expected `s_expr`, received {:?}", rule),
+                    span,
+                )),
             }
         }
     }
 }
 
+/// The contents of the quotes in a typed string
+fn unquote(value: Pair<Rule>) -> Result<String, AstParseError> {
+    let inner = value
+        .into_inner()
+        .find(|inner| matches!(inner.as_rule(), Rule::string | Rule::hex_part))
+        .expect("a typed string always has contents");
+    match inner.as_rule() {
+        // addresses are validated later, so there's no need to complain about bad UTF-8 here
+        Rule::string => Ok(String::from_utf8_lossy(&string_contents(&inner)?).into_owned()),
+        _ => Ok(inner.as_str().to_string()),
+    }
+}
+
+/// The bytes a string literal stands for, with its quotes removed and escapes decoded.
+/// Anything that isn't a quoted string is taken as-is.
+fn string_contents(value: &Pair<Rule>) -> Result<Vec<u8>, AstParseError> {
+    let literal = value.as_str();
+    let contents = match literal.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        Some(contents) => contents,
+        None => return Ok(literal.as_bytes().to_owned()),
+    };
+    // the offset of `contents` in the source, for pointing at bad escapes
+    let start = value.as_span().start() + 1;
+
+    let mut bytes = Vec::with_capacity(contents.len());
+    let mut chars = contents.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        let escape = match chars.next() {
+            Some((_, 'n')) => b'\n',
+            Some((_, 'r')) => b'\r',
+            Some((_, '"')) => b'"',
+            Some((_, '\\')) => b'\\',
+            Some((_, 'x')) => {
+                let digits: String = chars.by_ref().take(2).map(|(_, c)| c).collect();
+                match u8::from_str_radix(&digits, 16) {
+                    Ok(byte) if digits.len() == 2 && digits.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                        byte
+                    }
+                    _ => {
+                        return Err(AstParseError::at(
+                            "`\\x` must be followed by two hex digits".to_string(),
+                            Span {
+                                start: start + i,
+                                end: start + i + 2 + digits.len(),
+                            },
+                        ))
+                    }
+                }
+            }
+            other => {
+                let end = other.map_or(contents.len(), |(j, c)| j + c.len_utf8());
+                return Err(AstParseError::at(
+                    format!("unknown escape `{}`", &contents[i..end]),
+                    Span {
+                        start: start + i,
+                        end: start + end,
+                    },
+                ));
+            }
+        };
+        bytes.push(escape);
+    }
+    Ok(bytes)
+}
+
 // TODO: convert ParseError messages to enums
 #[derive(Debug, Clone)]
 pub enum AstParseError {
-    ParseError(String),
+    /// A message, and where in the source it applies when that's known
+    ParseError(String, Option<Span>),
+}
+
+impl AstParseError {
+    fn at(message: String, span: Span) -> Self {
+        Self::ParseError(message, Some(span))
+    }
+
+    /// Attaches a span to an error that doesn't have one yet
+    fn or_at(self, span: Span) -> Self {
+        match self {
+            Self::ParseError(message, None) => Self::ParseError(message, Some(span)),
+            located => located,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -455,11 +1051,11 @@ mod tests {
         fn try_from__fails_on_invalid_strings() {
             // bad capitalization?
             let proxy_mode = ProxyMode::try_from("OPaQUE");
-            assert!(matches!(proxy_mode, Err(AstParseError::ParseError(_))));
+            assert!(matches!(proxy_mode, Err(AstParseError::ParseError(..))));
 
             // random string?
             let proxy_mode = ProxyMode::try_from("oeau");
-            assert!(matches!(proxy_mode, Err(AstParseError::ParseError(_))));
+            assert!(matches!(proxy_mode, Err(AstParseError::ParseError(..))));
         }
     }
 
@@ -540,6 +1136,38 @@ mod tests {
             }
         }
 
+        mod set_eval {
+            use super::*;
+            use crate::vm::Evaluation;
+
+            #[test]
+            fn try_from__works_with_expected_parse_trees() {
+                let parse_tree = RuleParser::parse(Rule::s_exp, "(set-eval ON-CONNECT)")
+                    .unwrap()
+                    .next()
+                    .unwrap();
+
+                let ast = SpecialForm::try_from(parse_tree).unwrap();
+                assert!(matches!(
+                    ast,
+                    SpecialForm::SetEval {
+                        evaluation: Evaluation::OnConnect
+                    }
+                ));
+            }
+
+            #[test]
+            fn try_from__fails_on_well_formed_parse_tree_with_unexpected_argument() {
+                let parse_tree = RuleParser::parse(Rule::s_exp, "(set-eval ON-DISCONNECT)")
+                    .unwrap()
+                    .next()
+                    .unwrap();
+
+                let ast = SpecialForm::try_from(parse_tree);
+                assert!(ast.is_err());
+            }
+        }
+
         mod r#if {
             use super::*;
 
@@ -558,7 +1186,7 @@ mod tests {
                         consequent,
                         alternative
                     }
-                    if matches!(*predicate, AstNode::Sexp(_)) && matches!(*consequent.clone(), AstNode::Ident(id) if id == "foo") && matches!(*alternative, AstNode::Num(n) if n == 85)
+                    if matches!(*predicate, AstNode::Sexp(..)) && matches!(*consequent.clone(), AstNode::Ident(id, _) if id == "foo") && matches!(*alternative, AstNode::Num(n, _) if n == 85)
                 ));
             }
 
@@ -594,6 +1222,90 @@ mod tests {
             }
         }
 
+        mod cond {
+            use super::*;
+            use crate::ast::{Keyword, RuleOutcome};
+
+            #[test]
+            fn try_from__desugars_to_nested_ifs() {
+                let parse_tree =
+                    RuleParser::parse(Rule::s_exp, "(cond (a DROP) ((b) REJECT) (else CONTINUE))")
+                        .unwrap()
+                        .next()
+                        .unwrap();
+
+                let SpecialForm::If {
+                    predicate,
+                    consequent,
+                    alternative,
+                } = SpecialForm::try_from(parse_tree).unwrap()
+                else {
+                    panic!("expected an if");
+                };
+                assert!(matches!(*predicate, AstNode::Ident(id, _) if id == "a"));
+                assert!(matches!(
+                    *consequent,
+                    AstNode::Keyword(Keyword::Outcome(RuleOutcome::DROP), _)
+                ));
+
+                let AstNode::Keyword(
+                    Keyword::SpecialForm(SpecialForm::If {
+                        predicate,
+                        consequent,
+                        alternative,
+                    }),
+                    _,
+                ) = *alternative
+                else {
+                    panic!("expected a nested if");
+                };
+                assert!(matches!(*predicate, AstNode::Sexp(..)));
+                assert!(matches!(
+                    *consequent,
+                    AstNode::Keyword(Keyword::Outcome(RuleOutcome::REJECT), _)
+                ));
+                assert!(matches!(
+                    *alternative,
+                    AstNode::Keyword(Keyword::Outcome(RuleOutcome::CONTINUE), _)
+                ));
+            }
+
+            #[test]
+            fn try_from__continues_without_else() {
+                let parse_tree = RuleParser::parse(Rule::s_exp, "(cond (a DROP))")
+                    .unwrap()
+                    .next()
+                    .unwrap();
+
+                let ast = SpecialForm::try_from(parse_tree).unwrap();
+                assert!(matches!(
+                    ast,
+                    SpecialForm::If { alternative, .. }
+                    if matches!(*alternative, AstNode::Keyword(Keyword::Outcome(RuleOutcome::CONTINUE), _))
+                ));
+            }
+
+            #[test]
+            fn try_from__fails_on_malformed_clauses() {
+                for cond in [
+                    "(cond)",
+                    "(cond (else DROP))",
+                    "(cond (else DROP) (a REJECT))",
+                    "(cond (a DROP REJECT))",
+                    "(cond a)",
+                    "(cond (69 DROP))",
+                ] {
+                    let parse_tree = RuleParser::parse(Rule::s_exp, cond)
+                        .unwrap()
+                        .next()
+                        .unwrap();
+
+                    let ast = SpecialForm::try_from(parse_tree);
+                    assert!(ast.is_err(), "{}", cond);
+                }
+            }
+        }
+
         mod def_var {
             use super::*;
 
@@ -607,7 +1319,7 @@ mod tests {
                 let ast = SpecialForm::try_from(parse_tree).unwrap();
                 assert!(matches!(ast, SpecialForm::DefVar {
                     name, value
-                } if name == "foo" && matches!(*value, AstNode::Num(n) if n == 420)));
+                } if name == "foo" && matches!(*value, AstNode::Num(n, _) if n == 420)));
             }
 
             #[test]
@@ -642,8 +1354,117 @@ mod tests {
             }
         }
 
+        mod def_set {
+            use super::*;
+
+            #[test]
+            fn try_from__works_with_expected_parse_trees() {
+                let parse_tree =
+                    RuleParser::parse(Rule::s_exp, r#"(def-set blocked (ip"1.2.3.4" ip"5.6.7.8"))"#)
+                        .unwrap()
+                        .next()
+                        .unwrap();
+
+                let ast = SpecialForm::try_from(parse_tree).unwrap();
+                assert!(matches!(ast, SpecialForm::DefSet {
+                    name,
+                    members
+                } if name == "blocked" && matches!(members.as_slice(), [AstNode::Ip(a, _), AstNode::Ip(b, _)] if a == "1.2.3.4" && b == "5.6.7.8")));
+            }
+
+            #[test]
+            fn try_from__fails_on_well_formed_parse_tree_with_unexpected_argument() {
+                let parse_tree = RuleParser::parse(Rule::s_exp, r#"(def-set blocked ip"1.2.3.4")"#)
+                    .unwrap()
+                    .next()
+                    .unwrap();
+
+                let ast = SpecialForm::try_from(parse_tree);
+                assert!(ast.is_err());
+            }
+        }
+
+        mod def_upstream {
+            use super::*;
+            use crate::ast::{Backend, HealthCheck};
+            use crate::vm::Strategy;
+
+            #[test]
+            fn try_from__works_with_expected_parse_trees() {
+                let parse_tree = RuleParser::parse(
+                    Rule::s_exp,
+                    r#"(def-upstream web LEAST-CONNECTIONS ("10.0.0.1" 8080) (ip"10.0.0.2" 8080 3))"#,
+                )
+                .unwrap()
+                .next()
+                .unwrap();
+
+                let ast = SpecialForm::try_from(parse_tree).unwrap();
+                assert!(matches!(ast, SpecialForm::DefUpstream {
+                    name,
+                    strategy: Strategy::LeastConnections,
+                    backends,
+                    check: None,
+                } if name == "web" && matches!(backends.as_slice(), [
+                    Backend { addr: a, port: 8080, weight: 1, .. },
+                    Backend { addr: b, port: 8080, weight: 3, .. },
+                ] if a == "10.0.0.1" && b == "10.0.0.2")));
+            }
+
+            #[test]
+            fn try_from__parses_checks() {
+                let parse_tree = RuleParser::parse(
+                    Rule::s_exp,
+                    r#"(def-upstream cache ROUND-ROBIN (check 1000 "PING\r\n" #x"2b 50") ("10.0.0.1" 6379))"#,
+                )
+                .unwrap()
+                .next()
+                .unwrap();
+
+                let ast = SpecialForm::try_from(parse_tree).unwrap();
+                assert!(matches!(ast, SpecialForm::DefUpstream {
+                    check: Some(HealthCheck { interval_ms: 1000, send, expect, .. }),
+                    backends,
+                    ..
+                } if send == b"PING\r\n" && expect == b"+P" && backends.len() == 1));
+
+                for program in [
+                    r#"(def-upstream web ROUND-ROBIN (check) ("10.0.0.1" 8080))"#,
+                    r#"(def-upstream web ROUND-ROBIN (check fast) ("10.0.0.1" 8080))"#,
+                    r#"(def-upstream web ROUND-ROBIN (check 1000 "GET /") ("10.0.0.1" 8080))"#,
+                    r#"(def-upstream web ROUND-ROBIN (check 1000 80 "OK") ("10.0.0.1" 8080))"#,
+                    r#"(def-upstream web ROUND-ROBIN (check 1000) (check 2000) ("10.0.0.1" 8080))"#,
+                ] {
+                    let parse_tree = RuleParser::parse(Rule::s_exp, program)
+                        .unwrap()
+                        .next()
+                        .unwrap();
+                    assert!(SpecialForm::try_from(parse_tree).is_err(), "{}", program);
+                }
+            }
+
+            #[test]
+            fn try_from__fails_on_bad_strategies_and_backends() {
+                for program in [
+                    r#"(def-upstream web FASTEST ("10.0.0.1" 8080))"#,
+                    r#"(def-upstream web ROUND-ROBIN "10.0.0.1")"#,
+                    r#"(def-upstream web ROUND-ROBIN ("10.0.0.1"))"#,
+                    r#"(def-upstream web ROUND-ROBIN ("10.0.0.1" 80800))"#,
+                    r#"(def-upstream web ROUND-ROBIN ("10.0.0.1" 8080 -1))"#,
+                    r#"(def-upstream web)"#,
+                ] {
+                    let parse_tree = RuleParser::parse(Rule::s_exp, program)
+                        .unwrap()
+                        .next()
+                        .unwrap();
+                    assert!(SpecialForm::try_from(parse_tree).is_err(), "{}", program);
+                }
+            }
+        }
+
         mod def_rule {
             use super::*;
+            use crate::ast::{Keyword, RuleOutcome};
 
             #[test]
             fn try_from__works_with_expected_parse_trees() {
@@ -657,7 +1478,7 @@ mod tests {
 
                 let ast = SpecialForm::try_from(parse_tree).unwrap();
                 assert!(
-                    matches!(ast, SpecialForm::DefRule { name, body } if name == "simple-rule" && matches!(*body, AstNode::Keyword(_)))
+                    matches!(ast, SpecialForm::DefRule { name, body } if name == "simple-rule" && matches!(*body, AstNode::Keyword(..)))
                 );
             }
 
@@ -691,6 +1512,28 @@ mod tests {
                 let ast = SpecialForm::try_from(parse_tree);
                 assert!(ast.is_err());
             }
+
+            #[test]
+            fn try_from__parses_response_rules() {
+                let parse_tree = RuleParser::parse(
+                    Rule::s_exp,
+                    r#"(def-response-rule strip-banner (REWRITE "^SSH-[^\r]*" ""))"#,
+                )
+                .unwrap()
+                .next()
+                .unwrap();
+
+                let ast = SpecialForm::try_from(parse_tree).unwrap();
+                assert!(
+                    matches!(ast, SpecialForm::DefResponseRule { name, body } if name == "strip-banner" && matches!(*body, AstNode::Keyword(Keyword::Outcome(RuleOutcome::REWRITE { .. }), _)))
+                );
+
+                let parse_tree = RuleParser::parse(Rule::s_exp, "(def-response-rule foo)")
+                    .unwrap()
+                    .next()
+                    .unwrap();
+                assert!(SpecialForm::try_from(parse_tree).is_err());
+            }
         }
     }
 
@@ -795,6 +1638,16 @@ mod tests {
                 assert!(
                     matches!(ast, RuleOutcome::REDIRECT {addr, port: 80} if addr == "127.0.0.1")
                 );
+
+                let parse_tree = RuleParser::parse(Rule::s_exp, r#"(REDIRECT ip"127.0.0.1" 80)"#)
+                    .unwrap()
+                    .next()
+                    .unwrap();
+
+                let ast = RuleOutcome::try_from(parse_tree).unwrap();
+                assert!(
+                    matches!(ast, RuleOutcome::REDIRECT {addr, port: 80} if addr == "127.0.0.1")
+                );
             }
 
             #[test]
@@ -841,7 +1694,7 @@ mod tests {
 
                 let ast = RuleOutcome::try_from(parse_tree).unwrap();
                 assert!(
-                    matches!(ast, RuleOutcome::REWRITE {pattern, replace_with} if pattern == "^bar$" && replace_with == "baz")
+                    matches!(ast, RuleOutcome::REWRITE {pattern, replace_with} if pattern == "^bar$" && replace_with == b"baz")
                 );
             }
 
@@ -857,6 +1710,32 @@ mod tests {
             }
         }
 
+        mod balance {
+            use super::*;
+
+            #[test]
+            fn try_from__works_with_expected_parse_tree() {
+                let parse_tree = RuleParser::parse(Rule::s_exp, "(BALANCE web)")
+                    .unwrap()
+                    .next()
+                    .unwrap();
+
+                let ast = RuleOutcome::try_from(parse_tree).unwrap();
+                assert!(matches!(ast, RuleOutcome::BALANCE { upstream } if upstream == "web"));
+            }
+
+            #[test]
+            fn try_from__fails_on_well_formed_parse_tree_with_invalid_arguments() {
+                for outcome in [r#"(BALANCE "web")"#, "(BALANCE web api)", "(BALANCE)"] {
+                    let parse_tree = RuleParser::parse(Rule::s_exp, outcome)
+                        .unwrap()
+                        .next()
+                        .unwrap();
+                    assert!(RuleOutcome::try_from(parse_tree).is_err(), "{}", outcome);
+                }
+            }
+        }
+
         mod r#continue {
             use super::*;
 
@@ -886,7 +1765,8 @@ mod tests {
 
     mod ast_node {
         use super::*;
-        use crate::ast::{AstNode, Keyword, ProxyMode, RuleOutcome, SpecialForm};
+        use crate::ast::{AstNode, AstParseError, Keyword, ProxyMode, RuleOutcome, SpecialForm};
+        use crate::diagnostic::Span;
 
         #[test]
         fn try_from__works_correctly_on_atoms() {
@@ -895,7 +1775,7 @@ mod tests {
                 .next()
                 .unwrap();
             let ast = AstNode::try_from(parse_tree).unwrap();
-            assert!(matches!(ast, AstNode::Ident(id) if id == "foo"));
+            assert!(matches!(ast, AstNode::Ident(id, _) if id == "foo"));
 
             let parse_tree = RuleParser::parse(Rule::s_exp, r#"if"#)
                 .unwrap()
@@ -909,21 +1789,108 @@ mod tests {
                 .next()
                 .unwrap();
             let ast = AstNode::try_from(parse_tree).unwrap();
-            assert!(matches!(ast, AstNode::Num(69)));
+            assert!(matches!(ast, AstNode::Num(69, _)));
+
+            let parse_tree = RuleParser::parse(Rule::s_exp, "0")
+                .unwrap()
+                .next()
+                .unwrap();
+            let ast = AstNode::try_from(parse_tree).unwrap();
+            assert!(matches!(ast, AstNode::Num(0, _)));
+
+            let parse_tree = RuleParser::parse(Rule::s_exp, "99999999999999999999")
+                .unwrap()
+                .next()
+                .unwrap();
+            let ast = AstNode::try_from(parse_tree);
+            assert!(ast.is_err());
+
+            let parse_tree = RuleParser::parse(Rule::s_exp, "<=")
+                .unwrap()
+                .next()
+                .unwrap();
+            let ast = AstNode::try_from(parse_tree).unwrap();
+            assert!(matches!(ast, AstNode::Ident(id, _) if id == "<="));
 
             let parse_tree = RuleParser::parse(Rule::s_exp, r#""chicken nuggets""#)
                 .unwrap()
                 .next()
                 .unwrap();
             let ast = AstNode::try_from(parse_tree).unwrap();
-            assert!(matches!(ast, AstNode::String(s) if s == "chicken nuggets"));
+            assert!(matches!(ast, AstNode::String(s, _) if s == "chicken nuggets"));
+
+            let parse_tree = RuleParser::parse(Rule::s_exp, r#"ip"10.0.0.1""#)
+                .unwrap()
+                .next()
+                .unwrap();
+            let ast = AstNode::try_from(parse_tree).unwrap();
+            assert!(matches!(ast, AstNode::Ip(s, _) if s == "10.0.0.1"));
+
+            let parse_tree = RuleParser::parse(Rule::s_exp, r#"cidr"10.0.0.0/8""#)
+                .unwrap()
+                .next()
+                .unwrap();
+            let ast = AstNode::try_from(parse_tree).unwrap();
+            assert!(matches!(ast, AstNode::Cidr(s, _) if s == "10.0.0.0/8"));
+
+            let parse_tree = RuleParser::parse(Rule::s_exp, r#"#x"16 03 0a""#)
+                .unwrap()
+                .next()
+                .unwrap();
+            let ast = AstNode::try_from(parse_tree).unwrap();
+            assert!(matches!(ast, AstNode::Bytes(b, _) if b == [0x16, 0x03, 0x0a]));
+
+            let parse_tree = RuleParser::parse(Rule::s_exp, r#"#x"160""#)
+                .unwrap()
+                .next()
+                .unwrap();
+            let ast = AstNode::try_from(parse_tree);
+            assert!(ast.is_err());
+
+            // without the quotes right after it, `ip` is just an identifier
+            let parse_tree = RuleParser::parse(Rule::s_exp, "ip")
+                .unwrap()
+                .next()
+                .unwrap();
+            let ast = AstNode::try_from(parse_tree).unwrap();
+            assert!(matches!(ast, AstNode::Ident(id, _) if id == "ip"));
 
             let parse_tree = RuleParser::parse(Rule::s_exp, "#t")
                 .unwrap()
                 .next()
                 .unwrap();
             let ast = AstNode::try_from(parse_tree).unwrap();
-            assert!(matches!(ast, AstNode::Bool(true)));
+            assert!(matches!(ast, AstNode::Bool(true, _)));
+        }
+
+        #[test]
+        fn try_from__decodes_string_escapes() {
+            let parse = |source: &str| {
+                AstNode::try_from(RuleParser::parse(Rule::s_exp, source).unwrap().next().unwrap())
+            };
+
+            let ast = parse(r#""GET / HTTP/1.1\r\n\"quoted\" \\ \x41""#).unwrap();
+            assert!(matches!(ast, AstNode::String(s, _) if s == "GET / HTTP/1.1\r\n\"quoted\" \\ A"));
+
+            let ast = parse("\"line one\nline two ✓\"").unwrap();
+            assert!(matches!(ast, AstNode::String(s, _) if s == "line one\nline two ✓"));
+
+            // escaped bytes that aren't UTF-8 make a byte string
+            let ast = parse(r#""\x16\x03\xff""#).unwrap();
+            assert!(matches!(ast, AstNode::Bytes(b, _) if b == [0x16, 0x03, 0xff]));
+
+            let ast = parse(r#"ip"10.0.0.\x31""#).unwrap();
+            assert!(matches!(ast, AstNode::Ip(s, _) if s == "10.0.0.1"));
+
+            let AstParseError::ParseError(message, span) = parse(r#""user=(\w+)""#).unwrap_err();
+            assert_eq!(message, "unknown escape `\\w`");
+            assert_eq!(span, Some(Span { start: 7, end: 9 }));
+
+            let AstParseError::ParseError(message, span) = parse(r#""\x4""#).unwrap_err();
+            assert_eq!(message, "`\\x` must be followed by two hex digits");
+            assert_eq!(span, Some(Span { start: 1, end: 4 }));
+
+            assert!(parse(r#""\x+1""#).is_err());
         }
 
         #[test]
@@ -933,14 +1900,14 @@ mod tests {
                 .next()
                 .unwrap();
             let ast = AstNode::try_from(parse_tree).unwrap();
-            assert!(matches!(ast, AstNode::Sexp(_)));
-            if let AstNode::Sexp(v) = ast {
-                assert!(matches!(v[0], AstNode::Num(1)));
-                assert!(matches!(v[1], AstNode::Num(2)));
-                assert!(matches!(v[2], AstNode::Num(3)));
-                assert!(matches!(v[3].clone(), AstNode::Ident(s) if s == "a"));
-                assert!(matches!(v[4].clone(), AstNode::String(s) if s == "b"));
-                assert!(matches!(v[5].clone(), AstNode::Ident(s) if s == "c"));
+            assert!(matches!(ast, AstNode::Sexp(..)));
+            if let AstNode::Sexp(v, _) = ast {
+                assert!(matches!(v[0], AstNode::Num(1, _)));
+                assert!(matches!(v[1], AstNode::Num(2, _)));
+                assert!(matches!(v[2], AstNode::Num(3, _)));
+                assert!(matches!(v[3].clone(), AstNode::Ident(s, _) if s == "a"));
+                assert!(matches!(v[4].clone(), AstNode::String(s, _) if s == "b"));
+                assert!(matches!(v[5].clone(), AstNode::Ident(s, _) if s == "c"));
             }
         }
 
@@ -949,7 +1916,7 @@ mod tests {
             let program = r#"
             (set-mode OPAQUE)
 
-            (def-var bad-ip "192.0.1.2")
+            (def-var bad-ip ip"192.0.1.2")
 
             (def-rule simple-rewrite
                 (if (exact? :metadata-source bad-ip)
@@ -968,67 +1935,99 @@ mod tests {
                 .unwrap();
             let ast = AstNode::try_from(parse_tree).unwrap();
 
-            if let AstNode::Program(stmts) = ast {
+            if let AstNode::Program(stmts, _) = ast {
                 assert_eq!(stmts.len(), 4);
 
-                assert!(matches!(stmts[0].clone(), AstNode::Keyword(a)
+                assert!(matches!(stmts[0].clone(), AstNode::Keyword(a, _)
                         if matches!(a.clone(), Keyword::SpecialForm(b)
                             if matches!(b.clone(), SpecialForm::SetMode {mode}
                                 if matches!(mode, ProxyMode::OPAQUE)))));
 
-                assert!(matches!(stmts[1].clone(), AstNode::Keyword(a)
+                assert!(matches!(stmts[1].clone(), AstNode::Keyword(a, _)
                     if matches!(a.clone(), Keyword::SpecialForm(b)
                         if matches!(b.clone(), SpecialForm::DefVar {name, value}
                             if name == "bad-ip"
-                            && matches!(*value.clone(), AstNode::String(s)
+                            && matches!(*value.clone(), AstNode::Ip(s, _)
                                 if s == "192.0.1.2")))));
 
-                assert!(matches!(stmts[2].clone(), AstNode::Keyword(a)
+                assert!(matches!(stmts[2].clone(), AstNode::Keyword(a, _)
                     if matches!(a.clone(), Keyword::SpecialForm(b)
                         if matches!(b.clone(), SpecialForm::DefRule {name, body}
                             if name == "simple-rewrite"
-                            && matches!(*body.clone(), AstNode::Keyword(a)
+                            && matches!(*body.clone(), AstNode::Keyword(a, _)
                                 if matches!(a.clone(), Keyword::SpecialForm(b)
                                     if matches!(b.clone(), SpecialForm::If {predicate, consequent, alternative}
-                                        if matches!(*predicate.clone(), AstNode::Sexp(v)
-                                            if matches!(v[0].clone(), AstNode::Ident(s)
+                                        if matches!(*predicate.clone(), AstNode::Sexp(v, _)
+                                            if matches!(v[0].clone(), AstNode::Ident(s, _)
                                                 if s == "exact?")
-                                            && matches!(v[1].clone(), AstNode::Ident(s)
+                                            && matches!(v[1].clone(), AstNode::Ident(s, _)
                                                 if s == ":metadata-source")
-                                            && matches!(v[2].clone(), AstNode::Ident(s)
+                                            && matches!(v[2].clone(), AstNode::Ident(s, _)
                                                 if s == "bad-ip"))
-                                        && matches!(*consequent.clone(), AstNode::Keyword(a)
+                                        && matches!(*consequent.clone(), AstNode::Keyword(a, _)
                                             if matches!(a.clone(), Keyword::Outcome(o)
                                                 if matches!(o.clone(), RuleOutcome::REWRITE {pattern, replace_with}
                                                     if pattern == "^bar$"
-                                                    && replace_with == "baz")))
-                                        && matches!(*alternative.clone(), AstNode::Keyword(a)
+                                                    && replace_with == b"baz")))
+                                        && matches!(*alternative.clone(), AstNode::Keyword(a, _)
                                             if matches!(a.clone(), Keyword::Outcome(o)
                                                 if matches!(o.clone(), RuleOutcome::CONTINUE))))))))));
 
-                assert!(matches!(stmts[3].clone(), AstNode::Keyword(a)
+                assert!(matches!(stmts[3].clone(), AstNode::Keyword(a, _)
                         if matches!(a.clone(), Keyword::SpecialForm(b)
                             if matches!(b.clone(), SpecialForm::DefRule {name, body}
                                 if name == "simple-rule"
-                                && matches!(*body.clone(), AstNode::Keyword(a)
+                                && matches!(*body.clone(), AstNode::Keyword(a, _)
                                     if matches!(a.clone(), Keyword::SpecialForm(b)
                                         if matches!(b.clone(), SpecialForm::If {predicate, consequent, alternative}
-                                            if matches!(*predicate.clone(), AstNode::Sexp(v)
-                                                if matches!(v[0].clone(), AstNode::Ident(s)
+                                            if matches!(*predicate.clone(), AstNode::Sexp(v, _)
+                                                if matches!(v[0].clone(), AstNode::Ident(s, _)
                                                     if s == "exact?")
-                                                && matches!(v[1].clone(), AstNode::Ident(s)
+                                                && matches!(v[1].clone(), AstNode::Ident(s, _)
                                                     if s == ":metadata-source")
-                                                && matches!(v[2].clone(), AstNode::Ident(s)
+                                                && matches!(v[2].clone(), AstNode::Ident(s, _)
                                                     if s == "bad-ip")) &&
-                                            matches!(*consequent.clone(), AstNode::Keyword(a)
+                                            matches!(*consequent.clone(), AstNode::Keyword(a, _)
                                                 if matches!(a.clone(), Keyword::Outcome(o)
                                                     if matches!(o.clone(), RuleOutcome::DROP))
-                                                && matches!(*alternative.clone(), AstNode::Keyword(a)
+                                                && matches!(*alternative.clone(), AstNode::Keyword(a, _)
                                                     if matches!(a.clone(), Keyword::Outcome(o)
                                                         if matches!(o.clone(), RuleOutcome::REDIRECT{addr, port}
                                                             if addr == "127.0.0.1" && port == 80)))))))))));
             } else {
-                assert!(false);
+                panic!("expected an `AstNode::Program`");
+            }
+        }
+
+        #[test]
+        fn try_from__keeps_comments() {
+            let program = r#";; OPAQUE or TRANSPARENT
+(set-mode OPAQUE) ; trailing
+#| a block comment
+   #| which nests |# |#
+(def-rule r #| inline |# (REDIRECT "127.0.0.1;#|" 80))"#;
+
+            let parse_tree = RuleParser::parse(Rule::program, program)
+                .unwrap()
+                .next()
+                .unwrap();
+            let AstNode::Program(stmts, comments) = AstNode::try_from(parse_tree).unwrap() else {
+                panic!("expected an `AstNode::Program`");
+            };
+            assert_eq!(stmts.len(), 2);
+            let texts: Vec<_> = comments.iter().map(|c| c.value.as_str()).collect();
+            assert_eq!(
+                texts,
+                [
+                    ";; OPAQUE or TRANSPARENT",
+                    "; trailing",
+                    "#| a block comment\n   #| which nests |# |#",
+                    "#| inline |#",
+                ]
+            );
+            for comment in &comments {
+                let span = comment.span.unwrap();
+                assert_eq!(&program[span.start..span.end], comment.value);
             }
         }
     }
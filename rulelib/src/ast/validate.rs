@@ -0,0 +1,1695 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use regex::bytes::Regex;
+
+use crate::ast::*;
+use crate::diagnostic::Spanned;
+use crate::vm::Evaluation;
+
+/// The types of values a rule file can talk about
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ValueType {
+    Ip,
+    Subnet,
+    Port,
+    Int,
+    Bool,
+    Data,
+    IpSet,
+    IntSet,
+}
+
+impl ValueType {
+    /// Ports are integers too, so the two can be compared
+    fn is_numeric(self) -> bool {
+        matches!(self, ValueType::Port | ValueType::Int)
+    }
+
+    fn is_compatible_with(self, other: ValueType) -> bool {
+        self == other || (self.is_numeric() && other.is_numeric())
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueType::Ip => write!(f, "ip"),
+            ValueType::Subnet => write!(f, "subnet"),
+            ValueType::Port => write!(f, "port"),
+            ValueType::Int => write!(f, "int"),
+            ValueType::Bool => write!(f, "bool"),
+            ValueType::Data => write!(f, "data"),
+            ValueType::IpSet => write!(f, "ip set"),
+            ValueType::IntSet => write!(f, "int set"),
+        }
+    }
+}
+
+/// Everything `AstNode::validate` can object to.
+/// Each of these would otherwise be a panic (or a silent miscompile) in codegen.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    /// The program never calls `set-mode`
+    MissingSetMode,
+    /// `set-mode` is somewhere other than the first statement
+    SetModeNotFirst,
+    /// `set-mode` appears more than once
+    DuplicateSetMode,
+    /// `set-eval` appears more than once
+    DuplicateSetEval,
+    /// A top-level statement that isn't a definition
+    UnexpectedStatement,
+    /// A name is used before (or without) being defined with `def-var`, or `def-upstream` for a `BALANCE`
+    UndefinedName(String),
+    /// A variable is defined twice
+    Redefinition(String),
+    /// A rule name is used twice
+    DuplicateRule(String),
+    /// A rule body is neither an `if` nor an outcome
+    InvalidRuleBody(String),
+    /// A predicate that isn't a predicate call, a variable or a boolean
+    InvalidPredicate,
+    /// A predicate call to something we don't know about
+    UnknownPredicate(String),
+    WrongArity {
+        form: String,
+        expected: usize,
+        found: usize,
+    },
+    TypeMismatch {
+        context: String,
+        expected: ValueType,
+        found: ValueType,
+    },
+    InvalidIp(String),
+    /// A subnet that isn't an IP address followed by a prefix length that fits it (at most 32 for IPv4, or 128 for IPv6)
+    InvalidSubnet(String),
+    /// A plain string, which is a byte string, used where an address or subnet is expected
+    UntypedAddress(String),
+    InvalidPort(i64),
+    /// A `matches?` whose pattern isn't a string literal
+    PatternNotLiteral,
+    InvalidRegex { pattern: String, reason: String },
+    /// A `REWRITE` replacement refers to a capture group its pattern doesn't have
+    UnknownCaptureGroup(String),
+    /// A `def-set` with no members, whose type we can't know
+    EmptySet(String),
+    /// A `def-set` member that is neither an IP address nor a number
+    InvalidSetMember(String),
+    /// A `def-upstream` with no backends
+    EmptyUpstream(String),
+    /// A backend of a `def-upstream` with a weight of 0
+    ZeroWeight(String),
+    /// A `def-upstream` check with an interval of 0
+    ZeroInterval(String),
+    /// A `def-var` whose value isn't an atom
+    InvalidValue(String),
+    /// Something the language should eventually support, but codegen can't handle yet
+    Unsupported(String),
+    /// Something that can't be done in the program's proxy mode
+    NotAllowedInMode { what: String, mode: ProxyMode },
+    /// An outcome that makes no sense for data coming back from the upstream
+    NotAllowedInResponse(String),
+    /// The program has no rules, so every packet would fall off the end
+    NoRules,
+    /// The last rule can `CONTINUE` or `REWRITE`, so some packets would fall off the end
+    FallsThrough(String),
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::MissingSetMode => write!(f, "program must begin with `set-mode`"),
+            ValidationError::SetModeNotFirst => {
+                write!(f, "`set-mode` must be the first statement")
+            }
+            ValidationError::DuplicateSetMode => write!(f, "`set-mode` may only appear once"),
+            ValidationError::DuplicateSetEval => write!(f, "`set-eval` may only appear once"),
+            ValidationError::UnexpectedStatement => write!(
+                f,
+                "expected one of `set-mode`, `set-eval`, `def-var`, `def-set`, `def-upstream`, `def-rule` or `def-response-rule` at the top level"
+            ),
+            ValidationError::UndefinedName(name) => write!(f, "`{}` is not defined", name),
+            ValidationError::Redefinition(name) => write!(f, "`{}` is already defined", name),
+            ValidationError::DuplicateRule(name) => {
+                write!(f, "a rule named `{}` already exists", name)
+            }
+            ValidationError::InvalidRuleBody(name) => write!(
+                f,
+                "the body of rule `{}` must be an `if` or an outcome",
+                name
+            ),
+            ValidationError::InvalidPredicate => write!(
+                f,
+                "a predicate must be a predicate call, a variable or a boolean"
+            ),
+            ValidationError::UnknownPredicate(name) => {
+                write!(f, "unknown predicate `{}`", name)
+            }
+            ValidationError::WrongArity {
+                form,
+                expected,
+                found,
+            } => write!(
+                f,
+                "wrong arity for {}; expected {}, received {}",
+                form, expected, found
+            ),
+            ValidationError::TypeMismatch {
+                context,
+                expected,
+                found,
+            } => write!(
+                f,
+                "type mismatch in {}; expected {}, found {}",
+                context, expected, found
+            ),
+            ValidationError::InvalidIp(s) => write!(f, "`{}` is not a valid IP address", s),
+            ValidationError::InvalidSubnet(s) => write!(f, "`{}` is not a valid subnet", s),
+            ValidationError::UntypedAddress(s) => write!(
+                f,
+                "`\"{s}\"` is a byte string; write `{}\"{s}\"` to use it as an address",
+                if s.contains('/') { "cidr" } else { "ip" },
+            ),
+            ValidationError::InvalidPort(n) => write!(f, "`{}` is not a valid port", n),
+            ValidationError::PatternNotLiteral => {
+                write!(f, "the pattern given to `matches?` must be a string literal")
+            }
+            ValidationError::InvalidRegex { pattern, reason } => {
+                write!(f, "`{}` is not a valid regex: {}", pattern, reason)
+            }
+            ValidationError::UnknownCaptureGroup(group) => write!(
+                f,
+                "the replacement refers to capture group `{}`, which the pattern doesn't have",
+                group
+            ),
+            ValidationError::EmptySet(name) => write!(f, "the set `{}` is empty", name),
+            ValidationError::InvalidSetMember(name) => write!(
+                f,
+                "the members of `{}` must be IP addresses or numbers",
+                name
+            ),
+            ValidationError::EmptyUpstream(name) => {
+                write!(f, "the upstream `{}` has no backends", name)
+            }
+            ValidationError::ZeroWeight(name) => write!(
+                f,
+                "the backends of `{}` must have a weight of at least 1",
+                name
+            ),
+            ValidationError::ZeroInterval(name) => write!(
+                f,
+                "the check of `{}` must have an interval of at least 1 ms",
+                name
+            ),
+            ValidationError::InvalidValue(name) => {
+                write!(f, "the value of `{}` must be an atom", name)
+            }
+            ValidationError::Unsupported(what) => write!(f, "{} are not supported yet", what),
+            ValidationError::NotAllowedInMode { what, mode } => {
+                write!(f, "{} is not allowed in {:?} mode", what, mode)
+            }
+            ValidationError::NotAllowedInResponse(what) => write!(
+                f,
+                "{} is not allowed in a response rule, since the upstream is already chosen",
+                what
+            ),
+            ValidationError::NoRules => write!(f, "program must define at least one rule"),
+            ValidationError::FallsThrough(name) => write!(
+                f,
+                "rule `{}` is the last rule, so it must end in `DROP`, `REJECT`, `REDIRECT` or `BALANCE`",
+                name
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ValidationEnv {
+    mode: Option<ProxyMode>,
+    vars: HashMap<String, ValueType>,
+    /// The `def-upstream`s, which share their names with the variables
+    upstreams: HashSet<String>,
+    rules: HashSet<String>,
+    /// Whether we're in a `def-response-rule`
+    in_response: bool,
+    errors: Vec<Spanned<ValidationError>>,
+}
+
+impl ValidationEnv {
+    /// Reports an error about the program as a whole
+    fn error(&mut self, error: ValidationError) {
+        self.errors.push(Spanned {
+            value: error,
+            span: None,
+        });
+    }
+
+    fn error_at(&mut self, error: ValidationError, span: Span) {
+        self.errors.push(Spanned {
+            value: error,
+            span: Some(span),
+        });
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        self.vars.contains_key(name) || self.upstreams.contains(name)
+    }
+
+    fn require_transparent(&mut self, what: &str, span: Span) {
+        if let Some(mode @ ProxyMode::OPAQUE) = self.mode {
+            self.error_at(
+                ValidationError::NotAllowedInMode {
+                    what: what.to_string(),
+                    mode,
+                },
+                span,
+            );
+        }
+    }
+}
+
+impl AstNode {
+    /// Checks that an `AstNode::Program` is well-formed enough for `AstNode::codegen`.
+    /// Every problem found is reported, rather than stopping at the first.
+    pub fn validate(&self) -> Result<(), Vec<Spanned<ValidationError>>> {
+        let statements = match self {
+            AstNode::Program(statements, _) => statements,
+            _ => unreachable!("AstNode::validate should only be called on Programs!"),
+        };
+
+        let mut env = ValidationEnv::default();
+        match statements.first() {
+            Some(AstNode::Keyword(Keyword::SpecialForm(SpecialForm::SetMode { mode }), _)) => {
+                env.mode = Some(*mode);
+            }
+            _ => match statements.iter().find(|s| is_set_mode(s)) {
+                Some(set_mode) => env.error_at(ValidationError::SetModeNotFirst, set_mode.span()),
+                None => env.error(ValidationError::MissingSetMode),
+            },
+        }
+
+        let mut evaluation = None;
+        let mut last_rule = None;
+        for (i, statement) in statements.iter().enumerate() {
+            let span = statement.span();
+            match statement {
+                AstNode::Keyword(Keyword::SpecialForm(sf), _) => match sf {
+                    SpecialForm::SetMode { .. } => {
+                        if i > 0 && matches!(statements.first(), Some(s) if is_set_mode(s)) {
+                            env.error_at(ValidationError::DuplicateSetMode, span);
+                        }
+                    }
+                    SpecialForm::SetEval { evaluation: eval } => {
+                        if evaluation.replace(*eval).is_some() {
+                            env.error_at(ValidationError::DuplicateSetEval, span);
+                        }
+                        // there's no content when the client connects, so content rules couldn't run
+                        if let (Evaluation::OnConnect, Some(mode @ ProxyMode::TRANSPARENT)) =
+                            (eval, env.mode)
+                        {
+                            env.error_at(
+                                ValidationError::NotAllowedInMode {
+                                    what: "`(set-eval ON-CONNECT)`".to_string(),
+                                    mode,
+                                },
+                                span,
+                            );
+                        }
+                    }
+                    SpecialForm::DefVar { name, value } => {
+                        validate_var(&mut env, name, value, span)
+                    }
+                    SpecialForm::DefSet { name, members } => {
+                        validate_set(&mut env, name, members, span)
+                    }
+                    SpecialForm::DefUpstream {
+                        name,
+                        backends,
+                        check,
+                        ..
+                    } => validate_upstream(&mut env, name, backends, check.as_ref(), span),
+                    SpecialForm::DefRule { name, body } => {
+                        if !env.rules.insert(name.clone()) {
+                            env.error_at(ValidationError::DuplicateRule(name.clone()), span);
+                        }
+                        validate_rule(&mut env, name, body);
+                        last_rule = Some((name, span, can_continue(body)));
+                    }
+                    SpecialForm::DefResponseRule { name, body } => {
+                        // there's no content to look at in OPAQUE mode
+                        env.require_transparent("`def-response-rule`", span);
+                        if !env.rules.insert(name.clone()) {
+                            env.error_at(ValidationError::DuplicateRule(name.clone()), span);
+                        }
+                        env.in_response = true;
+                        validate_rule(&mut env, name, body);
+                        env.in_response = false;
+                    }
+                    SpecialForm::If { .. } => {
+                        env.error_at(ValidationError::UnexpectedStatement, span)
+                    }
+                },
+                _ => env.error_at(ValidationError::UnexpectedStatement, span),
+            }
+        }
+
+        match last_rule {
+            None => env.error(ValidationError::NoRules),
+            Some((name, span, true)) => {
+                env.error_at(ValidationError::FallsThrough(name.clone()), span)
+            }
+            Some((_, _, false)) => {}
+        }
+
+        if env.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(env.errors)
+        }
+    }
+}
+
+fn is_set_mode(statement: &AstNode) -> bool {
+    matches!(
+        statement,
+        AstNode::Keyword(Keyword::SpecialForm(SpecialForm::SetMode { .. }), _)
+    )
+}
+
+/// Whether evaluating a rule body can go on to the next rule
+fn can_continue(body: &AstNode) -> bool {
+    match body {
+        // a rewrite goes on to the next rule, too
+        AstNode::Keyword(
+            Keyword::Outcome(RuleOutcome::CONTINUE | RuleOutcome::REWRITE { .. }),
+            _,
+        ) => true,
+        AstNode::Keyword(
+            Keyword::SpecialForm(SpecialForm::If {
+                consequent,
+                alternative,
+                ..
+            }),
+            _,
+        ) => can_continue(consequent) || can_continue(alternative),
+        _ => false,
+    }
+}
+
+fn validate_var(env: &mut ValidationEnv, name: &str, value: &AstNode, span: Span) {
+    if env.is_defined(name) {
+        env.error_at(ValidationError::Redefinition(name.to_string()), span);
+    }
+
+    let value_type = match value {
+        AstNode::Num(..) => Some(ValueType::Int),
+        AstNode::Bool(..) => Some(ValueType::Bool),
+        // NOTE: we only allow aliasing other variables, not packet fields
+        AstNode::Ident(ident, span) => match env.vars.get(ident) {
+            Some(value_type) => Some(*value_type),
+            None => {
+                env.error_at(ValidationError::UndefinedName(ident.clone()), *span);
+                None
+            }
+        },
+        AstNode::String(..) | AstNode::Ip(..) | AstNode::Cidr(..) | AstNode::Bytes(..) => {
+            validate_literal(env, value)
+        }
+        _ => {
+            env.error_at(ValidationError::InvalidValue(name.to_string()), value.span());
+            None
+        }
+    };
+
+    if let Some(value_type) = value_type {
+        env.vars.insert(name.to_string(), value_type);
+    }
+}
+
+/// Sets share a namespace with variables, and hold either IP addresses or numbers, but not both
+fn validate_set(env: &mut ValidationEnv, name: &str, members: &[AstNode], span: Span) {
+    if env.is_defined(name) {
+        env.error_at(ValidationError::Redefinition(name.to_string()), span);
+    }
+
+    let mut set_type = None;
+    for member in members {
+        let member_type = match member {
+            AstNode::Num(..) => Some(ValueType::Int),
+            AstNode::String(..) if untyped_address(env, ValueType::Ip, member) => continue,
+            AstNode::String(..) | AstNode::Ip(..) | AstNode::Cidr(..) | AstNode::Bytes(..) => {
+                validate_literal(env, member)
+            }
+            AstNode::Ident(ident, span) => match env.vars.get(ident) {
+                Some(value_type) => Some(*value_type),
+                None => {
+                    env.error_at(ValidationError::UndefinedName(ident.clone()), *span);
+                    None
+                }
+            },
+            _ => {
+                env.error_at(ValidationError::InvalidSetMember(name.to_string()), member.span());
+                None
+            }
+        };
+        let member_type = match member_type {
+            Some(ValueType::Ip) => ValueType::IpSet,
+            Some(t) if t.is_numeric() => ValueType::IntSet,
+            Some(_) => {
+                env.error_at(ValidationError::InvalidSetMember(name.to_string()), member.span());
+                continue;
+            }
+            None => continue,
+        };
+        match set_type {
+            Some(expected) if expected != member_type => env.error_at(
+                ValidationError::TypeMismatch {
+                    context: format!("def-set `{}`", name),
+                    expected,
+                    found: member_type,
+                },
+                member.span(),
+            ),
+            Some(_) => {}
+            None => set_type = Some(member_type),
+        }
+    }
+
+    if members.is_empty() {
+        env.error_at(ValidationError::EmptySet(name.to_string()), span);
+    }
+    if let Some(set_type) = set_type {
+        env.vars.insert(name.to_string(), set_type);
+    }
+}
+
+fn validate_upstream(
+    env: &mut ValidationEnv,
+    name: &str,
+    backends: &[Backend],
+    check: Option<&HealthCheck>,
+    span: Span,
+) {
+    if env.is_defined(name) {
+        env.error_at(ValidationError::Redefinition(name.to_string()), span);
+    }
+    if backends.is_empty() {
+        env.error_at(ValidationError::EmptyUpstream(name.to_string()), span);
+    }
+    for backend in backends {
+        if backend.addr.parse::<IpAddr>().is_err() {
+            env.error_at(ValidationError::InvalidIp(backend.addr.clone()), backend.span);
+        }
+        if backend.weight == 0 {
+            env.error_at(ValidationError::ZeroWeight(name.to_string()), backend.span);
+        }
+    }
+    if let Some(check) = check.filter(|check| check.interval_ms == 0) {
+        env.error_at(ValidationError::ZeroInterval(name.to_string()), check.span);
+    }
+    env.upstreams.insert(name.to_string());
+}
+
+fn validate_rule(env: &mut ValidationEnv, name: &str, body: &AstNode) {
+    match body {
+        AstNode::Keyword(
+            Keyword::SpecialForm(SpecialForm::If {
+                predicate,
+                consequent,
+                alternative,
+            }),
+            _,
+        ) => {
+            validate_pred(env, predicate, 0);
+            validate_rule(env, name, consequent);
+            validate_rule(env, name, alternative);
+        }
+        AstNode::Keyword(Keyword::Outcome(outcome), span) => validate_outcome(env, outcome, *span),
+        _ => env.error_at(ValidationError::InvalidRuleBody(name.to_string()), body.span()),
+    }
+}
+
+/// Predicates that test `:packet-content` against a needle or a pattern
+pub(crate) const CONTENT_PREDICATES: [&str; 7] = [
+    "contains?",
+    "prefix?",
+    "suffix?",
+    "contains-ci?",
+    "prefix-ci?",
+    "suffix-ci?",
+    "matches?",
+];
+
+/// How deeply `and`, `or` and `not` may nest; each level takes up a VM register
+const MAX_PREDICATE_DEPTH: usize = 12;
+
+fn validate_pred(env: &mut ValidationEnv, predicate: &AstNode, depth: usize) {
+    if depth > MAX_PREDICATE_DEPTH {
+        env.error_at(
+            ValidationError::Unsupported(format!(
+                "predicates nested more than {} deep",
+                MAX_PREDICATE_DEPTH
+            )),
+            predicate.span(),
+        );
+        return;
+    }
+
+    match predicate {
+        AstNode::Bool(..) => {}
+        AstNode::Ident(ident, span) => {
+            if let Some(found) = validate_operand(env, predicate) {
+                if found != ValueType::Bool {
+                    env.error_at(
+                        ValidationError::TypeMismatch {
+                            context: format!("predicate `{}`", ident),
+                            expected: ValueType::Bool,
+                            found,
+                        },
+                        *span,
+                    );
+                }
+            }
+        }
+        AstNode::Sexp(expr, span) => match expr.first() {
+            Some(AstNode::Ident(s, _)) if s == "exact?" => validate_exact(env, &expr[1..], *span),
+            Some(AstNode::Ident(s, _)) if s == "in-subnet?" => {
+                validate_in_subnet(env, &expr[1..], *span)
+            }
+            Some(AstNode::Ident(s, _)) if matches!(s.as_str(), "<" | "<=" | ">" | ">=") => {
+                validate_comparison(env, s, &expr[1..], *span)
+            }
+            Some(AstNode::Ident(s, _)) if s == "port-in-range?" => {
+                validate_port_in_range(env, &expr[1..], *span)
+            }
+            Some(AstNode::Ident(s, _)) if CONTENT_PREDICATES.contains(&s.as_str()) => {
+                validate_content_match(env, s, &expr[1..], *span)
+            }
+            Some(AstNode::Ident(s, _)) if s == "member?" => validate_member(env, &expr[1..], *span),
+            Some(AstNode::Ident(s, _)) if s == "and" || s == "or" => {
+                for operand in &expr[1..] {
+                    validate_pred(env, operand, depth + 1);
+                }
+            }
+            Some(AstNode::Ident(s, _)) if s == "not" => {
+                if expr.len() != 2 {
+                    env.error_at(
+                        ValidationError::WrongArity {
+                            form: "not".to_string(),
+                            expected: 1,
+                            found: expr.len() - 1,
+                        },
+                        *span,
+                    );
+                    return;
+                }
+                validate_pred(env, &expr[1], depth + 1);
+            }
+            Some(AstNode::Ident(s, span)) => {
+                env.error_at(ValidationError::UnknownPredicate(s.clone()), *span)
+            }
+            _ => env.error_at(ValidationError::InvalidPredicate, *span),
+        },
+        _ => env.error_at(ValidationError::InvalidPredicate, predicate.span()),
+    }
+}
+
+fn validate_exact(env: &mut ValidationEnv, args: &[AstNode], span: Span) {
+    if args.len() != 2 {
+        env.error_at(
+            ValidationError::WrongArity {
+                form: "exact?".to_string(),
+                expected: 2,
+                found: args.len(),
+            },
+            span,
+        );
+        return;
+    }
+
+    let lhs = validate_operand(env, &args[0]);
+    let rhs = validate_operand(env, &args[1]);
+    if let (Some(expected), Some(found)) = (lhs, rhs) {
+        if !expected.is_compatible_with(found)
+            && !untyped_address(env, expected, &args[1])
+            && !untyped_address(env, found, &args[0])
+        {
+            env.error_at(
+                ValidationError::TypeMismatch {
+                    context: "exact?".to_string(),
+                    expected,
+                    found,
+                },
+                span,
+            );
+        }
+    }
+}
+
+fn validate_comparison(env: &mut ValidationEnv, op: &str, args: &[AstNode], span: Span) {
+    if args.len() != 2 {
+        env.error_at(
+            ValidationError::WrongArity {
+                form: op.to_string(),
+                expected: 2,
+                found: args.len(),
+            },
+            span,
+        );
+        return;
+    }
+
+    for arg in args {
+        validate_numeric(env, op, arg);
+    }
+}
+
+fn validate_port_in_range(env: &mut ValidationEnv, args: &[AstNode], span: Span) {
+    if args.len() != 3 {
+        env.error_at(
+            ValidationError::WrongArity {
+                form: "port-in-range?".to_string(),
+                expected: 3,
+                found: args.len(),
+            },
+            span,
+        );
+        return;
+    }
+
+    for arg in args {
+        validate_numeric(env, "port-in-range?", arg);
+    }
+    for bound in &args[1..] {
+        if let AstNode::Num(n, span) = bound {
+            if u16::try_from(*n).is_err() {
+                env.error_at(ValidationError::InvalidPort(*n), *span);
+            }
+        }
+    }
+}
+
+/// Validates an argument that must be a port or an integer
+fn validate_numeric(env: &mut ValidationEnv, context: &str, arg: &AstNode) {
+    match validate_operand(env, arg) {
+        Some(found) if !found.is_numeric() => env.error_at(
+            ValidationError::TypeMismatch {
+                context: context.to_string(),
+                expected: ValueType::Int,
+                found,
+            },
+            arg.span(),
+        ),
+        _ => {}
+    }
+}
+
+fn validate_content_match(env: &mut ValidationEnv, form: &str, args: &[AstNode], span: Span) {
+    if args.len() != 2 {
+        env.error_at(
+            ValidationError::WrongArity {
+                form: form.to_string(),
+                expected: 2,
+                found: args.len(),
+            },
+            span,
+        );
+        return;
+    }
+
+    validate_data(env, form, &args[0]);
+    match &args[1] {
+        AstNode::String(pattern, span) if form == "matches?" => {
+            if let Err(e) = Regex::new(pattern) {
+                env.error_at(
+                    ValidationError::InvalidRegex {
+                        pattern: pattern.clone(),
+                        reason: e.to_string(),
+                    },
+                    *span,
+                );
+            }
+        }
+        AstNode::Bytes(pattern, span) if form == "matches?" => env.error_at(
+            ValidationError::InvalidRegex {
+                pattern: String::from_utf8_lossy(pattern).into_owned(),
+                reason: "patterns must be valid UTF-8; write `\\\\xNN` to match a raw byte"
+                    .to_string(),
+            },
+            *span,
+        ),
+        pattern if form == "matches?" => {
+            env.error_at(ValidationError::PatternNotLiteral, pattern.span())
+        }
+        needle => validate_data(env, form, needle),
+    }
+}
+
+/// Validates an argument that must be data
+fn validate_data(env: &mut ValidationEnv, context: &str, arg: &AstNode) {
+    match validate_operand(env, arg) {
+        Some(found) if found != ValueType::Data => env.error_at(
+            ValidationError::TypeMismatch {
+                context: context.to_string(),
+                expected: ValueType::Data,
+                found,
+            },
+            arg.span(),
+        ),
+        _ => {}
+    }
+}
+
+fn validate_member(env: &mut ValidationEnv, args: &[AstNode], span: Span) {
+    if args.len() != 2 {
+        env.error_at(
+            ValidationError::WrongArity {
+                form: "member?".to_string(),
+                expected: 2,
+                found: args.len(),
+            },
+            span,
+        );
+        return;
+    }
+
+    let value = validate_operand(env, &args[0]);
+    let set = validate_operand(env, &args[1]);
+    let expected = match set {
+        Some(ValueType::IpSet) => ValueType::Ip,
+        Some(ValueType::IntSet) => ValueType::Int,
+        Some(found) => {
+            env.error_at(
+                ValidationError::TypeMismatch {
+                    context: "member?".to_string(),
+                    expected: ValueType::IpSet,
+                    found,
+                },
+                args[1].span(),
+            );
+            return;
+        }
+        None => return,
+    };
+    if let Some(found) = value {
+        if !expected.is_compatible_with(found) && !untyped_address(env, expected, &args[0]) {
+            env.error_at(
+                ValidationError::TypeMismatch {
+                    context: "member?".to_string(),
+                    expected,
+                    found,
+                },
+                args[0].span(),
+            );
+        }
+    }
+}
+
+fn validate_in_subnet(env: &mut ValidationEnv, args: &[AstNode], span: Span) {
+    if args.len() != 2 {
+        env.error_at(
+            ValidationError::WrongArity {
+                form: "in-subnet?".to_string(),
+                expected: 2,
+                found: args.len(),
+            },
+            span,
+        );
+        return;
+    }
+
+    let expected = [ValueType::Ip, ValueType::Subnet];
+    for (arg, expected) in args.iter().zip(expected) {
+        match validate_operand(env, arg) {
+            Some(found) if found != expected && !untyped_address(env, expected, arg) => env.error_at(
+                ValidationError::TypeMismatch {
+                    context: "in-subnet?".to_string(),
+                    expected,
+                    found,
+                },
+                arg.span(),
+            ),
+            _ => {}
+        }
+    }
+}
+
+/// Validates an argument to a predicate, returning its type if it has one
+fn validate_operand(env: &mut ValidationEnv, node: &AstNode) -> Option<ValueType> {
+    match node {
+        AstNode::Bool(..) => Some(ValueType::Bool),
+        AstNode::String(..) | AstNode::Ip(..) | AstNode::Cidr(..) | AstNode::Bytes(..) => {
+            validate_literal(env, node)
+        }
+        AstNode::Num(..) => Some(ValueType::Int),
+        AstNode::Ident(s, span) => match s.as_str() {
+            ":packet-source-ip" => Some(ValueType::Ip),
+            ":packet-source-port" => Some(ValueType::Port),
+            ":packet-dest-ip" => Some(ValueType::Ip),
+            ":packet-dest-port" => Some(ValueType::Port),
+            ":listener" => Some(ValueType::Data),
+            ":packet-content" => {
+                env.require_transparent("matching on `:packet-content`", *span);
+                Some(ValueType::Data)
+            }
+            ":packet-content-length" => {
+                env.require_transparent("matching on `:packet-content-length`", *span);
+                Some(ValueType::Int)
+            }
+            _ => match env.vars.get(s) {
+                Some(value_type) => Some(*value_type),
+                None => {
+                    env.error_at(ValidationError::UndefinedName(s.clone()), *span);
+                    None
+                }
+            },
+        },
+        _ => {
+            env.error_at(ValidationError::InvalidPredicate, node.span());
+            None
+        }
+    }
+}
+
+/// NOTE: plain and hex strings are data; only `ip"..."` and `cidr"..."` are addresses
+fn validate_literal(env: &mut ValidationEnv, node: &AstNode) -> Option<ValueType> {
+    match node {
+        AstNode::String(..) | AstNode::Bytes(..) => Some(ValueType::Data),
+        AstNode::Ip(s, span) => {
+            if s.parse::<IpAddr>().is_err() {
+                env.error_at(ValidationError::InvalidIp(s.clone()), *span);
+            }
+            Some(ValueType::Ip)
+        }
+        AstNode::Cidr(s, span) => {
+            if s.parse::<IpNet>().is_err() {
+                env.error_at(ValidationError::InvalidSubnet(s.clone()), *span);
+            }
+            Some(ValueType::Subnet)
+        }
+        _ => unreachable!("only called on string literals"),
+    }
+}
+
+/// Reports a plain string that looks like an address, used where an address is expected, since
+/// the fix is almost certainly to add an `ip` or `cidr` prefix rather than to change the types
+fn untyped_address(env: &mut ValidationEnv, expected: ValueType, node: &AstNode) -> bool {
+    match (expected, node) {
+        (ValueType::Ip | ValueType::Subnet, AstNode::String(s, span))
+            if s.parse::<IpAddr>().is_ok() || s.parse::<IpNet>().is_ok() =>
+        {
+            env.error_at(ValidationError::UntypedAddress(s.clone()), *span);
+            true
+        }
+        _ => false,
+    }
+}
+
+fn validate_outcome(env: &mut ValidationEnv, outcome: &RuleOutcome, span: Span) {
+    match outcome {
+        RuleOutcome::REDIRECT { addr, .. } => {
+            if env.in_response {
+                env.error_at(ValidationError::NotAllowedInResponse("`REDIRECT`".to_string()), span);
+            }
+            if addr.parse::<IpAddr>().is_err() {
+                env.error_at(ValidationError::InvalidIp(addr.clone()), span);
+            }
+        }
+        RuleOutcome::REWRITE {
+            pattern,
+            replace_with,
+        } => {
+            env.require_transparent("`REWRITE`", span);
+            match Regex::new(pattern) {
+                Ok(regex) => {
+                    // capture group names are ASCII, so nothing is lost here
+                    let replace_with = String::from_utf8_lossy(replace_with);
+                    for group in capture_references(&replace_with) {
+                        let exists = match group.parse::<usize>() {
+                            Ok(i) => i < regex.captures_len(),
+                            Err(_) => regex.capture_names().any(|name| name == Some(group)),
+                        };
+                        if !exists {
+                            env.error_at(
+                                ValidationError::UnknownCaptureGroup(group.to_string()),
+                                span,
+                            );
+                        }
+                    }
+                }
+                Err(e) => env.error_at(
+                    ValidationError::InvalidRegex {
+                        pattern: pattern.clone(),
+                        reason: e.to_string(),
+                    },
+                    span,
+                ),
+            }
+        }
+        RuleOutcome::BALANCE { upstream } => {
+            if env.in_response {
+                env.error_at(ValidationError::NotAllowedInResponse("`BALANCE`".to_string()), span);
+            }
+            if !env.upstreams.contains(upstream) {
+                env.error_at(ValidationError::UndefinedName(upstream.clone()), span);
+            }
+        }
+        RuleOutcome::DROP | RuleOutcome::REJECT | RuleOutcome::CONTINUE => {}
+    }
+}
+
+/// The capture groups a `REWRITE` replacement refers to, following the rules of `Regex::replace_all`:
+/// `$name` takes the longest run of `[_0-9a-zA-Z]`, `${name}` is delimited, and `$$` is a literal `$`
+fn capture_references(replacement: &str) -> Vec<&str> {
+    let mut groups = vec![];
+    let mut rest = replacement;
+    while let Some(i) = rest.find('$') {
+        rest = &rest[i + 1..];
+        if let Some(braced) = rest.strip_prefix('{') {
+            if let Some(end) = braced.find('}') {
+                groups.push(&braced[..end]);
+                rest = &braced[end + 1..];
+            }
+            continue;
+        }
+        let end = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        if end > 0 {
+            groups.push(&rest[..end]);
+        } else if rest.starts_with('$') {
+            // an escaped `$`
+            rest = &rest[1..];
+        }
+        rest = &rest[end..];
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use pest::Parser;
+
+    use crate::parser::{Rule, RuleParser};
+
+    use super::*;
+
+    fn validate(program: &str) -> Result<(), Vec<ValidationError>> {
+        let parse_tree = RuleParser::parse(Rule::program, program)
+            .unwrap()
+            .next()
+            .unwrap();
+        AstNode::try_from(parse_tree)
+            .unwrap()
+            .validate()
+            .map_err(|errors| errors.into_iter().map(|e| e.value).collect())
+    }
+
+    #[test]
+    fn accepts_valid_program() {
+        let program = r#"
+            (set-mode TRANSPARENT)
+
+            (def-var bad-ip ip"192.0.1.2")
+
+            (def-rule simple-rewrite
+                (if (exact? :packet-source-ip bad-ip)
+                    (REWRITE "^bar$" "baz")
+                    CONTINUE))
+
+            (def-rule simple-rule
+                (if (exact? :packet-source-ip bad-ip)
+                    DROP
+                    (REDIRECT "127.0.0.1" 80)))
+        "#;
+        assert_eq!(validate(program), Ok(()));
+    }
+
+    #[test]
+    fn reports_every_error() {
+        let program = r#"
+            (def-var port (exact? 1 2))
+
+            (def-rule bad
+                (if (exact? :packet-source-ip good-ip)
+                    (REDIRECT "not-an-ip" 80)
+                    CONTINUE))
+        "#;
+        assert_eq!(
+            validate(program),
+            Err(vec![
+                ValidationError::MissingSetMode,
+                ValidationError::InvalidValue("port".to_string()),
+                ValidationError::UndefinedName("good-ip".to_string()),
+                ValidationError::InvalidIp("not-an-ip".to_string()),
+                ValidationError::FallsThrough("bad".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_misplaced_set_mode() {
+        let program = r#"
+            (def-rule allow (REDIRECT "127.0.0.1" 80))
+            (set-mode OPAQUE)
+        "#;
+        assert_eq!(validate(program), Err(vec![ValidationError::SetModeNotFirst]));
+
+        let program = r#"
+            (set-mode OPAQUE)
+            (set-mode TRANSPARENT)
+            (def-rule allow (REDIRECT "127.0.0.1" 80))
+        "#;
+        assert_eq!(
+            validate(program),
+            Err(vec![ValidationError::DuplicateSetMode])
+        );
+    }
+
+    #[test]
+    fn validates_set_eval() {
+        let program = r#"
+            (set-mode OPAQUE)
+            (set-eval ON-CONNECT)
+            (def-rule allow (REDIRECT "127.0.0.1" 80))
+        "#;
+        assert_eq!(validate(program), Ok(()));
+
+        let program = r#"
+            (set-mode TRANSPARENT)
+            (set-eval ON-CONNECT)
+            (set-eval ON-DATA)
+            (def-rule allow (REDIRECT "127.0.0.1" 80))
+        "#;
+        assert_eq!(
+            validate(program),
+            Err(vec![
+                ValidationError::NotAllowedInMode {
+                    what: "`(set-eval ON-CONNECT)`".to_string(),
+                    mode: ProxyMode::TRANSPARENT,
+                },
+                ValidationError::DuplicateSetEval,
+            ])
+        );
+    }
+
+    #[test]
+    fn validates_response_rules() {
+        let program = r#"
+            (set-mode TRANSPARENT)
+            (def-rule allow (REDIRECT "127.0.0.1" 22))
+            (def-response-rule strip-banner
+                (if (prefix? :packet-content "SSH-") (REWRITE "^SSH-[^\\r]*" "SSH-2.0") CONTINUE))
+        "#;
+        assert_eq!(validate(program), Ok(()));
+
+        let program = r#"
+            (set-mode TRANSPARENT)
+            (def-rule allow (REDIRECT "127.0.0.1" 22))
+            (def-response-rule allow
+                (if (contains? :packet-content "secret") (REDIRECT "127.0.0.1" 23) CONTINUE))
+        "#;
+        assert_eq!(
+            validate(program),
+            Err(vec![
+                ValidationError::DuplicateRule("allow".to_string()),
+                ValidationError::NotAllowedInResponse("`REDIRECT`".to_string()),
+            ])
+        );
+
+        let program = r#"
+            (set-mode OPAQUE)
+            (def-rule allow (REDIRECT "127.0.0.1" 22))
+            (def-response-rule block DROP)
+        "#;
+        assert_eq!(
+            validate(program),
+            Err(vec![ValidationError::NotAllowedInMode {
+                what: "`def-response-rule`".to_string(),
+                mode: ProxyMode::OPAQUE,
+            }])
+        );
+    }
+
+    #[test]
+    fn validates_upstreams() {
+        let program = r#"
+            (set-mode OPAQUE)
+            (def-upstream web ROUND-ROBIN ("10.0.0.1" 8080 2) (ip"10.0.0.2" 8080) (check 500))
+            (def-rule balance (if (exact? :packet-dest-port 80) (BALANCE web) DROP))
+        "#;
+        assert_eq!(validate(program), Ok(()));
+
+        let program = r#"
+            (set-mode TRANSPARENT)
+            (def-var web 80)
+            (def-upstream web SOURCE-IP-HASH ("10.0.0.1" 8080 0) ("localhost" 8080))
+            (def-upstream none LEAST-CONNECTIONS)
+            (def-upstream api ROUND-ROBIN (check 0) ("10.0.0.3" 8080))
+            (def-rule balance (if (exact? :packet-dest-port 80) (BALANCE api) (BALANCE ghost)))
+            (def-response-rule back (BALANCE none))
+        "#;
+        assert_eq!(
+            validate(program),
+            Err(vec![
+                ValidationError::Redefinition("web".to_string()),
+                ValidationError::ZeroWeight("web".to_string()),
+                ValidationError::InvalidIp("localhost".to_string()),
+                ValidationError::EmptyUpstream("none".to_string()),
+                ValidationError::ZeroInterval("api".to_string()),
+                ValidationError::UndefinedName("ghost".to_string()),
+                ValidationError::NotAllowedInResponse("`BALANCE`".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_content_rules_in_opaque_mode() {
+        let program = r#"
+            (set-mode OPAQUE)
+
+            (def-var bad-ip ip"192.0.1.2")
+
+            (def-rule simple-rewrite
+                (if (exact? :packet-source-ip bad-ip)
+                    (REWRITE "^bar$" "baz")
+                    DROP))
+            (def-rule rest REJECT)
+        "#;
+        assert_eq!(
+            validate(program),
+            Err(vec![ValidationError::NotAllowedInMode {
+                what: "`REWRITE`".to_string(),
+                mode: ProxyMode::OPAQUE
+            }])
+        );
+    }
+
+    #[test]
+    fn rejects_type_mismatches() {
+        let program = r#"
+            (set-mode OPAQUE)
+
+            (def-var good-port 31337)
+            (def-var flag good-port)
+
+            (def-rule mismatched
+                (if (exact? :packet-source-ip good-port)
+                    DROP
+                    REJECT))
+
+            (def-rule not-a-bool
+                (if flag DROP REJECT))
+        "#;
+        assert_eq!(
+            validate(program),
+            Err(vec![
+                ValidationError::TypeMismatch {
+                    context: "exact?".to_string(),
+                    expected: ValueType::Ip,
+                    found: ValueType::Int,
+                },
+                ValidationError::TypeMismatch {
+                    context: "predicate `flag`".to_string(),
+                    expected: ValueType::Bool,
+                    found: ValueType::Int,
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_bad_arity_and_unknown_predicates() {
+        let program = r#"
+            (set-mode OPAQUE)
+
+            (def-rule arity
+                (if (exact? :packet-source-ip) DROP REJECT))
+
+            (def-rule unknown
+                (if (resolves? :packet-source-ip ip"1.2.3.4") DROP REJECT))
+        "#;
+        assert_eq!(
+            validate(program),
+            Err(vec![
+                ValidationError::WrongArity {
+                    form: "exact?".to_string(),
+                    expected: 2,
+                    found: 1,
+                },
+                ValidationError::UnknownPredicate("resolves?".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn validates_boolean_combinators() {
+        let program = r#"
+            (set-mode OPAQUE)
+
+            (def-var bad-ip ip"192.0.1.2")
+            (def-var bad-port 80)
+
+            (def-rule combined
+                (if (or (and) (not (exact? :packet-source-ip bad-ip)) (exact? bad-port bad-port))
+                    DROP
+                    REJECT))
+        "#;
+        assert_eq!(validate(program), Ok(()));
+
+        let program = r#"
+            (set-mode OPAQUE)
+
+            (def-var bad-port 80)
+
+            (def-rule bad
+                (if (and (not #t #f) (or bad-port (exact? :packet-source-ip))) DROP REJECT))
+        "#;
+        assert_eq!(
+            validate(program),
+            Err(vec![
+                ValidationError::WrongArity {
+                    form: "not".to_string(),
+                    expected: 1,
+                    found: 2,
+                },
+                ValidationError::TypeMismatch {
+                    context: "predicate `bad-port`".to_string(),
+                    expected: ValueType::Bool,
+                    found: ValueType::Int,
+                },
+                ValidationError::WrongArity {
+                    form: "exact?".to_string(),
+                    expected: 2,
+                    found: 1,
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_deeply_nested_predicates() {
+        let predicate = (0..16).fold("#t".to_string(), |p, _| format!("(not {})", p));
+        let program = format!("(set-mode OPAQUE) (def-rule r (if {} DROP REJECT))", predicate);
+        assert!(matches!(
+            validate(&program).unwrap_err().as_slice(),
+            [ValidationError::Unsupported(_)]
+        ));
+    }
+
+    #[test]
+    fn validates_nested_branches() {
+        let program = r#"
+            (set-mode OPAQUE)
+
+            (def-var bad-ip ip"192.0.1.2")
+
+            (def-rule nested
+                (if (exact? :packet-source-ip bad-ip)
+                    (if (exact? bad-ip undefined) DROP CONTINUE)
+                    CONTINUE))
+
+            (def-rule no-else
+                (cond ((exact? :packet-source-ip bad-ip) DROP)))
+        "#;
+        assert_eq!(
+            validate(program),
+            Err(vec![
+                ValidationError::UndefinedName("undefined".to_string()),
+                ValidationError::FallsThrough("no-else".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn validates_subnets() {
+        let program = r#"
+            (set-mode OPAQUE)
+
+            (def-var internal cidr"10.0.0.0/8")
+            (def-var too-long cidr"10.0.0.0/33")
+            (def-var no-prefix cidr"10.0.0.0/")
+            (def-var host cidr"10.1.2.3/8")
+
+            (def-rule bad
+                (if (or (in-subnet? :packet-source-ip internal)
+                        (in-subnet? internal :packet-source-ip)
+                        (in-subnet? :packet-source-ip))
+                    DROP
+                    REJECT))
+        "#;
+        assert_eq!(
+            validate(program),
+            Err(vec![
+                ValidationError::InvalidSubnet("10.0.0.0/33".to_string()),
+                ValidationError::InvalidSubnet("10.0.0.0/".to_string()),
+                ValidationError::TypeMismatch {
+                    context: "in-subnet?".to_string(),
+                    expected: ValueType::Ip,
+                    found: ValueType::Subnet,
+                },
+                ValidationError::TypeMismatch {
+                    context: "in-subnet?".to_string(),
+                    expected: ValueType::Subnet,
+                    found: ValueType::Ip,
+                },
+                ValidationError::WrongArity {
+                    form: "in-subnet?".to_string(),
+                    expected: 2,
+                    found: 1,
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn validates_ipv6_literals() {
+        let program = r#"
+            (set-mode OPAQUE)
+
+            (def-var loopback ip"::1")
+            (def-var docs cidr"2001:db8::/32")
+            (def-var too-long cidr"2001:db8::/129")
+            (def-var bad ip"2001:db8::1::2")
+
+            (def-rule r
+                (if (or (exact? :packet-source-ip loopback)
+                        (in-subnet? :packet-source-ip docs)
+                        (exact? :packet-source-ip "::1"))
+                    (REDIRECT ip"::1" 80)
+                    (REDIRECT "fe80::1" 80)))
+        "#;
+        assert_eq!(
+            validate(program),
+            Err(vec![
+                ValidationError::InvalidSubnet("2001:db8::/129".to_string()),
+                ValidationError::InvalidIp("2001:db8::1::2".to_string()),
+                ValidationError::UntypedAddress("::1".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn validates_numeric_comparisons() {
+        let program = r#"
+            (set-mode TRANSPARENT)
+
+            (def-var ephemeral 49152)
+            (def-var big 100000000000)
+
+            (def-rule ok
+                (if (and (>= :packet-source-port ephemeral)
+                         (port-in-range? :packet-source-port 0 1023)
+                         (> :packet-content-length big)
+                         (exact? :packet-source-port 80))
+                    DROP
+                    REJECT))
+
+            (def-rule bad
+                (if (or (< :packet-source-ip 5)
+                        (<= 1)
+                        (port-in-range? :packet-source-port -1 65536))
+                    DROP
+                    REJECT))
+        "#;
+        assert_eq!(
+            validate(program),
+            Err(vec![
+                ValidationError::TypeMismatch {
+                    context: "<".to_string(),
+                    expected: ValueType::Int,
+                    found: ValueType::Ip,
+                },
+                ValidationError::WrongArity {
+                    form: "<=".to_string(),
+                    expected: 2,
+                    found: 1,
+                },
+                ValidationError::InvalidPort(-1),
+                ValidationError::InvalidPort(65536),
+            ])
+        );
+    }
+
+    #[test]
+    fn validates_content_matching() {
+        let program = r#"
+            (set-mode TRANSPARENT)
+
+            (def-rule ok
+                (if (or (contains? :packet-content "GET ")
+                        (prefix-ci? :packet-content "host:")
+                        (matches? :packet-content "^[A-Z]+ /admin"))
+                    DROP
+                    REJECT))
+
+            (def-rule bad
+                (if (or (contains? :packet-source-ip "GET ")
+                        (suffix? :packet-content 80)
+                        (matches? :packet-content "(unclosed")
+                        (matches? :packet-content :packet-content)
+                        (prefix? :packet-content))
+                    DROP
+                    REJECT))
+        "#;
+        let errors = validate(program).unwrap_err();
+        assert_eq!(errors.len(), 5, "{:?}", errors);
+        assert_eq!(
+            errors[0],
+            ValidationError::TypeMismatch {
+                context: "contains?".to_string(),
+                expected: ValueType::Data,
+                found: ValueType::Ip,
+            }
+        );
+        assert_eq!(
+            errors[1],
+            ValidationError::TypeMismatch {
+                context: "suffix?".to_string(),
+                expected: ValueType::Data,
+                found: ValueType::Int,
+            }
+        );
+        assert!(matches!(&errors[2], ValidationError::InvalidRegex { pattern, .. } if pattern == "(unclosed"));
+        assert_eq!(errors[3], ValidationError::PatternNotLiteral);
+        assert_eq!(
+            errors[4],
+            ValidationError::WrongArity {
+                form: "prefix?".to_string(),
+                expected: 2,
+                found: 1,
+            }
+        );
+
+        let program = r#"
+            (set-mode OPAQUE)
+            (def-rule r (if (contains? :packet-content "GET ") DROP REJECT))
+        "#;
+        assert!(matches!(
+            validate(program).unwrap_err().as_slice(),
+            [ValidationError::NotAllowedInMode { .. }]
+        ));
+    }
+
+    #[test]
+    fn validates_typed_literals() {
+        let program = r#"
+            (set-mode TRANSPARENT)
+
+            (def-var localhost ip"127.0.0.1")
+            (def-var internal cidr"10.0.0.0/8")
+            (def-var handshake #x"16 03")
+            (def-var greeting "10.0.0.1")
+
+            (def-rule ok
+                (if (or (exact? :packet-source-ip localhost)
+                        (in-subnet? :packet-source-ip internal)
+                        (prefix? :packet-content handshake)
+                        (contains? :packet-content greeting))
+                    DROP
+                    CONTINUE))
+
+            (def-rule bad
+                (if (or (exact? :packet-source-ip "127.0.0.1")
+                        (in-subnet? :packet-source-ip "10.0.0.0/8")
+                        (exact? :packet-source-ip greeting)
+                        (exact? :packet-source-ip ip"localhost"))
+                    DROP
+                    REJECT))
+        "#;
+        assert_eq!(
+            validate(program),
+            Err(vec![
+                ValidationError::UntypedAddress("127.0.0.1".to_string()),
+                ValidationError::UntypedAddress("10.0.0.0/8".to_string()),
+                ValidationError::TypeMismatch {
+                    context: "exact?".to_string(),
+                    expected: ValueType::Ip,
+                    found: ValueType::Data,
+                },
+                ValidationError::InvalidIp("localhost".to_string()),
+            ])
+        );
+        assert_eq!(
+            ValidationError::UntypedAddress("10.0.0.0/8".to_string()).to_string(),
+            "`\"10.0.0.0/8\"` is a byte string; write `cidr\"10.0.0.0/8\"` to use it as an address"
+        );
+
+        let program = r#"
+            (set-mode OPAQUE)
+            (def-set blocked ("1.2.3.4" ip"5.6.7.8"))
+            (def-rule r (if (member? :packet-source-ip blocked) DROP REJECT))
+        "#;
+        assert_eq!(
+            validate(program),
+            Err(vec![ValidationError::UntypedAddress("1.2.3.4".to_string())])
+        );
+    }
+
+    #[test]
+    fn validates_rewrites() {
+        let program = r#"
+            (set-mode TRANSPARENT)
+
+            (def-rule ok (if (and) (REWRITE "user=(?P<name>\\w+)" "$$${name}:$1 ${0}$0") CONTINUE))
+            (def-rule bad-regex (if (and) (REWRITE "(unclosed" "") CONTINUE))
+            (def-rule bad-group (REWRITE "(a)" "$1a $2 ${b}"))
+            (def-rule rest REJECT)
+        "#;
+        let errors = validate(program).unwrap_err();
+        assert!(
+            matches!(&errors[0], ValidationError::InvalidRegex { pattern, .. } if pattern == "(unclosed")
+        );
+        assert_eq!(
+            errors[1..],
+            [
+                ValidationError::UnknownCaptureGroup("1a".to_string()),
+                ValidationError::UnknownCaptureGroup("2".to_string()),
+                ValidationError::UnknownCaptureGroup("b".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_capture_references() {
+        assert_eq!(capture_references("$1 ${name} $$2 $ $"), vec!["1", "name"]);
+        assert_eq!(capture_references("$$$x_1.$"), vec!["x_1"]);
+        assert_eq!(capture_references("${unclosed"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn validates_sets() {
+        let program = r#"
+            (set-mode OPAQUE)
+
+            (def-var web 80)
+            (def-set blocked (ip"1.2.3.4" ip"5.6.7.8"))
+            (def-set ports (web 443 8080))
+
+            (def-rule ok
+                (if (or (member? :packet-source-ip blocked) (member? :packet-source-port ports))
+                    DROP
+                    REJECT))
+        "#;
+        assert_eq!(validate(program), Ok(()));
+
+        let program = r#"
+            (set-mode OPAQUE)
+
+            (def-set mixed (ip"1.2.3.4" 80))
+            (def-set subnets (cidr"10.0.0.0/8"))
+            (def-set empty nil)
+            (def-set ports (80))
+            (def-set ports (443))
+
+            (def-rule bad
+                (if (or (member? :packet-source-ip ports) (member? :packet-source-ip ip"1.2.3.4"))
+                    DROP
+                    REJECT))
+        "#;
+        assert_eq!(
+            validate(program),
+            Err(vec![
+                ValidationError::TypeMismatch {
+                    context: "def-set `mixed`".to_string(),
+                    expected: ValueType::IpSet,
+                    found: ValueType::IntSet,
+                },
+                ValidationError::InvalidSetMember("subnets".to_string()),
+                ValidationError::EmptySet("empty".to_string()),
+                ValidationError::Redefinition("ports".to_string()),
+                ValidationError::TypeMismatch {
+                    context: "member?".to_string(),
+                    expected: ValueType::Int,
+                    found: ValueType::Ip,
+                },
+                ValidationError::TypeMismatch {
+                    context: "member?".to_string(),
+                    expected: ValueType::IpSet,
+                    found: ValueType::Ip,
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_definitions() {
+        let program = r#"
+            (set-mode OPAQUE)
+
+            (def-var ip ip"1.2.3.4")
+            (def-var ip ip"5.6.7.8")
+
+            (def-rule allow DROP)
+            (def-rule allow REJECT)
+        "#;
+        assert_eq!(
+            validate(program),
+            Err(vec![
+                ValidationError::Redefinition("ip".to_string()),
+                ValidationError::DuplicateRule("allow".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_program_without_rules() {
+        assert_eq!(
+            validate("(set-mode OPAQUE)"),
+            Err(vec![ValidationError::NoRules])
+        );
+    }
+
+    #[test]
+    fn accepts_bundled_rule_files() {
+        let root = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+        for dir in ["rules", "rulefiles"] {
+            for entry in std::fs::read_dir(root.join(dir)).unwrap() {
+                let path = entry.unwrap().path();
+                let program = std::fs::read_to_string(&path).unwrap();
+                assert_eq!(validate(&program), Ok(()), "{} is invalid", path.display());
+            }
+        }
+    }
+
+    #[test]
+    fn accepts_documented_examples() {
+        let root = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+        let docs = std::fs::read_to_string(root.join("docs/rules/rules.md")).unwrap();
+        let examples: Vec<&str> = docs
+            .split("```lisp")
+            .skip(1)
+            .filter_map(|block| block.split("```").next())
+            // the rest are fragments rather than whole programs
+            .filter(|block| block.contains("(set-mode"))
+            .collect();
+        assert!(!examples.is_empty());
+        for example in examples {
+            assert_eq!(validate(example), Ok(()), "{}", example);
+        }
+    }
+}
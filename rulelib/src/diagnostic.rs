@@ -0,0 +1,251 @@
+/**
+* # Diagnostics
+*
+* ## Description:
+* Compiler-style error reports for rule files. Every stage of compilation (pest, `AstNode::try_from`
+* and `AstNode::validate`) reports its errors as `Diagnostic`s, which carry the byte span of the
+* source they refer to.
+*
+* ## Public Interface:
+* `render` turns a list of diagnostics into a report like the following, which the redirector sends
+* back over RPC and the client prints as-is:
+*
+* ```text
+* error: `good-ip` is not defined
+*  --> rules/localhost.rf:7:35
+*   |
+* 7 |     (if (exact? :packet-source-ip good-ip)
+*   |                                   ^^^^^^^
+* ```
+*/
+use std::fmt::Write;
+
+use pest::error::InputLocation;
+
+use crate::ast::{AstParseError, ValidationError};
+use crate::parser::Rule;
+
+/// A range of byte offsets into a rule file
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl From<pest::Span<'_>> for Span {
+    fn from(span: pest::Span<'_>) -> Self {
+        Self {
+            start: span.start(),
+            end: span.end(),
+        }
+    }
+}
+
+/// A value along with the part of the source it refers to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned<T> {
+    pub value: T,
+    pub span: Option<Span>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl From<pest::error::Error<Rule>> for Diagnostic {
+    fn from(error: pest::error::Error<Rule>) -> Self {
+        let span = match error.location {
+            InputLocation::Pos(pos) => Span {
+                start: pos,
+                end: pos,
+            },
+            InputLocation::Span((start, end)) => Span { start, end },
+        };
+        Self {
+            message: error.variant.message().into_owned(),
+            span: Some(span),
+        }
+    }
+}
+
+impl From<AstParseError> for Diagnostic {
+    fn from(error: AstParseError) -> Self {
+        match error {
+            AstParseError::ParseError(message, span) => Self { message, span },
+        }
+    }
+}
+
+impl From<Spanned<ValidationError>> for Diagnostic {
+    fn from(error: Spanned<ValidationError>) -> Self {
+        Self {
+            message: error.value.to_string(),
+            span: error.span,
+        }
+    }
+}
+
+/// The 1-based line and column of a byte offset, along with the text of that line
+fn locate(source: &str, offset: usize) -> (usize, usize, &str) {
+    let offset = offset.min(source.len());
+    let line_start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[offset..]
+        .find('\n')
+        .map_or(source.len(), |i| offset + i);
+    let line = source[..offset].matches('\n').count() + 1;
+    let column = source[line_start..offset].chars().count() + 1;
+
+    (line, column, source[line_start..line_end].trim_end_matches('\r'))
+}
+
+/// Renders diagnostics for the rule file `file` with contents `source`, one after the other
+pub fn render(file: &str, source: &str, diagnostics: &[Diagnostic]) -> String {
+    let mut report = String::new();
+    for (i, diagnostic) in diagnostics.iter().enumerate() {
+        if i > 0 {
+            report.push('\n');
+        }
+        let _ = writeln!(report, "error: {}", diagnostic.message);
+
+        let span = match diagnostic.span {
+            Some(span) => span,
+            None => {
+                let _ = writeln!(report, " --> {}", file);
+                continue;
+            }
+        };
+
+        let (line, column, text) = locate(source, span.start);
+        let gutter = " ".repeat(line.to_string().len());
+        // keep tabs so the caret lines up with the source
+        let padding: String = text
+            .chars()
+            .take(column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        // multi-line spans are only underlined up to the end of their first line
+        let width = text
+            .chars()
+            .skip(column - 1)
+            .take(span.end.saturating_sub(span.start))
+            .count()
+            .max(1);
+
+        let _ = writeln!(report, "{}--> {}:{}:{}", gutter, file, line, column);
+        let _ = writeln!(report, "{} |", gutter);
+        let _ = writeln!(report, "{} | {}", line, text);
+        let _ = writeln!(report, "{} | {}{}", gutter, padding, "^".repeat(width));
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_caret_under_span() {
+        let source = "(set-mode OPAQUE)\n(def-var ip \"1.2.3\")\n";
+        let diagnostics = [Diagnostic {
+            message: "`1.2.3` is not a valid IP address".to_string(),
+            span: Some(Span { start: 30, end: 37 }),
+        }];
+        assert_eq!(
+            render("test.rf", source, &diagnostics),
+            "error: `1.2.3` is not a valid IP address\n \
+             --> test.rf:2:13\n  \
+             |\n\
+             2 | (def-var ip \"1.2.3\")\n  \
+             |             ^^^^^^^\n"
+        );
+    }
+
+    #[test]
+    fn renders_every_diagnostic() {
+        let source = "(set-mode OPAQUE)";
+        let diagnostics = [
+            Diagnostic {
+                message: "first".to_string(),
+                span: None,
+            },
+            Diagnostic {
+                message: "second".to_string(),
+                span: Some(Span { start: 10, end: 16 }),
+            },
+        ];
+        assert_eq!(
+            render("test.rf", source, &diagnostics),
+            "error: first\n --> test.rf\n\n\
+             error: second\n \
+             --> test.rf:1:11\n  \
+             |\n\
+             1 | (set-mode OPAQUE)\n  \
+             |           ^^^^^^\n"
+        );
+    }
+
+    #[test]
+    fn locates_offsets_past_the_last_newline() {
+        assert_eq!(locate("ab\ncd", 4), (2, 2, "cd"));
+        assert_eq!(locate("ab\r\ncd", 0), (1, 1, "ab"));
+        assert_eq!(locate("", 0), (1, 1, ""));
+    }
+
+    #[test]
+    fn compile_reports_source_locations() {
+        let source = "(set-mode OPAQUE)\n\n(def-rule allow-localhost\n    (if (exact? :packet-source-ip good-ip)\n        (REDIRECT \"127.0.0.1\" 80)\n        DROP))\n";
+        let diagnostics = crate::compile(source).unwrap_err();
+        assert_eq!(
+            render("localhost.rf", source, &diagnostics),
+            "error: `good-ip` is not defined\n \
+             --> localhost.rf:4:35\n  \
+             |\n\
+             4 |     (if (exact? :packet-source-ip good-ip)\n  \
+             |                                   ^^^^^^^\n"
+        );
+    }
+
+    #[test]
+    fn compile_reports_errors_inside_outcomes() {
+        let source = "(set-mode TRANSPARENT)\n(def-rule r (REWRITE \"(\\d+)\" \"n\"))\n";
+        let diagnostics = crate::compile(source).unwrap_err();
+        assert_eq!(
+            render("rewrite.rf", source, &diagnostics),
+            "error: unknown escape `\\d`\n \
+             --> rewrite.rf:2:24\n  \
+             |\n\
+             2 | (def-rule r (REWRITE \"(\\d+)\" \"n\"))\n  \
+             |                        ^^\n"
+        );
+    }
+
+    #[test]
+    fn compile_reports_locations_past_comments() {
+        let source = "; ünïcödé comment\n(set-mode OPAQUE) #| x |# (def-var v ip\"1.2.3\")\n(def-rule r DROP)\n";
+        let diagnostics = crate::compile(source).unwrap_err();
+        assert_eq!(
+            render("comments.rf", source, &diagnostics),
+            "error: `1.2.3` is not a valid IP address\n \
+             --> comments.rf:2:38\n  \
+             |\n\
+             2 | (set-mode OPAQUE) #| x |# (def-var v ip\"1.2.3\")\n  \
+             |                                      ^^^^^^^^^\n"
+        );
+
+        let source = "(set-mode OPAQUE)\n#| never closed\n(def-rule r DROP)\n";
+        let diagnostics = crate::compile(source).unwrap_err();
+        assert_eq!(diagnostics.len(), 1);
+        let (line, _, _) = locate(source, diagnostics[0].span.unwrap().start);
+        assert_eq!(line, 2);
+    }
+
+    #[test]
+    fn compile_reports_syntax_errors() {
+        let diagnostics = crate::compile("(set-mode OPAQUE)\n(def-rule (").unwrap_err();
+        assert_eq!(diagnostics.len(), 1);
+        let (line, _, _) = locate("(set-mode OPAQUE)\n(def-rule (", diagnostics[0].span.unwrap().start);
+        assert_eq!(line, 2);
+    }
+}
@@ -1,3 +1,25 @@
 pub mod ast;
+pub mod diagnostic;
 pub mod parser;
 pub mod vm;
+
+use pest::Parser;
+
+use crate::ast::AstNode;
+use crate::diagnostic::Diagnostic;
+use crate::parser::{Rule, RuleParser};
+use crate::vm::Program;
+
+/// Parses, validates and compiles a rule file, reporting every error found along the way.
+/// Render the errors with `diagnostic::render`.
+pub fn compile(source: &str) -> Result<Program, Vec<Diagnostic>> {
+    let parse_tree = RuleParser::parse(Rule::program, source)
+        .map_err(|e| vec![Diagnostic::from(e)])?
+        .next()
+        .expect("a successful parse always yields a `program`");
+    let ast = AstNode::try_from(parse_tree).map_err(|e| vec![Diagnostic::from(e)])?;
+    ast.validate()
+        .map_err(|errors| errors.into_iter().map(Diagnostic::from).collect::<Vec<_>>())?;
+
+    Ok(ast.codegen())
+}
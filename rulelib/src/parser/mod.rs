@@ -14,7 +14,7 @@ mod tests {
         let program = r#"
     (set-mode OPAQUE)
 
-    (def-var bad-ip "192.0.1.2")
+    (def-var bad-ip ip"192.0.1.2")
 
     (def-rule simple-rewrite
         (if (exact? metadata-source bad-ip)
@@ -34,7 +34,7 @@ mod tests {
     fn test_bad_program() {
         // program has an unclosed paranthesis
         let bad_program = r#"
-            (def-var bad-ip "192.0.1.2")
+            (def-var bad-ip ip"192.0.1.2")
 
             (def-rule simple-rule (:target "127.0.0.1" :port   "80")
                 (if (and (exact? metadata-source bad-ip) (exact? metedata-dest   :target)
@@ -50,6 +50,32 @@ mod tests {
         assert!(parse_result.is_err());
     }
 
+    #[test]
+    fn test_comments() {
+        let program = r#";; OPAQUE or TRANSPARENT
+(set-mode OPAQUE) ; trailing
+
+#| a block comment
+   #| which nests |#
+   (def-var hidden 1) |#
+(def-rule r ; between atoms
+    (if (exact? :packet-source-port #| inline |# 80)
+        (REDIRECT "127.0.0.1;#|" 80) ; not a comment inside a string
+        DROP))
+;; at the very end"#;
+        let parse = RuleParser::parse(Rule::program, program).unwrap().next().unwrap();
+        let statements: Vec<_> = parse
+            .into_inner()
+            .filter(|pair| pair.as_rule() == Rule::s_exp)
+            .map(|pair| pair.as_str())
+            .collect();
+        assert_eq!(statements.len(), 2);
+        assert_eq!(statements[0], "(set-mode OPAQUE)");
+        assert!(statements[1].contains("\"127.0.0.1;#|\""));
+
+        assert!(RuleParser::parse(Rule::program, "(set-mode OPAQUE) #| unclosed").is_err());
+    }
+
     #[test]
     fn test_boolean() {
         let program1 = "(def-var bool-var #t)";
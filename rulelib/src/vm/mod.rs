@@ -1,21 +1,43 @@
-use std::collections::HashMap;
-use std::net::Ipv4Addr;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
+
+use ipnet::IpNet;
+use regex::bytes::Regex;
+use regex_syntax::hir::{Capture, Hir, HirKind, Look, Repetition};
+use regex_syntax::ParserBuilder;
+
+mod stream;
+
+pub use stream::{Needles, Scan};
 
 pub(crate) type Reg = usize;
 pub(crate) type ObjKey = u32; // use positive numbers for HashMap keys, use negative numbers for packet fields
 pub(crate) type Label = usize;
 
 pub const PACKET_MASK: u32 = 0x80000000; // to access packet fields, set MSB of ObjKey to 1
+#[allow(clippy::identity_op)]
 pub const PACKET_SOURCE_IP: ObjKey = 0 | PACKET_MASK;
 pub const PACKET_SOURCE_PORT: ObjKey = 1 | PACKET_MASK;
 pub const PACKET_DEST_IP: ObjKey = 2 | PACKET_MASK;
 pub const PACKET_DEST_PORT: ObjKey = 3 | PACKET_MASK;
 pub const PACKET_CONTENT: ObjKey = 4 | PACKET_MASK;
+pub const PACKET_CONTENT_LENGTH: ObjKey = 5 | PACKET_MASK;
+pub const PACKET_LISTENER: ObjKey = 6 | PACKET_MASK;
 
 #[derive(Debug, Clone)]
 pub enum Instruction {
     SEQ(Reg, ObjKey, ObjKey), // set-if-equal
+    SIN(Reg, ObjKey, ObjKey), // set-if-in-subnet: address, subnet
+    SLT(Reg, ObjKey, ObjKey), // set-if-less-than
+    SLE(Reg, ObjKey, ObjKey), // set-if-less-than-or-equal
+    SCON(Reg, ObjKey, ObjKey), // set-if-contains: haystack, needle
+    SPRE(Reg, ObjKey, ObjKey), // set-if-prefix: haystack, prefix
+    SSUF(Reg, ObjKey, ObjKey), // set-if-suffix: haystack, suffix
+    SMAT(Reg, ObjKey, ObjKey), // set-if-matches: haystack, pattern
+    SMEM(Reg, ObjKey, ObjKey), // set-if-member: value, set
     AND(Reg, Reg, Reg),       // bitwise AND
     OR(Reg, Reg, Reg),        // bitwise OR
     NOT(Reg, Reg),            // bitwise NOT
@@ -23,13 +45,33 @@ pub enum Instruction {
     DROP,
     REDIRECT(ObjKey, ObjKey), // redirect Address, Port,
     REJECT,
-    REWRITE(ObjKey, ObjKey), // rewrite find_string replace_string
+    REWRITE(ObjKey, ObjKey), // rewrite pattern replacement
+    BALANCE(ObjKey),         // balance across a pool
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct Program {
     pub instructions: Vec<Instruction>,
+    /// The `def-response-rule`s, run on data the upstream sends back to the client. They share
+    /// `data` with the request rules; with none, responses are passed through untouched.
+    pub response: Vec<Instruction>,
     pub data: HashMap<ObjKey, Object>,
+    pub evaluation: Evaluation,
+    /// What the request rules look for in `:packet-content`
+    pub needles: Needles,
+    /// What the response rules look for in `:packet-content`
+    pub response_needles: Needles,
+}
+
+/// When the redirector runs a program
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Evaluation {
+    /// On every chunk of data the client sends
+    #[default]
+    OnData,
+    /// Once, when the client connects; the action is then used for the rest of the connection.
+    /// Packets have no content.
+    OnConnect,
 }
 
 const NUM_REGS: usize = 16;
@@ -37,25 +79,249 @@ pub struct VM {
     registers: [u32; NUM_REGS],
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Clone, Debug)]
 pub enum Action {
     DROP,
     REDIRECT(Object, Object),
-    REWRITE(Object, Object),
     REJECT,
+    /// Forward to a backend of the pool, which the redirector picks
+    BALANCE(Arc<Pool>),
+}
+
+/// A rewrite of the content, made by a `REWRITE` on the way to the action.
+/// The redirector applies them in order, each to the result of the one before.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Transform {
+    pub pattern: Pattern,
+    pub replacement: Arc<Vec<u8>>,
+}
+
+impl Transform {
+    pub fn apply(&self, content: &[u8], edges: Edges) -> Vec<u8> {
+        self.pattern.replace_all(content, &self.replacement, edges)
+    }
 }
 
 #[derive(PartialEq, Clone, Debug)]
 pub enum Object {
-    IP(Ipv4Addr),
+    IP(IpAddr),
+    Subnet(IpNet),
     Port(u16),
+    Int(i64),
     Data(Arc<Vec<u8>>), // TODO: make this a lifetime
+    Pattern(Pattern),
+    IPSet(Arc<HashSet<IpAddr>>),
+    /// A set of ports or integers
+    IntSet(Arc<HashSet<i64>>),
+    Pool(Arc<Pool>),
+}
+
+/// How the redirector picks a backend from a pool
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Strategy {
+    /// Each backend in turn, as many times in a row as its weight
+    RoundRobin,
+    /// The backend with the fewest open connections for its weight
+    LeastConnections,
+    /// The same backend for every connection from a client address, for as long as that backend is up
+    SourceIpHash,
+}
+
+/// The backends a `(def-upstream ...)` lists
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pool {
+    pub name: String,
+    pub strategy: Strategy,
+    /// Never empty
+    pub backends: Vec<Backend>,
+    /// How the redirector probes the backends; without one they're always taken to be up
+    pub check: Option<HealthCheck>,
+}
+
+/// A probe of each backend, every `interval`: a TCP connect, then optionally an exchange of bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthCheck {
+    /// Also how long a probe has to finish
+    pub interval: Duration,
+    /// Sent once connected; may be empty
+    pub send: Vec<u8>,
+    /// What the backend's reply has to start with; empty to only check the connect
+    pub expect: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backend {
+    pub addr: SocketAddr,
+    /// At least 1
+    pub weight: u32,
+}
+
+/// A regex, compiled once when the program is generated
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    regex: Regex,
+    /// The regexes for content that doesn't end its stream, doesn't begin it, or neither, in which
+    /// `\A` and `^`, `\z` and `$`, or all four never match. `None` when it has none of them.
+    within: Option<Box<[Regex; 3]>>,
+    /// Whether a match can depend on reaching the end of the content, with `\z` or `$`
+    ends: bool,
+}
+
+/// Whether some content begins its connection's stream, and whether it runs to the end of what's
+/// been sent of it with no more to wait for, which is where the anchors of patterns can match
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edges {
+    pub start: bool,
+    pub end: bool,
+}
+
+impl Edges {
+    /// Content that is the whole of what's matched, like a field other than `:packet-content`
+    pub const WHOLE: Edges = Edges {
+        start: true,
+        end: true,
+    };
+}
+
+impl Pattern {
+    pub fn new(regex: Regex) -> Self {
+        let hir = ParserBuilder::new().utf8(false).build().parse(regex.as_str()).ok();
+        let looks = hir.as_ref().map(|hir| hir.properties().look_set()).unwrap_or_default();
+        let ends = looks.contains(Look::End);
+        let within = match hir {
+            Some(hir) if looks.contains(Look::Start) || ends => {
+                let variant = |start, end| {
+                    Regex::new(&without_anchors(hir.clone(), start, end).to_string())
+                };
+                // NOTE: each variant matches less than the pattern does, so it compiles as that did
+                match (variant(true, false), variant(false, true), variant(false, false)) {
+                    (Ok(first), Ok(last), Ok(middle)) => Some(Box::new([first, last, middle])),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        Self { regex, within, ends }
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.regex.as_str()
+    }
+
+    /// Whether a match can depend on reaching the end of the content
+    pub fn ends(&self) -> bool {
+        self.ends
+    }
+
+    fn regex(&self, edges: Edges) -> &Regex {
+        match (&self.within, edges.start, edges.end) {
+            (Some(within), true, false) => &within[0],
+            (Some(within), false, true) => &within[1],
+            (Some(within), false, false) => &within[2],
+            _ => &self.regex,
+        }
+    }
+
+    pub fn is_match(&self, content: &[u8], edges: Edges) -> bool {
+        self.regex(edges).is_match(content)
+    }
+
+    /// Replaces every match in `content`; `$1` or `${name}` in the replacement refers to a capture group
+    pub fn replace_all(&self, content: &[u8], replacement: &[u8], edges: Edges) -> Vec<u8> {
+        self.regex(edges).replace_all(content, replacement).into_owned()
+    }
+
+    /// Where the matches that start before `limit` end: `limit`, or further if one goes past it
+    pub fn settled_end(&self, content: &[u8], limit: usize, edges: Edges) -> usize {
+        self.regex(edges)
+            .find_iter(content)
+            .take_while(|m| m.start() < limit)
+            .last()
+            .map_or(limit, |m| limit.max(m.end()))
+    }
+}
+
+/// `hir` with `\A` and `^` never matching unless it's at the `start` of the stream, and `\z` and
+/// `$` never matching unless it's at the `end`
+fn without_anchors(hir: Hir, start: bool, end: bool) -> Hir {
+    let inner = |sub: Box<Hir>| Box::new(without_anchors(*sub, start, end));
+    match hir.into_kind() {
+        HirKind::Look(Look::Start) if !start => Hir::fail(),
+        HirKind::Look(Look::End) if !end => Hir::fail(),
+        HirKind::Look(look) => Hir::look(look),
+        HirKind::Empty => Hir::empty(),
+        HirKind::Literal(literal) => Hir::literal(literal.0),
+        HirKind::Class(class) => Hir::class(class),
+        HirKind::Repetition(repetition) => Hir::repetition(Repetition {
+            sub: inner(repetition.sub),
+            ..repetition
+        }),
+        HirKind::Capture(capture) => Hir::capture(Capture {
+            sub: inner(capture.sub),
+            ..capture
+        }),
+        HirKind::Concat(subs) => {
+            Hir::concat(subs.into_iter().map(|sub| without_anchors(sub, start, end)).collect())
+        }
+        HirKind::Alternation(subs) => {
+            Hir::alternation(subs.into_iter().map(|sub| without_anchors(sub, start, end)).collect())
+        }
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Object {
+    /// Ports and integers are both numbers, and compare as such
+    fn as_int(&self) -> Option<i64> {
+        match self {
+            Object::Port(port) => Some(*port as i64),
+            Object::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
 }
 
 pub struct Packet {
-    pub source: (Ipv4Addr, u16),
-    pub dest: (Ipv4Addr, u16),
+    /// IPv4-mapped IPv6 addresses should be converted to IPv4 (with `IpAddr::to_canonical`), since
+    /// rules compare them with the IPv4 addresses they stand for
+    /// The client's address
+    pub source: (IpAddr, u16),
+    /// The local address the client connected to, which on a multi-homed host or a listener bound
+    /// to every interface says which of them was hit
+    pub dest: (IpAddr, u16),
     pub content: Arc<Vec<u8>>,
+    /// How far into the connection's stream `content` begins; `prefix?`, `\A` and `^` only match
+    /// content that begins it
+    pub offset: usize,
+    /// Whether `content` runs to the end of what's been sent, with no more to wait for before
+    /// deciding; `suffix?`, `\z` and `$` only match then
+    pub end: bool,
+    /// The name of the listener that accepted the connection
+    pub listener: Arc<Vec<u8>>,
+}
+
+impl Packet {
+    /// The edges of a field: only `:packet-content` is part of a stream
+    fn edges(&self, key: ObjKey) -> Edges {
+        match key {
+            PACKET_CONTENT => Edges {
+                start: self.offset == 0,
+                end: self.end,
+            },
+            _ => Edges::WHOLE,
+        }
+    }
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
@@ -64,17 +330,111 @@ impl VM {
         Self { registers: regs }
     }
 
+    /// Returns the action and, in order, the rewrites of the content made on the way to it.
     /// Precondition: program is a valid Program (has valid register numbers and labels)
-    pub fn run_program(&mut self, program: &Program, packet: &Packet) -> Result<Action, &str> {
+    pub fn run_program(
+        &mut self,
+        program: &Program,
+        packet: &Packet,
+    ) -> Result<(Action, Vec<Transform>), &str> {
+        match self.run(&program.instructions, program, packet)? {
+            (Some(action), transforms) => Ok((action, transforms)),
+            (None, _) => Err("Program ended without action"),
+        }
+    }
+
+    /// Runs the program's response rules on a packet from the upstream.
+    /// `None` means that every rule continued, so the response goes back to the client with only
+    /// the rewrites applied.
+    pub fn run_response(
+        &mut self,
+        program: &Program,
+        packet: &Packet,
+    ) -> Result<(Option<Action>, Vec<Transform>), &str> {
+        self.run(&program.response, program, packet)
+    }
+
+    fn run(
+        &mut self,
+        instructions: &[Instruction],
+        program: &Program,
+        packet: &Packet,
+    ) -> Result<(Option<Action>, Vec<Transform>), &str> {
+        let mut transforms = vec![];
         let mut pc = 0; // program counter
-        while pc < program.instructions.len() {
+        while pc < instructions.len() {
             let mut control_normal = true;
-            match program.instructions[pc] {
+            match instructions[pc] {
                 Instruction::SEQ(r0, key1, key2) => {
-                    self.registers[r0] = (self.get_object(key1, program, packet)
-                        == self.get_object(key2, program, packet))
+                    let lhs = self.get_object(key1, program, packet);
+                    let rhs = self.get_object(key2, program, packet);
+                    self.registers[r0] = match (&lhs, &rhs) {
+                        (Ok(lhs), Ok(rhs)) => match (lhs.as_int(), rhs.as_int()) {
+                            (Some(lhs), Some(rhs)) => lhs == rhs,
+                            _ => lhs == rhs,
+                        },
+                        _ => lhs == rhs,
+                    } as u32;
+                }
+                Instruction::SLT(r0, key1, key2) => {
+                    self.registers[r0] = matches!(
+                        self.compare(key1, key2, program, packet),
+                        Some(Ordering::Less)
+                    ) as u32;
+                }
+                Instruction::SLE(r0, key1, key2) => {
+                    self.registers[r0] = matches!(
+                        self.compare(key1, key2, program, packet),
+                        Some(Ordering::Less | Ordering::Equal)
+                    ) as u32;
+                }
+                Instruction::SIN(r0, key1, key2) => {
+                    self.registers[r0] = match (
+                        self.get_object(key1, program, packet),
+                        self.get_object(key2, program, packet),
+                    ) {
+                        (Ok(Object::IP(addr)), Ok(Object::Subnet(subnet))) => subnet.contains(&addr),
+                        _ => false,
+                    } as u32;
+                }
+                Instruction::SCON(r0, key1, key2) => {
+                    self.registers[r0] = self.match_data(key1, key2, program, packet, |h, n| {
+                        n.is_empty() || h.windows(n.len()).any(|window| window == n)
+                    }) as u32;
+                }
+                Instruction::SPRE(r0, key1, key2) => {
+                    self.registers[r0] = (packet.edges(key1).start
+                        && self.match_data(key1, key2, program, packet, |h, n| h.starts_with(n)))
+                        as u32;
+                }
+                Instruction::SSUF(r0, key1, key2) => {
+                    self.registers[r0] = (packet.edges(key1).end
+                        && self.match_data(key1, key2, program, packet, |h, n| h.ends_with(n)))
                         as u32;
                 }
+                Instruction::SMAT(r0, key1, key2) => {
+                    self.registers[r0] = match (
+                        self.get_object(key1, program, packet),
+                        self.get_object(key2, program, packet),
+                    ) {
+                        (Ok(Object::Data(data)), Ok(Object::Pattern(pattern))) => {
+                            pattern.is_match(&data, packet.edges(key1))
+                        }
+                        _ => false,
+                    } as u32;
+                }
+                Instruction::SMEM(r0, key1, key2) => {
+                    self.registers[r0] = match (
+                        self.get_object(key1, program, packet),
+                        self.get_object(key2, program, packet),
+                    ) {
+                        (Ok(Object::IP(addr)), Ok(Object::IPSet(set))) => set.contains(&addr),
+                        (Ok(value), Ok(Object::IntSet(set))) => {
+                            value.as_int().is_some_and(|n| set.contains(&n))
+                        }
+                        _ => false,
+                    } as u32;
+                }
                 Instruction::AND(r0, r1, r2) => {
                     self.registers[r0] = self.registers[r1] & self.registers[r2];
                 }
@@ -93,27 +453,73 @@ impl VM {
                     control_normal = false;
                 }
                 Instruction::DROP => {
-                    return Ok(Action::DROP);
+                    return Ok((Some(Action::DROP), transforms));
                 }
                 Instruction::REDIRECT(address_label, port_label) => {
-                    return Ok(Action::REDIRECT(
+                    let action = Action::REDIRECT(
                         self.get_object(address_label, program, packet).unwrap(),
                         self.get_object(port_label, program, packet).unwrap(),
-                    ));
+                    );
+                    return Ok((Some(action), transforms));
                 }
-                Instruction::REJECT => return Ok(Action::REJECT),
+                Instruction::REJECT => return Ok((Some(Action::REJECT), transforms)),
                 Instruction::REWRITE(find_label, replace_label) => {
-                    return Ok(Action::REWRITE(
-                        self.get_object(find_label, program, packet).unwrap(),
-                        self.get_object(replace_label, program, packet).unwrap(),
-                    ));
+                    match (
+                        self.get_object(find_label, program, packet),
+                        self.get_object(replace_label, program, packet),
+                    ) {
+                        (Ok(Object::Pattern(pattern)), Ok(Object::Data(replacement))) => {
+                            transforms.push(Transform {
+                                pattern,
+                                replacement,
+                            })
+                        }
+                        _ => return Err("REWRITE needs a pattern and a replacement"),
+                    }
+                }
+                Instruction::BALANCE(pool_label) => {
+                    return match self.get_object(pool_label, program, packet) {
+                        Ok(Object::Pool(pool)) => Ok((Some(Action::BALANCE(pool)), transforms)),
+                        _ => Err("BALANCE needs a pool"),
+                    };
                 }
             }
             if control_normal {
                 pc += 1;
             }
         }
-        Err("Program ended without action")
+        Ok((None, transforms))
+    }
+
+    /// Numerically compares two objects; anything that isn't a number is incomparable
+    fn compare(
+        &self,
+        key1: ObjKey,
+        key2: ObjKey,
+        program: &Program,
+        packet: &Packet,
+    ) -> Option<Ordering> {
+        let lhs = self.get_object(key1, program, packet).ok()?.as_int()?;
+        let rhs = self.get_object(key2, program, packet).ok()?.as_int()?;
+        Some(lhs.cmp(&rhs))
+    }
+
+    /// Tests two `Data` objects against each other; anything else never matches
+    fn match_data(
+        &self,
+        key1: ObjKey,
+        key2: ObjKey,
+        program: &Program,
+        packet: &Packet,
+        test: impl Fn(&[u8], &[u8]) -> bool,
+    ) -> bool {
+        match (
+            self.get_object(key1, program, packet),
+            self.get_object(key2, program, packet),
+        ) {
+            (Ok(Object::Data(haystack)), Ok(Object::Data(needle))) => test(&haystack, &needle),
+            _ => false,
+        }
     }
 
     // this is the "memory controller"
@@ -129,9 +535,11 @@ impl VM {
             match key {
                 PACKET_SOURCE_IP => Ok(Object::IP(packet.source.0)),
                 PACKET_SOURCE_PORT => Ok(Object::Port(packet.source.1)),
-                PACKET_DEST_IP => Ok(Object::IP(packet.source.0)),
-                PACKET_DEST_PORT => Ok(Object::Port(packet.source.1)),
+                PACKET_DEST_IP => Ok(Object::IP(packet.dest.0)),
+                PACKET_DEST_PORT => Ok(Object::Port(packet.dest.1)),
                 PACKET_CONTENT => Ok(Object::Data(packet.content.clone())),
+                PACKET_CONTENT_LENGTH => Ok(Object::Int(packet.content.len() as i64)),
+                PACKET_LISTENER => Ok(Object::Data(packet.listener.clone())),
                 _ => Err("Invalid key"),
             }
         }
@@ -153,6 +561,11 @@ mod tests {
     use crate::parser::Rule;
     use crate::parser::RuleParser;
     use pest::Parser;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn v4(a: u8, b: u8, c: u8, d: u8) -> IpAddr {
+        Ipv4Addr::new(a, b, c, d).into()
+    }
 
     #[test]
     pub fn test_vm_seq() {
@@ -162,15 +575,19 @@ mod tests {
         data.insert(1, Object::Port(10));
         let program = Program {
             instructions: insns,
-            data: data,
+            data,
+            ..Default::default()
         };
         let mut vm = VM::new();
         let packet = Packet {
-            source: (Ipv4Addr::new(0, 0, 0, 0), 16),
-            dest: (Ipv4Addr::new(0, 0, 0, 0), 16),
+            source: (v4(0, 0, 0, 0), 16),
+            dest: (v4(0, 0, 0, 0), 16),
             content: Arc::new(vec![]),
+            offset: 0,
+            end: true,
+            listener: Arc::default(),
         };
-        vm.run_program(&program, &packet);
+        let _ = vm.run_program(&program, &packet);
         assert_eq!(vm.registers[0], 1);
         assert_eq!(vm.registers[1], 0);
     }
@@ -184,22 +601,26 @@ mod tests {
             REDIRECT(1, 2),
         ];
         let mut data = HashMap::new();
-        data.insert(0, Object::IP(Ipv4Addr::new(123, 123, 123, 123)));
-        data.insert(1, Object::IP(Ipv4Addr::new(128, 128, 128, 128)));
+        data.insert(0, Object::IP(v4(123, 123, 123, 123)));
+        data.insert(1, Object::IP(v4(128, 128, 128, 128)));
         data.insert(2, Object::Port(443));
         let program = Program {
             instructions: insns,
-            data: data,
+            data,
+            ..Default::default()
         };
         let mut vm = VM::new();
         let packet = Packet {
-            source: (Ipv4Addr::new(123, 123, 123, 123), 16),
-            dest: (Ipv4Addr::new(0, 0, 0, 0), 16),
+            source: (v4(123, 123, 123, 123), 16),
+            dest: (v4(0, 0, 0, 0), 16),
             content: Arc::new(vec![]),
+            offset: 0,
+            end: true,
+            listener: Arc::default(),
         };
         let result = vm.run_program(&program, &packet);
         assert!(result.is_ok());
-        assert_eq!(result.unwrap(), Action::DROP);
+        assert_eq!(result.unwrap(), (Action::DROP, vec![]));
     }
 
     #[test]
@@ -210,15 +631,19 @@ mod tests {
         data.insert(1, Object::Data(Arc::new(vec![1, 2, 3])));
         let program = Program {
             instructions: insns,
-            data: data,
+            data,
+            ..Default::default()
         };
         let mut vm = VM::new();
         let packet = Packet {
-            source: (Ipv4Addr::new(0, 0, 0, 0), 16),
-            dest: (Ipv4Addr::new(0, 0, 0, 0), 16),
+            source: (v4(0, 0, 0, 0), 16),
+            dest: (v4(0, 0, 0, 0), 16),
             content: Arc::new(vec![]),
+            offset: 0,
+            end: true,
+            listener: Arc::default(),
         };
-        vm.run_program(&program, &packet);
+        let _ = vm.run_program(&program, &packet);
         assert_eq!(vm.registers[5], 1);
         assert_eq!(vm.registers[1], 0);
     }
@@ -241,17 +666,21 @@ mod tests {
         ];
         let program = Program {
             instructions: insns,
-            data: data,
+            data,
+            ..Default::default()
         };
         let packet = Packet {
-            source: (Ipv4Addr::new(0, 0, 0, 0), 16),
-            dest: (Ipv4Addr::new(0, 0, 0, 0), 16),
+            source: (v4(0, 0, 0, 0), 16),
+            dest: (v4(0, 0, 0, 0), 16),
             content: Arc::new(vec![]),
+            offset: 0,
+            end: true,
+            listener: Arc::default(),
         };
         let mut vm = VM::new();
         let result = vm.run_program(&program, &packet);
         assert!(result.is_ok());
-        assert!(result.unwrap() == Action::DROP);
+        assert!(result.unwrap() == (Action::DROP, vec![]));
         assert_eq!(vm.registers[2], 1);
         assert_eq!(vm.registers[3], 0);
         assert_eq!(vm.registers[5], !0);
@@ -272,26 +701,32 @@ mod tests {
         let mut vm = VM::new();
         let mut data = HashMap::new();
 
-        let find = Object::Data(Arc::new(vec![0x41]));
-        let replace = Object::Data(Arc::new(vec![0x61]));
+        let find = Pattern::new(Regex::new("A").unwrap());
+        let replace = Arc::new(vec![0x61]);
 
-        let redirect_ip = Object::IP(Ipv4Addr::new(123, 123, 123, 123));
+        let redirect_ip = Object::IP(v4(123, 123, 123, 123));
         let redirect_port = Object::Port(442);
 
         data.insert(0, Object::Data(Arc::new(vec![0x41, 0x41, 0x41])));
-        data.insert(1, find.clone());
-        data.insert(2, replace.clone());
+        data.insert(1, Object::Pattern(find.clone()));
+        data.insert(2, Object::Data(replace.clone()));
         data.insert(3, redirect_ip.clone());
         data.insert(4, redirect_port.clone());
 
         let packet1 = Packet {
-            source: (Ipv4Addr::new(0, 0, 0, 0), 16),
-            dest: (Ipv4Addr::new(0, 0, 0, 0), 16),
+            source: (v4(0, 0, 0, 0), 16),
+            dest: (v4(0, 0, 0, 0), 16),
             content: Arc::new(vec![0x41, 0x41, 0x41]),
+            offset: 0,
+            end: true,
+            listener: Arc::default(),
         };
 
         let packet2 = Packet {
             content: Arc::new(vec![0x42, 0x42, 0x42]),
+            offset: 0,
+            end: true,
+            listener: packet1.listener.clone(),
             ..packet1
         };
         let insns = vec![
@@ -302,23 +737,31 @@ mod tests {
         ];
 
         let program = Program {
-            data: data,
+            data,
             instructions: insns,
+            ..Default::default()
         };
 
-        // test with packet that goes to if
+        // test with packet that goes to if: the rewrite is recorded, and evaluation goes on
         let result1 = vm.run_program(&program, &packet1);
         assert!(result1.is_ok());
-        let action1 = result1.unwrap();
-        assert_eq!(action1, Action::REWRITE(find, replace));
+        let (action1, transforms1) = result1.unwrap();
+        assert_eq!(action1, Action::REDIRECT(redirect_ip.clone(), redirect_port.clone()));
+        let transform = Transform {
+            pattern: find,
+            replacement: replace,
+        };
+        assert_eq!(transform.apply(&packet1.content, Edges::WHOLE), b"aaa");
+        assert_eq!(transforms1, vec![transform]);
 
         vm.reset();
 
         // test with packet that goes to else
         let result2 = vm.run_program(&program, &packet2);
         assert!(result2.is_ok());
-        let action2 = result2.unwrap();
+        let (action2, transforms2) = result2.unwrap();
         assert_eq!(action2, Action::REDIRECT(redirect_ip, redirect_port));
+        assert!(transforms2.is_empty());
     }
 
     fn test_program_helper<'a>(
@@ -332,7 +775,7 @@ mod tests {
             .unwrap();
         let ast = AstNode::try_from(parse_tree).unwrap();
         let bytecode = AstNode::codegen(&ast);
-        vm.run_program(&bytecode, packet)
+        vm.run_program(&bytecode, packet).map(|(action, _)| action)
     }
 
     #[test]
@@ -340,26 +783,32 @@ mod tests {
         let program = r#"
         (set-mode OPAQUE)
 
-        (def-var bad-ip "192.0.1.2")
+        (def-var bad-ip ip"192.0.1.2")
 
         (def-rule simple-rule
             (if (exact? :packet-source-ip bad-ip)
                 DROP
                 (REDIRECT "127.0.0.1" 80)))
         "#;
-        let bad_ip = Ipv4Addr::new(192, 0, 1, 2);
-        let good_ip = Ipv4Addr::new(192, 168, 0, 1);
-        let dest_ip = Ipv4Addr::new(192, 168, 1, 1);
+        let bad_ip = v4(192, 0, 1, 2);
+        let good_ip = v4(192, 168, 0, 1);
+        let dest_ip = v4(192, 168, 1, 1);
         let content: Vec<u8> = vec![];
         let bad_packet = Packet {
             source: (bad_ip, 80),
             dest: (dest_ip, 80),
             content: Arc::new(content.clone()),
+            offset: 0,
+            end: true,
+            listener: Arc::default(),
         };
         let good_packet = Packet {
             source: (good_ip, 80),
             dest: (dest_ip, 80),
             content: Arc::new(content.clone()),
+            offset: 0,
+            end: true,
+            listener: Arc::default(),
         };
         let mut vm = VM::new();
         let bad_action = test_program_helper(program, &mut vm, &bad_packet).unwrap();
@@ -367,7 +816,700 @@ mod tests {
         assert_eq!(bad_action, bad_action_target);
         let good_action = test_program_helper(program, &mut vm, &good_packet).unwrap();
         let good_action_target =
-            Action::REDIRECT(Object::IP(Ipv4Addr::new(127, 0, 0, 1)), Object::Port(80));
+            Action::REDIRECT(Object::IP(v4(127, 0, 0, 1)), Object::Port(80));
         assert_eq!(good_action, good_action_target);
     }
+
+    #[test]
+    pub fn test_boolean_combinators() {
+        let program = r#"
+        (set-mode OPAQUE)
+
+        (def-var bad-ip ip"192.0.1.2")
+        (def-var other-ip ip"192.0.1.3")
+        (def-var bad-port 80)
+
+        (def-rule combined
+            (if (or (and (exact? :packet-source-ip bad-ip) (exact? :packet-source-port bad-port))
+                    (and (exact? :packet-source-ip other-ip) (not (exact? :packet-source-port bad-port))))
+                DROP
+                (REDIRECT "127.0.0.1" 80)))
+        "#;
+        let redirect =
+            Action::REDIRECT(Object::IP(v4(127, 0, 0, 1)), Object::Port(80));
+        let cases = [
+            (v4(192, 0, 1, 2), 80, Action::DROP),
+            (v4(192, 0, 1, 2), 81, redirect.clone()),
+            (v4(192, 0, 1, 3), 81, Action::DROP),
+            (v4(192, 0, 1, 3), 80, redirect.clone()),
+            (v4(10, 0, 0, 1), 80, redirect),
+        ];
+        for (ip, port, expected) in cases {
+            let packet = Packet {
+                source: (ip, port),
+                dest: (v4(192, 168, 1, 1), 80),
+                content: Arc::new(vec![]),
+                offset: 0,
+                end: true,
+                listener: Arc::default(),
+            };
+            let mut vm = VM::new();
+            let action = test_program_helper(program, &mut vm, &packet).unwrap();
+            assert_eq!(action, expected, "{}:{}", ip, port);
+        }
+    }
+
+    #[test]
+    pub fn test_empty_and_or() {
+        let packet = Packet {
+            source: (v4(0, 0, 0, 0), 16),
+            dest: (v4(0, 0, 0, 0), 16),
+            content: Arc::new(vec![]),
+            offset: 0,
+            end: true,
+            listener: Arc::default(),
+        };
+        let mut vm = VM::new();
+        let program = "(set-mode OPAQUE) (def-rule r (if (and) DROP REJECT))";
+        assert_eq!(test_program_helper(program, &mut vm, &packet), Ok(Action::DROP));
+        let program = "(set-mode OPAQUE) (def-rule r (if (or) DROP REJECT))";
+        assert_eq!(test_program_helper(program, &mut vm, &packet), Ok(Action::REJECT));
+        let program = "(set-mode OPAQUE) (def-rule r (if (not (or #f #t)) DROP REJECT))";
+        assert_eq!(test_program_helper(program, &mut vm, &packet), Ok(Action::REJECT));
+    }
+
+    #[test]
+    pub fn test_and_short_circuits() {
+        let program = r#"
+        (set-mode OPAQUE)
+        (def-rule r (if (and #f (exact? :packet-source-ip ip"1.2.3.4")) DROP REJECT))
+        "#;
+        let parse_tree = RuleParser::parse(Rule::program, program)
+            .unwrap()
+            .next()
+            .unwrap();
+        let bytecode = AstNode::codegen(&AstNode::try_from(parse_tree).unwrap());
+        // the first operand's result is checked before the second is evaluated
+        let short_circuit = bytecode
+            .instructions
+            .iter()
+            .position(|i| matches!(i, ITE(..)))
+            .unwrap();
+        assert!(matches!(bytecode.instructions[short_circuit - 1], AND(..)));
+        assert!(matches!(bytecode.instructions[short_circuit + 1], SEQ(_, PACKET_SOURCE_IP, _)));
+    }
+
+    #[test]
+    pub fn test_nested_ifs_and_cond() {
+        let program = r#"
+        (set-mode OPAQUE)
+
+        (def-var bad-ip ip"192.0.1.2")
+        (def-var other-ip ip"192.0.1.3")
+        (def-var web-port 80)
+        (def-var ssh-port 22)
+
+        (def-rule skip-other
+            (if (exact? :packet-source-ip other-ip)
+                (if (exact? :packet-source-port web-port)
+                    CONTINUE
+                    REJECT)
+                CONTINUE))
+
+        (def-rule by-port
+            (cond ((exact? :packet-source-port web-port)
+                   (if (exact? :packet-source-ip bad-ip) DROP (REDIRECT "127.0.0.1" 8080)))
+                  ((exact? :packet-source-port ssh-port) (REDIRECT "127.0.0.1" 2222))
+                  (else REJECT)))
+        "#;
+        let redirect = |port| Action::REDIRECT(Object::IP(v4(127, 0, 0, 1)), Object::Port(port));
+        let cases = [
+            (v4(192, 0, 1, 2), 80, Action::DROP),
+            (v4(10, 0, 0, 1), 80, redirect(8080)),
+            (v4(10, 0, 0, 1), 22, redirect(2222)),
+            (v4(10, 0, 0, 1), 443, Action::REJECT),
+            (v4(192, 0, 1, 3), 80, redirect(8080)),
+            (v4(192, 0, 1, 3), 22, Action::REJECT),
+        ];
+        for (ip, port, expected) in cases {
+            let packet = Packet {
+                source: (ip, port),
+                dest: (v4(192, 168, 1, 1), 80),
+                content: Arc::new(vec![]),
+                offset: 0,
+                end: true,
+                listener: Arc::default(),
+            };
+            let mut vm = VM::new();
+            let action = test_program_helper(program, &mut vm, &packet).unwrap();
+            assert_eq!(action, expected, "{}:{}", ip, port);
+        }
+    }
+
+    #[test]
+    pub fn test_deeply_nested_ifs_reuse_registers() {
+        // 20 levels deep, which would run out of registers if each level kept its own
+        let body = (0..20).fold("DROP".to_string(), |body, _| {
+            format!("(if (and #t (not #f)) {} REJECT)", body)
+        });
+        let program = format!("(set-mode OPAQUE) (def-rule deep {})", body);
+        let packet = Packet {
+            source: (v4(0, 0, 0, 0), 16),
+            dest: (v4(0, 0, 0, 0), 16),
+            content: Arc::new(vec![]),
+            offset: 0,
+            end: true,
+            listener: Arc::default(),
+        };
+        let mut vm = VM::new();
+        assert_eq!(test_program_helper(&program, &mut vm, &packet), Ok(Action::DROP));
+    }
+
+    #[test]
+    pub fn test_vm_in_subnet() {
+        let insns = vec![
+            SIN(0, PACKET_SOURCE_IP, 0),
+            SIN(1, PACKET_SOURCE_IP, 1),
+            SIN(2, 1, 0),
+        ];
+        let mut data = HashMap::new();
+        data.insert(0, Object::Subnet("10.0.0.0/8".parse().unwrap()));
+        data.insert(1, Object::Subnet("192.168.0.0/16".parse().unwrap()));
+        let program = Program {
+            instructions: insns,
+            data,
+            ..Default::default()
+        };
+        let packet = Packet {
+            source: (v4(10, 1, 2, 3), 16),
+            dest: (v4(0, 0, 0, 0), 16),
+            content: Arc::new(vec![]),
+            offset: 0,
+            end: true,
+            listener: Arc::default(),
+        };
+        let mut vm = VM::new();
+        let _ = vm.run_program(&program, &packet);
+        assert_eq!(vm.registers[0], 1);
+        assert_eq!(vm.registers[1], 0);
+        // a subnet is never in a subnet
+        assert_eq!(vm.registers[2], 0);
+    }
+
+    #[test]
+    pub fn test_in_subnet_program() {
+        let program = r#"
+        (set-mode OPAQUE)
+
+        (def-var internal cidr"10.0.0.0/8")
+
+        (def-rule block-internal
+            (cond ((in-subnet? :packet-source-ip cidr"10.20.0.0/16") REJECT)
+                  ((in-subnet? :packet-source-ip internal) DROP)
+                  (else (REDIRECT "127.0.0.1" 80))))
+        "#;
+        let cases = [
+            (v4(10, 20, 1, 1), Action::REJECT),
+            (v4(10, 0, 0, 1), Action::DROP),
+            (
+                v4(11, 0, 0, 1),
+                Action::REDIRECT(Object::IP(v4(127, 0, 0, 1)), Object::Port(80)),
+            ),
+        ];
+        for (ip, expected) in cases {
+            let packet = Packet {
+                source: (ip, 1234),
+                dest: (v4(192, 168, 1, 1), 80),
+                content: Arc::new(vec![]),
+                offset: 0,
+                end: true,
+                listener: Arc::default(),
+            };
+            let mut vm = VM::new();
+            assert_eq!(test_program_helper(program, &mut vm, &packet), Ok(expected), "{}", ip);
+        }
+    }
+
+    #[test]
+    pub fn test_ipv6_program() {
+        let program = r#"
+        (set-mode OPAQUE)
+
+        (def-set blocked (ip"2001:db8::1" ip"10.0.0.1"))
+
+        (def-rule block
+            (cond ((exact? :packet-source-ip ip"::1") (REDIRECT ip"::1" 8080))
+                  ((member? :packet-source-ip blocked) DROP)
+                  ((in-subnet? :packet-source-ip cidr"fd00::/8") REJECT)
+                  (else (REDIRECT "127.0.0.1" 80))))
+        "#;
+        let fallback = Action::REDIRECT(Object::IP(v4(127, 0, 0, 1)), Object::Port(80));
+        let cases = [
+            (
+                IpAddr::V6(Ipv6Addr::LOCALHOST),
+                Action::REDIRECT(Object::IP(IpAddr::V6(Ipv6Addr::LOCALHOST)), Object::Port(8080)),
+            ),
+            ("2001:db8::1".parse().unwrap(), Action::DROP),
+            (v4(10, 0, 0, 1), Action::DROP),
+            ("fd12:3456::1".parse().unwrap(), Action::REJECT),
+            ("2001:db8::2".parse().unwrap(), fallback.clone()),
+            // an IPv4 address is never in an IPv6 subnet, even if its bits would be
+            (v4(253, 0, 0, 1), fallback),
+        ];
+        for (ip, expected) in cases {
+            let packet = Packet {
+                source: (ip, 1234),
+                dest: (IpAddr::V6(Ipv6Addr::LOCALHOST), 80),
+                content: Arc::new(vec![]),
+                offset: 0,
+                end: true,
+                listener: Arc::default(),
+            };
+            let mut vm = VM::new();
+            assert_eq!(test_program_helper(program, &mut vm, &packet), Ok(expected), "{}", ip);
+        }
+    }
+
+    #[test]
+    pub fn test_packet_metadata() {
+        let program = r#"
+        (set-mode OPAQUE)
+
+        (def-rule route
+            (cond ((exact? :listener "admin") REJECT)
+                  ((and (exact? :packet-dest-ip ip"10.0.0.2") (exact? :packet-dest-port 443)) DROP)
+                  ((exact? :packet-source-port 443) (REDIRECT "127.0.0.1" 1))
+                  (else (REDIRECT "127.0.0.1" 80))))
+        "#;
+        let redirect = |port| Action::REDIRECT(Object::IP(v4(127, 0, 0, 1)), Object::Port(port));
+        let cases = [
+            ("admin", v4(10, 0, 0, 2), 443, Action::REJECT),
+            ("public", v4(10, 0, 0, 2), 443, Action::DROP),
+            // the other local address, or the other port, doesn't match
+            ("public", v4(10, 0, 0, 1), 443, redirect(80)),
+            ("public", v4(10, 0, 0, 2), 80, redirect(80)),
+        ];
+        for (listener, dest_ip, dest_port, expected) in cases {
+            let packet = Packet {
+                source: (v4(192, 168, 1, 1), 50000),
+                dest: (dest_ip, dest_port),
+                content: Arc::new(vec![]),
+                offset: 0,
+                end: true,
+                listener: Arc::new(listener.as_bytes().to_vec()),
+            };
+            let mut vm = VM::new();
+            assert_eq!(
+                test_program_helper(program, &mut vm, &packet),
+                Ok(expected),
+                "{} {}:{}",
+                listener,
+                dest_ip,
+                dest_port
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_settled_end() {
+        let pattern = Pattern::new(Regex::new(r"user=(\w+);").unwrap());
+        let content = b"user=al; user=bob; user=c";
+
+        // a match that starts before the limit is settled, even if it ends past it
+        assert_eq!(pattern.settled_end(content, 10, Edges::WHOLE), 18);
+        assert_eq!(pattern.settled_end(content, 3, Edges::WHOLE), 8);
+        assert_eq!(pattern.settled_end(content, 8, Edges::WHOLE), 8);
+        assert_eq!(pattern.settled_end(content, content.len(), Edges::WHOLE), content.len());
+    }
+
+    #[test]
+    pub fn test_response_rules() {
+        let program = crate::compile(
+            r#"
+        (set-mode TRANSPARENT)
+
+        (def-var secret "hunter2")
+        (def-rule forward (if (contains? :packet-content secret) DROP (REDIRECT "127.0.0.1" 80)))
+
+        (def-response-rule leak (if (contains? :packet-content secret) REJECT CONTINUE))
+        (def-response-rule banner
+            (if (prefix? :packet-content "Server: ") (REWRITE "^Server: .*" "Server: redacted") CONTINUE))
+        "#,
+        )
+        .unwrap();
+        let packet = |content: &[u8]| Packet {
+            source: (v4(192, 168, 1, 1), 50000),
+            dest: (v4(10, 0, 0, 1), 80),
+            content: Arc::new(content.to_vec()),
+            offset: 0,
+            end: true,
+            listener: Arc::default(),
+        };
+
+        let mut vm = VM::new();
+        assert_eq!(
+            vm.run_response(&program, &packet(b"hunter2")),
+            Ok((Some(Action::REJECT), vec![]))
+        );
+        // a rewritten response is passed back once the rules are done with it
+        let mut vm = VM::new();
+        let (action, transforms) = vm
+            .run_response(&program, &packet(b"Server: nginx"))
+            .unwrap();
+        assert_eq!(action, None);
+        assert_eq!(transforms.len(), 1);
+        assert_eq!(transforms[0].apply(b"Server: nginx", Edges::WHOLE), b"Server: redacted");
+        // a response no rule decides on is passed back untouched
+        let mut vm = VM::new();
+        assert_eq!(
+            vm.run_response(&program, &packet(b"Date: today")),
+            Ok((None, vec![]))
+        );
+
+        // the request rules are unaffected
+        let mut vm = VM::new();
+        assert_eq!(
+            vm.run_program(&program, &packet(b"hunter2")),
+            Ok((Action::DROP, vec![]))
+        );
+        let mut vm = VM::new();
+        assert_eq!(
+            vm.run_program(&program, &packet(b"GET /")),
+            Ok((
+                Action::REDIRECT(Object::IP(v4(127, 0, 0, 1)), Object::Port(80)),
+                vec![]
+            ))
+        );
+    }
+
+    #[test]
+    pub fn test_rewrites_accumulate() {
+        let program = crate::compile(
+            r#"
+        (set-mode TRANSPARENT)
+
+        (def-rule agent (REWRITE "User-Agent: [^\r]*" "User-Agent: redirector"))
+        (def-rule cookies (if (contains? :packet-content "Cookie: ") (REWRITE "Cookie: [^\r]*\r\n" "") CONTINUE))
+        (def-rule route
+            (if (contains? :packet-content "User-Agent: redirector")
+                (REDIRECT "127.0.0.2" 80)
+                (REDIRECT "127.0.0.1" 80)))
+        "#,
+        )
+        .unwrap();
+        let packet = |content: &[u8]| Packet {
+            source: (v4(192, 168, 1, 1), 50000),
+            dest: (v4(10, 0, 0, 1), 80),
+            content: Arc::new(content.to_vec()),
+            offset: 0,
+            end: true,
+            listener: Arc::default(),
+        };
+
+        let request = b"GET / HTTP/1.1\r\nUser-Agent: curl\r\nCookie: a=b\r\n\r\n";
+        let mut vm = VM::new();
+        let (action, transforms) = vm.run_program(&program, &packet(request)).unwrap();
+        // the rules see the content as it came in, not as rewritten
+        assert_eq!(
+            action,
+            Action::REDIRECT(Object::IP(v4(127, 0, 0, 1)), Object::Port(80))
+        );
+        let rewritten = transforms
+            .iter()
+            .fold(request.to_vec(), |content, transform| {
+                transform.apply(&content, Edges::WHOLE)
+            });
+        assert_eq!(
+            rewritten,
+            b"GET / HTTP/1.1\r\nUser-Agent: redirector\r\n\r\n"
+        );
+
+        let mut vm = VM::new();
+        let (_, transforms) = vm.run_program(&program, &packet(b"GET /")).unwrap();
+        assert_eq!(transforms.len(), 1);
+    }
+
+    #[test]
+    pub fn test_balance() {
+        let program = crate::compile(
+            r#"
+        (set-mode OPAQUE)
+
+        (def-upstream web LEAST-CONNECTIONS
+            (check 2000 "PING\r\n" "+PONG")
+            ("10.0.0.1" 8080 2)
+            ("10.0.0.2" 8080))
+        (def-rule route (if (exact? :packet-dest-port 80) (BALANCE web) REJECT))
+        "#,
+        )
+        .unwrap();
+        let packet = Packet {
+            source: (v4(192, 168, 1, 1), 50000),
+            dest: (v4(10, 0, 0, 1), 80),
+            content: Arc::new(vec![]),
+            offset: 0,
+            end: true,
+            listener: Arc::default(),
+        };
+
+        let mut vm = VM::new();
+        let (action, _) = vm.run_program(&program, &packet).unwrap();
+        let expected = Pool {
+            name: "web".to_string(),
+            strategy: Strategy::LeastConnections,
+            backends: vec![
+                Backend {
+                    addr: SocketAddr::from(([10, 0, 0, 1], 8080)),
+                    weight: 2,
+                },
+                Backend {
+                    addr: SocketAddr::from(([10, 0, 0, 2], 8080)),
+                    weight: 1,
+                },
+            ],
+            check: Some(HealthCheck {
+                interval: Duration::from_millis(2000),
+                send: b"PING\r\n".to_vec(),
+                expect: b"+PONG".to_vec(),
+            }),
+        };
+        assert_eq!(action, Action::BALANCE(Arc::new(expected)));
+    }
+
+    #[test]
+    pub fn test_vm_comparisons() {
+        let insns = vec![
+            SLT(0, 0, 1),
+            SLT(1, 1, 0),
+            SLE(2, 0, 0),
+            SEQ(3, 0, 2),
+            SLE(4, 0, 3),
+            SLT(5, 1, PACKET_CONTENT_LENGTH),
+        ];
+        let mut data = HashMap::new();
+        data.insert(0, Object::Port(80));
+        data.insert(1, Object::Int(443));
+        data.insert(2, Object::Int(80));
+        data.insert(3, Object::IP(v4(0, 0, 0, 80)));
+        let program = Program {
+            instructions: insns,
+            data,
+            ..Default::default()
+        };
+        let packet = Packet {
+            source: (v4(0, 0, 0, 0), 16),
+            dest: (v4(0, 0, 0, 0), 16),
+            content: Arc::new(vec![0; 1000]),
+            offset: 0,
+            end: true,
+            listener: Arc::default(),
+        };
+        let mut vm = VM::new();
+        let _ = vm.run_program(&program, &packet);
+        assert_eq!(vm.registers[0], 1);
+        assert_eq!(vm.registers[1], 0);
+        assert_eq!(vm.registers[2], 1);
+        // ports and integers are equal when their values are
+        assert_eq!(vm.registers[3], 1);
+        // non-numbers never compare
+        assert_eq!(vm.registers[4], 0);
+        assert_eq!(vm.registers[5], 1);
+    }
+
+    #[test]
+    pub fn test_numeric_program() {
+        let program = r#"
+        (set-mode TRANSPARENT)
+
+        (def-var ephemeral 49152)
+
+        (def-rule by-port
+            (cond ((port-in-range? :packet-source-port 0 1023) REJECT)
+                  ((and (>= :packet-source-port ephemeral) (> :packet-content-length 3)) DROP)
+                  (else (REDIRECT "127.0.0.1" 80))))
+        "#;
+        let redirect =
+            Action::REDIRECT(Object::IP(v4(127, 0, 0, 1)), Object::Port(80));
+        let cases = [
+            (0, vec![], Action::REJECT),
+            (1023, vec![], Action::REJECT),
+            (1024, vec![1, 2, 3, 4], redirect.clone()),
+            (49152, vec![1, 2, 3, 4], Action::DROP),
+            (65535, vec![1, 2, 3], redirect),
+        ];
+        for (port, content, expected) in cases {
+            let packet = Packet {
+                source: (v4(10, 0, 0, 1), port),
+                dest: (v4(192, 168, 1, 1), 80),
+                content: Arc::new(content),
+                offset: 0,
+                end: true,
+                listener: Arc::default(),
+            };
+            let mut vm = VM::new();
+            assert_eq!(test_program_helper(program, &mut vm, &packet), Ok(expected), "{}", port);
+        }
+    }
+
+    #[test]
+    pub fn test_content_matching() {
+        let cases = [
+            ("(contains? :packet-content \"/admin\")", true),
+            ("(contains? :packet-content \"/ADMIN\")", false),
+            ("(contains? :packet-content \"\")", true),
+            ("(contains-ci? :packet-content \"/ADMIN\")", true),
+            ("(prefix? :packet-content \"GET \")", true),
+            ("(prefix? :packet-content \"get \")", false),
+            ("(prefix-ci? :packet-content \"get \")", true),
+            ("(prefix-ci? :packet-content \"/admin\")", false),
+            ("(suffix? :packet-content \"1.1\")", true),
+            ("(suffix-ci? :packet-content \"HTTP/1.1\")", true),
+            // the needle is matched literally, even by the regex-backed forms
+            ("(suffix-ci? :packet-content \"HTTP/1.\")", false),
+            ("(contains-ci? :packet-content \"a.min\")", false),
+            ("(matches? :packet-content \"^[A-Z]+ /admin\")", true),
+            ("(matches? :packet-content \"^POST\")", false),
+        ];
+        let packet = Packet {
+            source: (v4(0, 0, 0, 0), 16),
+            dest: (v4(0, 0, 0, 0), 16),
+            content: Arc::new(b"GET /admin HTTP/1.1".to_vec()),
+            offset: 0,
+            end: true,
+            listener: Arc::default(),
+        };
+        for (predicate, expected) in cases {
+            let program = format!("(set-mode TRANSPARENT) (def-rule r (if {} DROP REJECT))", predicate);
+            let expected = if expected { Action::DROP } else { Action::REJECT };
+            let mut vm = VM::new();
+            assert_eq!(test_program_helper(&program, &mut vm, &packet), Ok(expected), "{}", predicate);
+        }
+    }
+
+    #[test]
+    pub fn test_content_matching_within_a_stream() {
+        // (predicate, matches in the middle of the stream, matches at its end)
+        let cases = [
+            ("(contains? :packet-content \"/admin\")", true, true),
+            ("(prefix? :packet-content \"GET \")", false, false),
+            ("(prefix-ci? :packet-content \"get \")", false, false),
+            ("(suffix? :packet-content \"1.1\")", false, true),
+            ("(suffix-ci? :packet-content \"http/1.1\")", false, true),
+            ("(matches? :packet-content \"^GET\")", false, false),
+            ("(matches? :packet-content \"\\\\AGET|admin\")", true, true),
+            ("(matches? :packet-content \"1\\\\.1$\")", false, true),
+            ("(matches? :packet-content \"(?m)^GET\")", true, true),
+        ];
+        for (predicate, within, at_end) in cases {
+            let program = format!("(set-mode TRANSPARENT) (def-rule r (if {} DROP REJECT))", predicate);
+            for (end, expected) in [(false, within), (true, at_end)] {
+                let packet = Packet {
+                    source: (v4(0, 0, 0, 0), 16),
+                    dest: (v4(0, 0, 0, 0), 16),
+                    content: Arc::new(b"GET /admin HTTP/1.1".to_vec()),
+                    offset: 7,
+                    end,
+                    listener: Arc::default(),
+                };
+                let expected = if expected { Action::DROP } else { Action::REJECT };
+                let mut vm = VM::new();
+                let result = test_program_helper(&program, &mut vm, &packet);
+                assert_eq!(result, Ok(expected), "{} {}", predicate, end);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_byte_string_literals() {
+        let cases = [
+            ("(prefix? :packet-content #x\"16 03 01\")", true),
+            ("(suffix? :packet-content #x\"ff\")", true),
+            ("(contains? :packet-content #x\"0300\")", false),
+            ("(contains-ci? :packet-content #x\"01 ff\")", true),
+            ("(contains? :packet-content \"\u{3}\")", true),
+            ("(prefix? :packet-content \"\\x16\\x03\")", true),
+            ("(suffix? :packet-content \"\\x01\\xff\")", true),
+        ];
+        let packet = Packet {
+            source: (v4(0, 0, 0, 0), 16),
+            dest: (v4(0, 0, 0, 0), 16),
+            content: Arc::new(vec![0x16, 0x03, 0x01, 0xff]),
+            offset: 0,
+            end: true,
+            listener: Arc::default(),
+        };
+        for (predicate, expected) in cases {
+            let program = format!("(set-mode TRANSPARENT) (def-rule r (if {} DROP REJECT))", predicate);
+            let expected = if expected { Action::DROP } else { Action::REJECT };
+            let mut vm = VM::new();
+            assert_eq!(test_program_helper(&program, &mut vm, &packet), Ok(expected), "{}", predicate);
+        }
+    }
+
+    #[test]
+    pub fn test_content_matching_non_utf8() {
+        let mut data = HashMap::new();
+        data.insert(0, Object::Data(Arc::new(vec![0xff, 0x00])));
+        data.insert(1, Object::Pattern(Pattern::new(Regex::new("(?-u)\\xff\\x00$").unwrap())));
+        let program = Program {
+            instructions: vec![SCON(0, PACKET_CONTENT, 0), SSUF(1, PACKET_CONTENT, 0), SMAT(2, PACKET_CONTENT, 1)],
+            data,
+            ..Default::default()
+        };
+        let packet = Packet {
+            source: (v4(0, 0, 0, 0), 16),
+            dest: (v4(0, 0, 0, 0), 16),
+            content: Arc::new(vec![0xfe, 0xff, 0x00]),
+            offset: 0,
+            end: true,
+            listener: Arc::default(),
+        };
+        let mut vm = VM::new();
+        let _ = vm.run_program(&program, &packet);
+        assert_eq!(vm.registers[0], 1);
+        assert_eq!(vm.registers[1], 1);
+        assert_eq!(vm.registers[2], 1);
+    }
+
+    #[test]
+    pub fn test_set_membership() {
+        let blocked: Vec<String> = (0..=255).map(|i| format!("ip\"10.0.{}.1\"", i)).collect();
+        let program = format!(
+            r#"
+            (set-mode OPAQUE)
+
+            (def-var ssh 22)
+            (def-set blocked ({}))
+            (def-set admin-ports (ssh 3389))
+
+            (def-rule block
+                (cond ((member? :packet-source-ip blocked) DROP)
+                      ((member? :packet-source-port admin-ports) REJECT)
+                      (else (REDIRECT "127.0.0.1" 80))))
+            "#,
+            blocked.join(" ")
+        );
+        let cases = [
+            (v4(10, 0, 200, 1), 80, Action::DROP),
+            (v4(10, 0, 200, 2), 3389, Action::REJECT),
+            (v4(10, 0, 200, 2), 22, Action::REJECT),
+            (
+                v4(10, 0, 200, 2),
+                80,
+                Action::REDIRECT(Object::IP(v4(127, 0, 0, 1)), Object::Port(80)),
+            ),
+        ];
+        for (ip, port, expected) in cases {
+            let packet = Packet {
+                source: (ip, port),
+                dest: (v4(192, 168, 1, 1), 80),
+                content: Arc::new(vec![]),
+                offset: 0,
+                end: true,
+                listener: Arc::default(),
+            };
+            let mut vm = VM::new();
+            assert_eq!(test_program_helper(&program, &mut vm, &packet), Ok(expected), "{}:{}", ip, port);
+        }
+    }
 }
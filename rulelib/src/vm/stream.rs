@@ -0,0 +1,411 @@
+//! Matching over a byte stream rather than over each read.
+//!
+//! The redirector keeps the data of a connection it hasn't forwarded yet in a buffer, and runs the
+//! content rules on that. Whatever a needle or pattern may still match, once more data arrives, is
+//! held back in the buffer instead of being forwarded, so a match split across reads is found all
+//! the same.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use regex_automata::hybrid::dfa::{Cache, DFA};
+use regex_automata::hybrid::{CacheError, LazyStateID};
+use regex_automata::nfa::thompson;
+use regex_automata::util::{start, syntax};
+use regex_automata::Anchored;
+
+use super::{Instruction, ObjKey, Object, PACKET_CONTENT};
+
+/// What a set of rules looks for in `:packet-content`
+#[derive(Debug, Clone, Default)]
+pub struct Needles {
+    /// Needles that match anywhere, as with `contains?`
+    literals: Vec<Arc<Vec<u8>>>,
+    /// Needles of `prefix?`, which only match content that begins the stream
+    prefixes: Vec<Arc<Vec<u8>>>,
+    /// Needles of `suffix?`, which only match content that ends it
+    suffixes: Vec<Arc<Vec<u8>>>,
+    patterns: Arc<Vec<StreamPattern>>,
+}
+
+#[derive(Debug)]
+struct StreamPattern {
+    /// `None` stands for a pattern the lazy DFA can't handle (e.g. a Unicode word boundary), which
+    /// we assume may always be matching
+    dfa: Option<DFA>,
+    /// Whether a match can depend on reaching the end of the stream, with `\z` or `$`
+    ends: bool,
+}
+
+impl Needles {
+    /// Collects the needles of the content predicates and the patterns of the `REWRITE`s in `instructions`
+    pub(crate) fn new(instructions: &[Instruction], data: &HashMap<ObjKey, Object>) -> Self {
+        let mut needles = Self::default();
+        let mut patterns = vec![];
+        for instruction in instructions {
+            let (literals, needle) = match *instruction {
+                Instruction::SPRE(_, PACKET_CONTENT, needle) => (&mut needles.prefixes, needle),
+                Instruction::SSUF(_, PACKET_CONTENT, needle) => (&mut needles.suffixes, needle),
+                Instruction::SCON(_, PACKET_CONTENT, needle)
+                | Instruction::SMAT(_, PACKET_CONTENT, needle)
+                | Instruction::REWRITE(needle, _) => (&mut needles.literals, needle),
+                _ => continue,
+            };
+            // NOTE: needles that are packet fields aren't in `data`, and aren't known until the packet is
+            match data.get(&needle) {
+                Some(Object::Data(literal)) => literals.push(literal.clone()),
+                Some(Object::Pattern(pattern)) => patterns.push(StreamPattern {
+                    dfa: lazy_dfa(pattern.as_str()),
+                    ends: pattern.ends(),
+                }),
+                _ => {}
+            }
+        }
+        needles.patterns = Arc::new(patterns);
+        needles
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.literals.is_empty()
+            && self.prefixes.is_empty()
+            && self.suffixes.is_empty()
+            && self.patterns.is_empty()
+    }
+
+    /// Starts scanning a stream for the needles
+    pub fn scan(&self) -> Scan {
+        Scan {
+            needles: self.clone(),
+            runs: self
+                .patterns
+                .iter()
+                .map(|pattern| pattern.dfa.as_ref().map(Runs::new))
+                .collect(),
+            scanned: 0,
+        }
+    }
+
+    /// Like `Scan::holdback`, for content that begins a stream and is scanned all at once
+    pub fn holdback(&self, content: &[u8], window: usize) -> usize {
+        self.scan().holdback(content, 0, None, window)
+    }
+}
+
+/// How far a scan of a stream for some needles has got, so each read only scans the bytes it adds
+#[derive(Debug)]
+pub struct Scan {
+    needles: Needles,
+    /// The matches in progress of each pattern the lazy DFA can handle
+    runs: Vec<Option<Runs>>,
+    /// How far into the stream the bytes have been scanned
+    scanned: usize,
+}
+
+impl Scan {
+    /// Whether this is a scan for `needles`, rather than for those of a program since replaced
+    pub fn follows(&self, needles: &Needles) -> bool {
+        Arc::ptr_eq(&self.needles.patterns, &needles.patterns)
+    }
+
+    /// The position in `content` from which a needle or pattern may still match, given more data,
+    /// or `content.len()` if nothing may. `content` begins `offset` bytes into the stream, after
+    /// the byte `before`. Only the last `window` bytes are considered, so at most that much is ever
+    /// held back, and nothing is when `window` is 0, at the end of the stream.
+    pub fn holdback(
+        &mut self,
+        content: &[u8],
+        offset: usize,
+        before: Option<u8>,
+        window: usize,
+    ) -> usize {
+        let end = offset + content.len();
+        let start = end.saturating_sub(window).max(offset);
+        let at = |from: usize| &content[from - offset..];
+
+        // bytes that were never scanned break every match in progress
+        let from = self.scanned.max(start);
+        if from > self.scanned {
+            self.runs.iter_mut().flatten().for_each(Runs::clear);
+        }
+        for pos in from..end {
+            let prev = if pos > offset { Some(content[pos - offset - 1]) } else { before };
+            let byte = content[pos - offset];
+            for (pattern, runs) in self.needles.patterns.iter().zip(self.runs.iter_mut()) {
+                if let (Some(dfa), Some(runs)) = (&pattern.dfa, runs) {
+                    runs.step(dfa, prev, byte, pos);
+                }
+            }
+        }
+        self.scanned = self.scanned.max(end);
+
+        let needles = &self.needles;
+        // a literal is still in progress if what's left is a proper prefix of it
+        let literals = needles.literals.iter().filter_map(|literal| {
+            (start.max(end.saturating_sub(literal.len().saturating_sub(1)))..end)
+                .find(|&from| literal.starts_with(at(from)))
+        });
+        let prefixes = needles
+            .prefixes
+            .iter()
+            .filter(|prefix| prefix.len() > content.len() && prefix.starts_with(content))
+            .filter(|_| start == 0)
+            .map(|_| 0);
+        // and a suffix until the stream ends, even if it's complete
+        let suffixes = needles.suffixes.iter().filter_map(|suffix| {
+            (start.max(end.saturating_sub(suffix.len()))..end)
+                .find(|&from| suffix.starts_with(at(from)))
+        });
+        let patterns = needles.patterns.iter().zip(self.runs.iter_mut()).filter_map(
+            |(pattern, runs)| match (&pattern.dfa, runs) {
+                (Some(dfa), Some(runs)) => runs.holdback(dfa, pattern.ends, start),
+                _ => (start < end).then_some(start),
+            },
+        );
+        let hold = literals.chain(prefixes).chain(suffixes).chain(patterns).min();
+        hold.map_or(content.len(), |hold| hold - offset)
+    }
+}
+
+/// The matches in progress of one pattern
+#[derive(Debug)]
+struct Runs {
+    cache: Cache,
+    /// The state of each match in progress with where it started in the stream, one per state
+    states: Vec<(LazyStateID, usize)>,
+    /// Whether a match in each state may still be completed, or made longer, by more data
+    continues: HashMap<LazyStateID, bool>,
+    /// Where the earliest match the lazy DFA gave up on started, assumed to be still in progress
+    unsure: Option<usize>,
+}
+
+impl Runs {
+    fn new(dfa: &DFA) -> Self {
+        Self {
+            cache: dfa.create_cache(),
+            states: vec![],
+            continues: HashMap::new(),
+            unsure: None,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.states.clear();
+        self.unsure = None;
+    }
+
+    /// Starts a match at `pos`, after `prev`, and follows every match in progress over `byte`
+    fn step(&mut self, dfa: &DFA, prev: Option<u8>, byte: u8, pos: usize) {
+        let config = start::Config::new().anchored(Anchored::Yes).look_behind(prev);
+        match dfa.start_state(&mut self.cache, &config) {
+            Ok(sid) => self.states.push((sid, pos)),
+            Err(_) => return self.give_up(dfa, pos),
+        }
+        for i in 0..self.states.len() {
+            match dfa.next_state(&mut self.cache, self.states[i].0, byte) {
+                Ok(sid) => self.states[i].0 = sid,
+                Err(_) => return self.give_up(dfa, pos),
+            }
+        }
+        for &(sid, start) in &self.states {
+            if sid.is_quit() {
+                self.unsure = Some(self.unsure.map_or(start, |unsure| unsure.min(start)));
+            }
+        }
+        self.states.retain(|(sid, _)| !sid.is_dead() && !sid.is_quit());
+        // matches in the same state go the same way from here on, so the earliest stands for all
+        self.states.sort_unstable();
+        self.states.dedup_by_key(|(sid, _)| *sid);
+    }
+
+    /// Where the earliest match that may still be completed, or made longer, started, if at or
+    /// after `start`
+    fn holdback(&mut self, dfa: &DFA, ends: bool, start: usize) -> Option<usize> {
+        self.unsure = self.unsure.filter(|&unsure| unsure >= start);
+        self.states.retain(|&(_, from)| from >= start);
+        let mut hold = self.unsure;
+        for i in 0..self.states.len() {
+            let (sid, from) = self.states[i];
+            if hold.is_some_and(|hold| hold <= from) {
+                continue;
+            }
+            match self.continues(dfa, sid, ends) {
+                Ok(true) => hold = Some(from),
+                Ok(false) => {}
+                Err(_) => {
+                    self.give_up(dfa, from);
+                    return self.unsure;
+                }
+            }
+        }
+        hold
+    }
+
+    fn continues(&mut self, dfa: &DFA, sid: LazyStateID, ends: bool) -> Result<bool, CacheError> {
+        if let Some(&continues) = self.continues.get(&sid) {
+            return Ok(continues);
+        }
+        let continues = (ends && dfa.next_eoi_state(&mut self.cache, sid)?.is_match())
+            || can_continue(dfa, &mut self.cache, sid)?;
+        self.continues.insert(sid, continues);
+        Ok(continues)
+    }
+
+    /// Once the cache fills up, the lazy DFA gives up rather than clearing it, which would leave us
+    /// holding states that no longer exist. So we clear it ourselves, and assume whatever was in
+    /// progress, from `pos` on if nothing was, still is.
+    fn give_up(&mut self, dfa: &DFA, pos: usize) {
+        let earliest = self.states.iter().map(|&(_, start)| start).chain(self.unsure).min();
+        self.unsure = Some(earliest.map_or(pos, |earliest| earliest.min(pos)));
+        self.states.clear();
+        self.continues.clear();
+        self.cache.reset(dfa);
+    }
+}
+
+/// Builds a lazy DFA that matches the same way `regex::bytes::Regex` does
+fn lazy_dfa(pattern: &str) -> Option<DFA> {
+    DFA::builder()
+        .configure(DFA::config().unicode_word_boundary(true).minimum_cache_clear_count(Some(0)))
+        .syntax(syntax::Config::new().utf8(false))
+        .thompson(thompson::Config::new().utf8(false))
+        .build(pattern)
+        .ok()
+}
+
+/// Whether there's another byte after which the DFA is still following a match.
+/// Matches are reported one byte late, so a state that only reports one that already ended doesn't
+/// count: it's a match if every byte after it leads to the dead state.
+fn can_continue(dfa: &DFA, cache: &mut Cache, sid: LazyStateID) -> Result<bool, CacheError> {
+    let mut only_matched = vec![];
+    for byte in 0..=u8::MAX {
+        let next = dfa.next_state(cache, sid, byte)?;
+        if next.is_dead() || only_matched.contains(&next) {
+            continue;
+        }
+        if next.is_quit() || !next.is_match() {
+            return Ok(true);
+        }
+        for byte in 0..=u8::MAX {
+            if !dfa.next_state(cache, next, byte)?.is_dead() {
+                return Ok(true);
+            }
+        }
+        only_matched.push(next);
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(program: &str) -> Needles {
+        crate::compile(program).unwrap().needles
+    }
+
+    #[test]
+    fn holds_back_partial_literals() {
+        let needles = compile(
+            r#"(set-mode TRANSPARENT)
+            (def-rule r (if (contains? :packet-content "secret") DROP (REDIRECT "127.0.0.1" 80)))"#,
+        );
+        assert_eq!(needles.holdback(b"GET / HTTP/1.1", 64), 14);
+        assert_eq!(needles.holdback(b"the sec", 64), 4);
+        // a complete match is settled
+        assert_eq!(needles.holdback(b"the secret", 64), 10);
+        // the window bounds how much is held back
+        assert_eq!(needles.holdback(b"the secre", 3), 9);
+    }
+
+    #[test]
+    fn holds_back_partial_and_growing_matches() {
+        let needles = compile(
+            r#"(set-mode TRANSPARENT)
+            (def-rule r (REWRITE "user=(\\w+);" "user=<$1>;"))
+            (def-rule forward (REDIRECT "127.0.0.1" 80))"#,
+        );
+        assert_eq!(needles.holdback(b"GET / HTTP/1.1\r\n", 64), 16);
+        assert_eq!(needles.holdback(b"x us", 64), 2);
+        assert_eq!(needles.holdback(b"x user=bo", 64), 2);
+        assert_eq!(needles.holdback(b"x user=bob;", 64), 11);
+
+        // without the `;`, the match may still grow
+        let needles = compile(
+            r#"(set-mode TRANSPARENT)
+            (def-rule r (REWRITE "user=(\\w+)" "user=<$1>"))
+            (def-rule forward (REDIRECT "127.0.0.1" 80))"#,
+        );
+        assert_eq!(needles.holdback(b"x user=bob", 64), 2);
+        assert_eq!(needles.holdback(b"x user=bob ", 64), 11);
+    }
+
+    #[test]
+    fn ignores_other_fields_and_anchored_patterns() {
+        let needles = compile(
+            r#"(set-mode TRANSPARENT)
+            (def-rule r
+                (if (or (exact? :listener "secret") (contains? :listener "secret") (prefix-ci? :packet-content "GET"))
+                    DROP
+                    (REDIRECT "127.0.0.1" 80)))"#,
+        );
+        assert_eq!(needles.holdback(b"the sec", 64), 7);
+        // `prefix-ci?` only matches content that begins the stream
+        assert_eq!(needles.holdback(b"ge", 64), 0);
+        assert_eq!(needles.holdback(b"x ge", 64), 4);
+        assert_eq!(needles.scan().holdback(b"ge", 2, Some(b' '), 64), 2);
+    }
+
+    #[test]
+    fn holds_back_prefixes_only_at_the_start_of_the_stream() {
+        let needles = compile(
+            r#"(set-mode TRANSPARENT)
+            (def-rule r (if (prefix? :packet-content "POST") REJECT (REDIRECT "127.0.0.1" 80)))"#,
+        );
+        assert_eq!(needles.holdback(b"PO", 64), 0);
+        assert_eq!(needles.holdback(b"GET /x\r\nPOS", 64), 11);
+        // nor past the window
+        assert_eq!(needles.holdback(b"POS", 2), 3);
+
+        let mut scan = needles.scan();
+        assert_eq!(scan.holdback(b"POS", 3, Some(b'\n'), 64), 3);
+    }
+
+    #[test]
+    fn holds_back_suffixes_until_the_stream_ends() {
+        let needles = compile(
+            r#"(set-mode TRANSPARENT)
+            (def-rule r (if (suffix? :packet-content "\r\n\r\n") REJECT (REDIRECT "127.0.0.1" 80)))
+            (def-rule s
+                (if (matches? :packet-content "(?i)bye$") DROP (REDIRECT "127.0.0.1" 80)))"#,
+        );
+        assert_eq!(needles.holdback(b"GET / HTTP/1.1", 64), 14);
+        assert_eq!(needles.holdback(b"GET / HTTP/1.1\r\n", 64), 14);
+        // even a complete one may not end the stream
+        assert_eq!(needles.holdback(b"GET / HTTP/1.1\r\n\r\n", 64), 14);
+        assert_eq!(needles.holdback(b"Good BYE", 64), 5);
+        assert_eq!(needles.holdback(b"Good BYE", 0), 8);
+    }
+
+    #[test]
+    fn scans_each_byte_once() {
+        let needles = compile(
+            r#"(set-mode TRANSPARENT)
+            (def-rule r (REWRITE "user=(\\w+);" "user=<$1>;"))
+            (def-rule forward (REDIRECT "127.0.0.1" 80))"#,
+        );
+        let mut scan = needles.scan();
+        assert!(scan.follows(&needles));
+        assert!(!scan.follows(&compile("(set-mode TRANSPARENT) (def-rule r REJECT)")));
+
+        // a match split across reads is followed from where the last scan got to
+        let mut content = b"GET /?us".to_vec();
+        assert_eq!(scan.holdback(&content, 0, None, 64), 6);
+        content.extend_from_slice(b"er=bo");
+        assert_eq!(scan.holdback(&content, 0, None, 64), 6);
+        // once what's before the match has been taken
+        let content = content.split_off(6);
+        assert_eq!(scan.holdback(&content, 6, Some(b'?'), 64), 0);
+        let content = [content, b"b; x".to_vec()].concat();
+        assert_eq!(scan.holdback(&content, 6, Some(b'?'), 64), 11);
+    }
+}
@@ -1,9 +1,22 @@
+use std::fmt;
+
 use tarpc::serde::Serialize;
 use serde::Deserialize;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Error {
-    Anyhow(String)
+    Anyhow(String),
+    /// A rule file failed to compile; holds the rendered diagnostics
+    InvalidRuleFile(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Anyhow(message) => write!(f, "{}", message),
+            Error::InvalidRuleFile(report) => write!(f, "{}", report),
+        }
+    }
 }
 
-pub type Result<T> = core::result::Result<T, Error>;
\ No newline at end of file
+pub type Result<T> = core::result::Result<T, Error>;
@@ -1,3 +1,6 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -5,4 +8,40 @@ pub struct RuleFile {
     pub id: i64,
     pub name: String,
     pub content: String,
-}
\ No newline at end of file
+}
+
+/// A port the redirector accepts connections on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListenerStatus {
+    pub name: String,
+    pub bind: SocketAddr,
+    /// Where connections go while there's no program
+    pub destination: SocketAddr,
+    /// Where the active program came from, if one is set
+    pub program: Option<String>,
+}
+
+/// A backend of an upstream of a listener's program
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackendStatus {
+    pub listener: String,
+    pub upstream: String,
+    pub addr: SocketAddr,
+    pub weight: u32,
+    pub health: BackendHealth,
+    /// The connections open to it, from any upstream
+    pub connections: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BackendHealth {
+    /// Its upstream has no check, so it's always taken to be up
+    Unchecked,
+    /// Not probed yet; it's taken to be up until a probe fails
+    Pending,
+    Up,
+    /// Left out of balancing; holds why the last probe failed
+    Down(String),
+    /// Left out of balancing after too many failed connects in a row; holds for how much longer
+    Ejected(Duration),
+}
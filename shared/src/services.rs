@@ -1,5 +1,7 @@
 use crate::error::Result;
-use crate::model::RuleFile;
+use std::net::SocketAddr;
+
+use crate::model::{BackendStatus, ListenerStatus, RuleFile};
 
 #[tarpc::service]
 pub trait RuleSvc {
@@ -8,5 +10,10 @@ pub trait RuleSvc {
     async fn request(id: i64) -> Result<RuleFile>;
     async fn update(id: i64, content: String) -> Result<()>;
     async fn delete(id: i64) -> Result<()>;
-    async fn set_program(id: i64) -> Result<()>;
+    async fn set_program(listener: String, id: i64) -> Result<()>;
+    async fn backends() -> Result<Vec<BackendStatus>>;
+    /// Returns the address the listener is bound to, which tells which port was picked for port 0
+    async fn add_listener(name: String, bind: SocketAddr, destination: SocketAddr) -> Result<SocketAddr>;
+    async fn remove_listener(name: String) -> Result<()>;
+    async fn listeners() -> Result<Vec<ListenerStatus>>;
 }
\ No newline at end of file